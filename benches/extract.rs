@@ -0,0 +1,60 @@
+// extraction throughput of a layer of many small files & one of a few large
+// ones, run with `cargo bench --bench extract`
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use rust_ns_overlay::extract_archive;
+
+// file count & size of each layer
+const LAYERS: [(&str, usize, usize); 2] = [
+    ("small_files", 20_000, 2 * 1024),
+    ("large_files", 8, 16 * 1024 * 1024),
+];
+
+fn build_layer(files: usize, file_size: usize) -> Vec<u8> {
+    let data = vec![0x5a; file_size];
+    let mut builder = tar::Builder::new(Vec::new());
+    for dir in 0..files.div_ceil(1000) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, format!("dir{}", dir), std::io::empty())
+            .unwrap();
+    }
+    for idx in 0..files {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(file_size as u64);
+        builder
+            .append_data(
+                &mut header,
+                format!("dir{}/file{}", idx / 1000, idx),
+                data.as_slice(),
+            )
+            .unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+fn extract(c: &mut Criterion) {
+    let mut group = c.benchmark_group("extract_archive");
+    group.sample_size(10);
+    for (name, files, file_size) in LAYERS {
+        let layer = build_layer(files, file_size);
+        group.throughput(Throughput::Bytes(layer.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &layer, |b, layer| {
+            b.iter_batched(
+                || tempfile::tempdir().unwrap(),
+                |dst_dir| {
+                    extract_archive(&mut layer.as_slice(), dst_dir.path()).unwrap();
+                    dst_dir
+                },
+                criterion::BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, extract);
+criterion_main!(benches);
@@ -15,4 +15,13 @@ fn main() {
     // version
     let version = env::var("PROGRAM_VERSION").unwrap_or("dev".to_string());
     println!("cargo:rustc-env=PROGRAM_VERSION={}", version);
+
+    // busybox feature, a static busybox binary to embed
+    if env::var_os("CARGO_FEATURE_BUSYBOX").is_some() {
+        println!("cargo:rerun-if-env-changed=BUSYBOX_PATH");
+        let busybox_path = env::var("BUSYBOX_PATH")
+            .expect("the busybox feature needs BUSYBOX_PATH set to a static busybox binary");
+        println!("cargo:rerun-if-changed={}", busybox_path);
+        println!("cargo:rustc-env=BUSYBOX_PATH={}", busybox_path);
+    }
 }
@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::docker_helper::split_image_reference;
+
+// docker hub credentials are stored under its v1 index url
+const DOCKER_HUB_AUTH_KEYS: [&str; 3] = [
+    "https://index.docker.io/v1/",
+    "index.docker.io",
+    "docker.io",
+];
+
+/// Registry credentials for pulls, never printed.
+#[derive(Clone, PartialEq)]
+pub enum RegistryAuth {
+    Password { username: String, password: String },
+    Token(String),
+}
+
+impl std::fmt::Debug for RegistryAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryAuth::Password { username, .. } => {
+                write!(
+                    f,
+                    "Password {{ username: {:?}, password: <redacted> }}",
+                    username
+                )
+            }
+            RegistryAuth::Token(_) => write!(f, "Token(<redacted>)"),
+        }
+    }
+}
+
+impl std::str::FromStr for RegistryAuth {
+    type Err = anyhow::Error;
+
+    // `user:pass`, the password may contain colons
+    fn from_str(credentials: &str) -> Result<Self> {
+        match credentials.split_once(':') {
+            Some((username, password)) if !username.is_empty() => Ok(RegistryAuth::Password {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => Err(anyhow::anyhow!(
+                "invalid registry auth, expect <user>:<password>"
+            )),
+        }
+    }
+}
+
+// registry host of an image reference, like docker resolves it: the first
+// path component is a host only if it looks like one
+pub fn registry_host(image: &str) -> &str {
+    let (repository, _, _) = split_image_reference(image);
+    match repository.split_once('/') {
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => host,
+        _ => "docker.io",
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct DockerConfig {
+    auths: HashMap<String, DockerConfigAuth>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct DockerConfigAuth {
+    auth: Option<String>,
+    identitytoken: Option<String>,
+}
+
+// $DOCKER_CONFIG/config.json or ~/.docker/config.json
+fn docker_config_path() -> Option<PathBuf> {
+    if let Some(config_dir) = std::env::var_os("DOCKER_CONFIG") {
+        return Some(PathBuf::from(config_dir).join("config.json"));
+    }
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker/config.json"))
+}
+
+// credentials `docker login` stored for host, credential helpers aren't supported
+pub fn docker_config_auth(host: &str) -> Result<Option<RegistryAuth>> {
+    let Some(config_path) = docker_config_path() else {
+        return Ok(None);
+    };
+    let content = match std::fs::read_to_string(&config_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let config: DockerConfig = serde_json::from_str(&content)
+        .context(format!("failed to parse {}", config_path.display()))?;
+
+    let keys = if host == "docker.io" {
+        DOCKER_HUB_AUTH_KEYS.to_vec()
+    } else {
+        vec![host]
+    };
+    // keys may be stored as bare hosts or as urls
+    let Some(entry) = config.auths.iter().find_map(|(key, entry)| {
+        let key_host = key
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .trim_end_matches('/');
+        (keys.contains(&key.as_str()) || keys.contains(&key_host)).then_some(entry)
+    }) else {
+        return Ok(None);
+    };
+
+    if let Some(token) = &entry.identitytoken
+        && !token.is_empty()
+    {
+        return Ok(Some(RegistryAuth::Token(token.clone())));
+    }
+    let Some(auth) = &entry.auth else {
+        return Ok(None);
+    };
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(auth)
+        .context(format!(
+            "invalid auth for {} in {}",
+            host,
+            config_path.display()
+        ))?;
+    let decoded = String::from_utf8(decoded).context(format!(
+        "invalid auth for {} in {}",
+        host,
+        config_path.display()
+    ))?;
+    decoded.parse().map(Some)
+}
@@ -0,0 +1,60 @@
+use anyhow::Result;
+use std::path::Path;
+#[cfg(feature = "busybox")]
+use std::{
+    fs::{self, Permissions},
+    os::unix::fs::{PermissionsExt, symlink},
+};
+#[cfg(feature = "busybox")]
+use tracing::{debug, info};
+
+#[cfg(feature = "busybox")]
+use crate::utils;
+
+// static busybox handed in by build.rs through BUSYBOX_PATH
+#[cfg(feature = "busybox")]
+const BUSYBOX: &[u8] = include_bytes!(env!("BUSYBOX_PATH"));
+
+// applets linked into /bin, enough to look around & fix things by hand
+#[cfg(feature = "busybox")]
+const APPLETS: [&str; 24] = [
+    "sh", "ls", "cat", "ps", "grep", "find", "vi", "less", "head", "tail", "cp", "mv", "rm",
+    "mkdir", "ln", "chmod", "df", "du", "top", "netstat", "ip", "ping", "wget", "tar",
+];
+
+// write busybox into the rootfs /bin & link its applets, existing binaries
+// of the image are only replaced with force
+#[cfg(feature = "busybox")]
+pub fn install(rootfs_dir: &Path, force: bool) -> Result<()> {
+    let bin_dir = utils::rootfs_path(rootfs_dir, "/bin")?;
+    fs::create_dir_all(&bin_dir)?;
+    let busybox_path = bin_dir.join("busybox");
+    if busybox_path.symlink_metadata().is_ok() {
+        fs::remove_file(&busybox_path)?;
+    }
+    fs::write(&busybox_path, BUSYBOX)?;
+    fs::set_permissions(&busybox_path, Permissions::from_mode(0o755))?;
+
+    let mut linked = 0;
+    for applet in APPLETS {
+        let applet_path = bin_dir.join(applet);
+        if applet_path.symlink_metadata().is_ok() {
+            if !force {
+                debug!("keeping the image's {}", applet_path.display());
+                continue;
+            }
+            fs::remove_file(&applet_path)?;
+        }
+        symlink("busybox", &applet_path)?;
+        linked += 1;
+    }
+    info!("installed busybox with {} applets", linked);
+    Ok(())
+}
+
+#[cfg(not(feature = "busybox"))]
+pub fn install(_rootfs_dir: &Path, _force: bool) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "built without busybox, rebuild with --features busybox & BUSYBOX_PATH set"
+    ))
+}
@@ -0,0 +1,466 @@
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{File, read_dir, read_to_string, remove_file, write},
+    io::{ErrorKind, copy},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tar::{Builder, EntryType, Header, HeaderMode};
+
+use crate::docker_helper::{ImageConfig, PlatformSpec, split_image_reference};
+use crate::utils::PAX_XATTR_PREFIX;
+
+// cache tar name for an image, or for a `docker save` tar or oci layout when
+// image_file is set, keyed on the selected entry's digest. an explicit platform is part of the name, the tag alone
+// would serve another arch's rootfs
+pub fn image_cache_filename(
+    image: &str,
+    image_file: Option<&Path>,
+    digest: Option<&str>,
+    platform: Option<&PlatformSpec>,
+) -> String {
+    let platform = platform
+        .map(|platform| format!("_{}", platform.to_string().replace('/', "_")))
+        .unwrap_or_default();
+    if let Some(image_file) = image_file {
+        let file_name = image_file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let digest = digest
+            .map(|digest| format!("_{}", short_digest(digest)))
+            .unwrap_or_default();
+        return format!(
+            "file_{}{}{}.tar",
+            file_name.trim_end_matches(".tar"),
+            platform,
+            digest
+        );
+    }
+    // registry ports & nested repositories become part of a flat file name
+    let (repository, tag, pinned_digest) = split_image_reference(image);
+    let image_name = repository.replace(['/', ':'], "_");
+    let tag = match pinned_digest {
+        Some(pinned_digest) => short_digest(pinned_digest),
+        None => tag.unwrap_or("latest").to_string(),
+    };
+    if let Some(digest) = digest {
+        return format!(
+            "{}_{}{}_{}.tar",
+            image_name,
+            tag,
+            platform,
+            short_digest(digest)
+        );
+    }
+    format!("{}_{}{}.tar", image_name, tag, platform)
+}
+
+// first 12 hex chars, like docker shows them
+fn short_digest(digest: &str) -> String {
+    let (_, hex) = digest.split_once(':').unwrap_or(("", digest));
+    hex.chars().take(12).collect()
+}
+
+// tar the rootfs for the cache so extracting it gives back the same tree:
+// numeric owners, special files, xattrs as PAX records & hardlinks as links
+pub fn archive_rootfs(rootfs_dir: &Path, cache_path: &Path) -> Result<()> {
+    let mut archive = Builder::new(File::create(cache_path)?);
+    archive.mode(HeaderMode::Complete);
+    archive.follow_symlinks(false);
+    let mut seen_inodes = HashMap::new();
+    append_tree(&mut archive, rootfs_dir, Path::new(""), &mut seen_inodes)?;
+    archive.finish()?;
+    Ok(())
+}
+
+fn append_tree(
+    archive: &mut Builder<File>,
+    rootfs_dir: &Path,
+    relative_dir: &Path,
+    seen_inodes: &mut HashMap<(u64, u64), PathBuf>,
+) -> Result<()> {
+    let mut entries = read_dir(rootfs_dir.join(relative_dir))?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    // stable archives for the checksum sidecar
+    entries.sort();
+    for file_name in entries {
+        let relative_path = relative_dir.join(&file_name);
+        let path = rootfs_dir.join(&relative_path);
+        let metadata = path.symlink_metadata()?;
+
+        if metadata.is_file() && metadata.nlink() > 1 {
+            let inode = (metadata.dev(), metadata.ino());
+            if let Some(target) = seen_inodes.get(&inode) {
+                let mut header = Header::new_gnu();
+                header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+                header.set_entry_type(EntryType::Link);
+                header.set_size(0);
+                archive.append_link(&mut header, &relative_path, target)?;
+                continue;
+            }
+            seen_inodes.insert(inode, relative_path.clone());
+        }
+
+        let xattrs = read_xattrs(&path);
+        if !xattrs.is_empty() {
+            archive.append_pax_extensions(
+                xattrs
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.as_slice())),
+            )?;
+        }
+        archive.append_path_with_name(&path, &relative_path)?;
+        if metadata.is_dir() {
+            append_tree(archive, rootfs_dir, &relative_path, seen_inodes)?;
+        }
+    }
+    Ok(())
+}
+
+// xattrs as PAX keys, unreadable ones are skipped
+fn read_xattrs(path: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut xattrs = Vec::new();
+    // e.g. a filesystem without xattr support
+    let Ok(names) = xattr::list(path) else {
+        return xattrs;
+    };
+    for name in names {
+        if let Ok(Some(value)) = xattr::get(path, &name) {
+            xattrs.push((
+                format!("{}{}", PAX_XATTR_PREFIX, name.to_string_lossy()),
+                value,
+            ));
+        }
+    }
+    xattrs
+}
+
+// sidecar file holding the sha256 of a cache tar
+pub fn checksum_path(cache_path: &Path) -> PathBuf {
+    let mut path = OsString::from(cache_path.as_os_str());
+    path.push(".sha256");
+    PathBuf::from(path)
+}
+
+pub fn write_checksum(cache_path: &Path) -> Result<()> {
+    write(checksum_path(cache_path), file_sha256(cache_path)?)?;
+    Ok(())
+}
+
+// a missing sidecar fails verification, the tar may have been cut short
+pub fn verify_checksum(cache_path: &Path) -> Result<bool> {
+    let expected = match read_to_string(checksum_path(cache_path)) {
+        Ok(expected) => expected,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(expected.trim() == file_sha256(cache_path)?)
+}
+
+// sidecar file holding the image config, the cache tar only has the rootfs
+pub fn image_config_path(cache_path: &Path) -> PathBuf {
+    let mut path = OsString::from(cache_path.as_os_str());
+    path.push(".config.json");
+    PathBuf::from(path)
+}
+
+pub fn write_image_config(cache_path: &Path, image_config: &ImageConfig) -> Result<()> {
+    write(
+        image_config_path(cache_path),
+        serde_json::to_vec(image_config)?,
+    )?;
+    Ok(())
+}
+
+// caches saved before the sidecar existed fall back to an empty config
+pub fn read_image_config(cache_path: &Path) -> Result<ImageConfig> {
+    match read_to_string(image_config_path(cache_path)) {
+        Ok(content) => Ok(serde_json::from_str(&content)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(ImageConfig::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// A rootfs cache tar.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub last_used: SystemTime,
+}
+
+// cache tars in cache_dir, least recently used first
+pub fn list_cache(cache_dir: &Path) -> Result<Vec<CacheEntry>> {
+    let mut entries = Vec::new();
+    for entry in read_dir(cache_dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "tar") {
+            continue;
+        }
+        let metadata = path.metadata()?;
+        let modified = metadata.modified()?;
+        // atime is only as good as the mount options, mtime is the fallback
+        let last_used = metadata.accessed().unwrap_or(modified).max(modified);
+        entries.push(CacheEntry {
+            path,
+            size: metadata.len(),
+            last_used,
+        });
+    }
+    entries.sort_by_key(|entry| entry.last_used);
+    Ok(entries)
+}
+
+// remove a cache tar & its sidecar files
+pub fn remove_cache_entry(cache_path: &Path) -> Result<()> {
+    remove_file(cache_path)?;
+    let _ = remove_file(checksum_path(cache_path));
+    let _ = remove_file(image_config_path(cache_path));
+    Ok(())
+}
+
+// remove least recently used cache tars until the total size fits max_size,
+// keep is never removed, returns the removed paths
+pub fn prune_cache(cache_dir: &Path, max_size: u64, keep: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let entries = list_cache(cache_dir)?;
+    let mut total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+
+    let mut removed = Vec::new();
+    for entry in entries {
+        if total_size <= max_size {
+            break;
+        }
+        if keep.is_some_and(|keep| keep == entry.path) {
+            continue;
+        }
+        remove_cache_entry(&entry.path)?;
+        total_size -= entry.size;
+        removed.push(entry.path);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::extract_archive;
+    use std::fs::{create_dir, hard_link, set_permissions};
+    use std::os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, PermissionsExt, symlink},
+    };
+
+    // a small tree with the file kinds archive_rootfs has to keep
+    fn build_rootfs(rootfs_dir: &Path) {
+        create_dir(rootfs_dir.join("bin")).unwrap();
+        write(rootfs_dir.join("bin/tool"), b"#!/bin/true").unwrap();
+        set_permissions(
+            rootfs_dir.join("bin/tool"),
+            PermissionsExt::from_mode(0o4755),
+        )
+        .unwrap();
+        hard_link(
+            rootfs_dir.join("bin/tool"),
+            rootfs_dir.join("bin/tool-link"),
+        )
+        .unwrap();
+        symlink("tool", rootfs_dir.join("bin/alias")).unwrap();
+        create_dir(rootfs_dir.join("tmp")).unwrap();
+        set_permissions(rootfs_dir.join("tmp"), PermissionsExt::from_mode(0o1777)).unwrap();
+        let fifo =
+            std::ffi::CString::new(rootfs_dir.join("tmp/fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+    }
+
+    fn round_trip(rootfs_dir: &Path) -> tempfile::TempDir {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_path = cache_dir.path().join("image.tar");
+        archive_rootfs(rootfs_dir, &cache_path).unwrap();
+        write_checksum(&cache_path).unwrap();
+        assert!(verify_checksum(&cache_path).unwrap());
+        let extracted = tempfile::tempdir().unwrap();
+        extract_archive(&mut File::open(&cache_path).unwrap(), extracted.path()).unwrap();
+        extracted
+    }
+
+    #[test]
+    fn image_cache_filename_tags() {
+        assert_eq!(
+            image_cache_filename("ubuntu", None, None, None),
+            "ubuntu_latest.tar"
+        );
+        assert_eq!(
+            image_cache_filename("ubuntu:22.04", None, None, None),
+            "ubuntu_22.04.tar"
+        );
+        assert_eq!(
+            image_cache_filename(
+                "library/ubuntu:22.04",
+                None,
+                Some("sha256:0123456789abcdef"),
+                None
+            ),
+            "library_ubuntu_22.04_0123456789ab.tar"
+        );
+    }
+
+    #[test]
+    fn image_cache_filename_registry_ports() {
+        assert_eq!(
+            image_cache_filename("localhost:5000/team/app", None, None, None),
+            "localhost_5000_team_app_latest.tar"
+        );
+        assert_eq!(
+            image_cache_filename("registry.example.com:5000/team/app:v1", None, None, None),
+            "registry.example.com_5000_team_app_v1.tar"
+        );
+    }
+
+    #[test]
+    fn image_cache_filename_pinned_digests() {
+        let pinned = "alpine@sha256:aaaabbbbccccddddeeee";
+        assert_eq!(
+            image_cache_filename(pinned, None, None, None),
+            "alpine_aaaabbbbcccc.tar"
+        );
+        let pinned = "localhost:5000/alpine:3.20@sha256:aaaabbbbccccddddeeee";
+        assert_eq!(
+            image_cache_filename(pinned, None, None, None),
+            "localhost_5000_alpine_aaaabbbbcccc.tar"
+        );
+    }
+
+    #[test]
+    fn image_cache_filename_image_file() {
+        let image_file = Path::new("/tmp/images/app.tar");
+        assert_eq!(
+            image_cache_filename("ignored:tag", Some(image_file), None, None),
+            "file_app.tar"
+        );
+    }
+
+    #[test]
+    fn archive_rootfs_round_trip() {
+        let rootfs = tempfile::tempdir().unwrap();
+        build_rootfs(rootfs.path());
+        let extracted = round_trip(rootfs.path());
+        let extracted = extracted.path();
+        assert_eq!(
+            std::fs::read(extracted.join("bin/tool")).unwrap(),
+            b"#!/bin/true"
+        );
+        let tool = extracted.join("bin/tool").symlink_metadata().unwrap();
+        let tool_link = extracted.join("bin/tool-link").symlink_metadata().unwrap();
+        assert_eq!(tool.mode() & 0o7777, 0o4755);
+        assert_eq!(tool.ino(), tool_link.ino());
+        assert_eq!(
+            std::fs::read_link(extracted.join("bin/alias")).unwrap(),
+            Path::new("tool")
+        );
+        let tmp = extracted.join("tmp").symlink_metadata().unwrap();
+        assert_eq!(tmp.mode() & 0o7777, 0o1777);
+        let fifo = extracted.join("tmp/fifo").symlink_metadata().unwrap();
+        assert!(fifo.file_type().is_fifo());
+    }
+
+    #[test]
+    fn archive_rootfs_keeps_xattrs() {
+        let rootfs = tempfile::tempdir().unwrap();
+        write(rootfs.path().join("file"), b"data").unwrap();
+        // e.g. a tmpfs without user xattrs
+        if xattr::set(rootfs.path().join("file"), "user.cache", b"kept").is_err() {
+            return;
+        }
+        let extracted = round_trip(rootfs.path());
+        assert_eq!(
+            xattr::get(extracted.path().join("file"), "user.cache").unwrap(),
+            Some(b"kept".to_vec())
+        );
+    }
+
+    #[test]
+    fn archive_rootfs_keeps_numeric_owners() {
+        // only root can hand files over to other users
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let rootfs = tempfile::tempdir().unwrap();
+        let path = rootfs.path().join("owned");
+        write(&path, b"data").unwrap();
+        // ids with no passwd entry, names can't be looked up
+        std::os::unix::fs::lchown(&path, Some(54321), Some(54322)).unwrap();
+        let extracted = round_trip(rootfs.path());
+        let metadata = extracted.path().join("owned").symlink_metadata().unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (54321, 54322));
+    }
+
+    #[test]
+    fn verify_checksum_detects_changes() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_path = cache_dir.path().join("image.tar");
+        write(&cache_path, b"cache").unwrap();
+        // no sidecar yet
+        assert!(!verify_checksum(&cache_path).unwrap());
+        write_checksum(&cache_path).unwrap();
+        assert!(verify_checksum(&cache_path).unwrap());
+        write(&cache_path, b"cut").unwrap();
+        assert!(!verify_checksum(&cache_path).unwrap());
+    }
+
+    #[test]
+    fn image_cache_filename_platform() {
+        let arm64 = PlatformSpec::parse("linux/arm64/v8").unwrap();
+        assert_eq!(
+            image_cache_filename("debian:12", None, None, Some(&arm64)),
+            "debian_12_linux_arm64_v8.tar"
+        );
+        assert_ne!(
+            image_cache_filename("debian:12", None, None, Some(&arm64)),
+            image_cache_filename("debian:12", None, None, None)
+        );
+        assert_eq!(
+            image_cache_filename(
+                "debian:12",
+                None,
+                Some("sha256:0123456789abcdef"),
+                Some(&arm64)
+            ),
+            "debian_12_linux_arm64_v8_0123456789ab.tar"
+        );
+        assert_eq!(
+            image_cache_filename("", Some(Path::new("app.tar")), None, Some(&arm64)),
+            "file_app_linux_arm64_v8.tar"
+        );
+    }
+
+    #[test]
+    fn image_cache_filename_image_file_entries() {
+        let image_file = Path::new("/tmp/images/bundle.tar");
+        let web = image_cache_filename(
+            "web:1",
+            Some(image_file),
+            Some("sha256:aaaabbbbccccdddd"),
+            None,
+        );
+        let db = image_cache_filename(
+            "db:2",
+            Some(image_file),
+            Some("sha256:1111222233334444"),
+            None,
+        );
+        assert_eq!(web, "file_bundle_aaaabbbbcccc.tar");
+        assert_eq!(db, "file_bundle_111122223333.tar");
+    }
+}
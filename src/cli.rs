@@ -1,56 +1,537 @@
-use clap::Parser;
+use anyhow::Result;
+use clap::{ArgAction, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use rust_ns_overlay::{
+    CacheKey, DebugSession, DebugSessionBuilder, HostMount, NetworkMode, OutputFormat, PullPolicy,
+    RegistryAuth, RuntimeKind,
+};
+use std::ffi::OsString;
+
+use crate::config::{FileConfig, is_default};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// build info emitted by build.rs & vergen, for actionable bug reports
+const VERSION: &str = concat!(
+    env!("PROGRAM_VERSION"),
+    "\ncommit     : ",
+    env!("VERGEN_GIT_SHA"),
+    "\ncommit date: ",
+    env!("VERGEN_GIT_COMMIT_DATE"),
+    "\ndirty      : ",
+    env!("VERGEN_GIT_DIRTY"),
+);
+
+// first arguments that aren't a bare container id
+const SUBCOMMANDS: [&str; 7] = [
+    "run",
+    "inspect",
+    "cp",
+    "clean",
+    "cache",
+    "completions",
+    "help",
+];
+const TOP_LEVEL_FLAGS: [&str; 4] = ["-h", "--help", "-V", "--version"];
 
 #[derive(Parser, Debug)]
-#[command(disable_version_flag = true, about, long_about = None)]
-pub struct VerArgs {
-    // version
-    #[arg(short, long, default_value_t = false)]
-    pub version: bool,
+#[command(version = VERSION, about, long_about = None)]
+pub struct Cli {
+    /// log level: error, warn, info, debug or trace, RUST_LOG takes precedence
+    #[arg(long, global = true, default_value_t = String::from("info"))]
+    pub log_level: String,
+
+    /// only print errors & command output, no logs, status lines or progress bars
+    #[arg(long, short, global = true, default_value_t = false)]
+    pub quiet: bool,
+
+    /// config file with flag defaults, ~/.config/rustnsoverlay/config.toml is read if present
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Command,
 }
 
-#[derive(Parser, Debug)]
-#[command(disable_version_flag = true, about, long_about = None)]
-pub struct Args {
-    /// Docker container ID
-    #[arg()]
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// start a debug session in a container, implied when the first argument is a container id
+    Run(Box<RunArgs>),
+    /// print the resolved container info & exit
+    Inspect(InspectArgs),
+    /// copy a file or directory out of a running container onto the host
+    Cp(CpArgs),
+    /// unmount & remove the workdir and/or the cache, refuses while a session is using them
+    Clean(CleanArgs),
+    /// manage the rootfs cache
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// print a shell completion script to stdout
+    #[command(hide = true)]
+    Completions {
+        /// shell to complete for
+        shell: clap_complete::Shell,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    /// Docker container ID, name or ID prefix
     pub id: String,
 
-    /// force repull image
+    /// container engine: docker, or podman through its docker compatible API
+    #[arg(long, value_enum, default_value_t = RuntimeKind::Docker)]
+    pub runtime: RuntimeKind,
+
+    /// docker daemon endpoint, e.g. unix:///var/run/docker.sock or tcp://host:2375, defaults to the runtime's socket
+    #[arg(long, env = "DOCKER_HOST")]
+    pub docker_host: Option<String>,
+
+    /// print a table, or json on stdout with logs on stderr
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    pub output: OutputFormat,
+}
+
+#[derive(Args, Debug)]
+pub struct CpArgs {
+    /// Docker container ID, name or ID prefix
+    pub id: String,
+
+    /// path inside the container
+    pub container_path: String,
+
+    /// host path, an existing directory receives the source by name
+    pub host_path: PathBuf,
+
+    /// container engine: docker, or podman through its docker compatible API
+    #[arg(long, value_enum, default_value_t = RuntimeKind::Docker)]
+    pub runtime: RuntimeKind,
+
+    /// docker daemon endpoint, e.g. unix:///var/run/docker.sock or tcp://host:2375, defaults to the runtime's socket
+    #[arg(long, env = "DOCKER_HOST")]
+    pub docker_host: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct CleanArgs {
+    /// workdir
+    #[arg(short, long, default_value = "/var/lib/rustnsoverlay/work")]
+    pub workdir: String,
+
+    /// work cache directory
+    #[arg(long, default_value_t = String::from("/var/cache/rustnsoverlay"))]
+    pub cache_dir: String,
+
+    /// clear the cache directory instead of the workdir
+    #[arg(long, default_value_t = false, conflicts_with = "all")]
+    pub cache: bool,
+
+    /// clear both the workdir & the cache directory
     #[arg(long, default_value_t = false)]
+    pub all: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// list cache entries, least recently used first
+    Ls {
+        /// work cache directory
+        #[arg(long, default_value_t = String::from("/var/cache/rustnsoverlay"))]
+        cache_dir: String,
+    },
+    /// remove cache entries by file name
+    Rm {
+        /// work cache directory
+        #[arg(long, default_value_t = String::from("/var/cache/rustnsoverlay"))]
+        cache_dir: String,
+
+        /// cache file names as shown by `cache ls`
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+}
+
+impl Cli {
+    // a bare container id or run flag implies `run`, as before subcommands existed
+    pub fn parse_compat() -> Result<Self> {
+        let mut args = std::env::args_os().collect::<Vec<_>>();
+        if let Some(first) = args.get(1).and_then(|arg| arg.to_str())
+            && !SUBCOMMANDS.contains(&first)
+            && !TOP_LEVEL_FLAGS.contains(&first)
+        {
+            args.insert(1, OsString::from("run"));
+        }
+        let matches = Cli::command().get_matches_from(args);
+        let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        let config = FileConfig::load(cli.config.as_deref())?;
+        cli.apply_config(&config, &matches);
+        Ok(cli)
+    }
+
+    // fill flags left at their default from the config file
+    fn apply_config(&mut self, config: &FileConfig, matches: &clap::ArgMatches) {
+        if let Some(log_level) = &config.log_level
+            && is_default(matches, "log_level")
+        {
+            self.log_level = log_level.clone();
+        }
+        let Some((_, sub_matches)) = matches.subcommand() else {
+            return;
+        };
+        match &mut self.command {
+            Command::Run(args) => args.apply_config(config, sub_matches),
+            Command::Inspect(InspectArgs { docker_host, .. })
+            | Command::Cp(CpArgs { docker_host, .. }) => {
+                if let Some(config_docker_host) = &config.docker_host
+                    && is_default(sub_matches, "docker_host")
+                {
+                    *docker_host = Some(config_docker_host.clone());
+                }
+            }
+            Command::Clean(args) => args.apply_config(config, sub_matches),
+            Command::Completions { .. } => {}
+            Command::Cache(command) => {
+                let Some((_, cache_matches)) = sub_matches.subcommand() else {
+                    return;
+                };
+                let (CacheCommand::Ls { cache_dir } | CacheCommand::Rm { cache_dir, .. }) = command;
+                if let Some(config_cache_dir) = &config.cache_dir
+                    && is_default(cache_matches, "cache_dir")
+                {
+                    *cache_dir = config_cache_dir.clone();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Docker container ID, name or ID prefix
+    #[arg()]
+    pub id: String,
+
+    /// container engine: docker, or podman through its docker compatible API
+    #[arg(long, value_enum, default_value_t = RuntimeKind::Docker)]
+    pub runtime: RuntimeKind,
+
+    /// docker daemon endpoint, e.g. unix:///var/run/docker.sock or tcp://host:2375, defaults to the runtime's socket
+    #[arg(long, env = "DOCKER_HOST")]
+    pub docker_host: Option<String>,
+
+    /// limit on every docker API call, for pulls the wait for progress, e.g. 30s
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    pub docker_timeout: Duration,
+
+    /// force repull image, same as --pull-policy always
+    #[arg(long, default_value_t = false, conflicts_with = "pull_policy")]
     pub pull: bool,
 
+    /// when to pull the image: always, missing (no rootfs cache & no local image) or never
+    #[arg(long, value_enum, default_value_t = PullPolicy::Missing)]
+    pub pull_policy: PullPolicy,
+
+    /// registry credentials as <user>:<password> for every pull, defaults to the docker login ones
+    #[arg(long, env = "REGISTRY_AUTH", hide_env_values = true)]
+    pub registry_auth: Option<RegistryAuth>,
+
+    /// registry identity token for every pull
+    #[arg(
+        long,
+        env = "REGISTRY_TOKEN",
+        hide_env_values = true,
+        conflicts_with = "registry_auth"
+    )]
+    pub registry_token: Option<String>,
+
+    /// retries for transient pull failures, auth & not found errors fail right away
+    #[arg(long, default_value_t = 3)]
+    pub pull_retries: u32,
+
     /// workdir
     #[arg(short, long, default_value = "/var/lib/rustnsoverlay/work")]
     pub workdir: String,
 
-    /// image to act as rootfs
-    #[arg(long, default_value_t = String::from("debian:12"))]
-    pub image: String,
+    /// wait for another session using the workdir to end instead of failing
+    #[arg(long, default_value_t = false)]
+    pub wait_lock: bool,
+
+    /// image to act as rootfs, repeat to stack images with later ones taking precedence
+    #[arg(long, default_value = "debian:12")]
+    pub image: Vec<String>,
+
+    /// pick the image matching the container's distro from its os-release, e.g. alpine:3.20, falls back to --image's default
+    #[arg(long, default_value_t = false, conflicts_with_all = ["image", "image_file", "oci_layout"])]
+    pub auto_image: bool,
+
+    /// `docker save` tar to act as rootfs instead of pulling, --image picks the image of a tar holding several
+    #[arg(long)]
+    pub image_file: Option<String>,
+
+    /// OCI image layout directory to act as rootfs instead of pulling, e.g. from skopeo copy ... oci:<dir>
+    #[arg(long, conflicts_with_all = ["image", "image_file"])]
+    pub oci_layout: Option<PathBuf>,
+
+    /// image platform as os/arch[/variant], default to the host platform
+    #[arg(long)]
+    pub platform: Option<String>,
+
+    /// fail instead of warning when the image isn't built for the host's architecture
+    #[arg(long, default_value_t = false)]
+    pub strict_arch: bool,
+
+    /// extract each layer once into <cache-dir>/layers & stack them as overlay lower dirs, shared layers are reused across images, replaces the rootfs cache
+    #[arg(long, default_value_t = false)]
+    pub layer_cache: bool,
+
+    /// skip checking layer blobs against their manifest digests
+    #[arg(long, default_value_t = false)]
+    pub no_verify_layers: bool,
 
     /// reuse image cache
     #[arg(long, default_value_t = true)]
     pub cache: bool,
 
+    /// cache key, digest invalidates the cache when the tag moves
+    #[arg(long, value_enum, default_value_t = CacheKey::Tag)]
+    pub cache_key: CacheKey,
+
+    /// ignore cache entries older than this, e.g. 7d, 0 never expires
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub cache_ttl: Option<Duration>,
+
+    /// verify the cache checksum before extracting it
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub verify_cache: bool,
+
     /// work cache directory
     #[arg(long, default_value_t = String::from("/var/cache/rustnsoverlay"))]
     pub cache_dir: String,
 
+    /// max total size of the cache directory in bytes, least recently used entries are removed
+    #[arg(long)]
+    pub cache_max_size: Option<u64>,
+
+    /// container namespaces to enter, comma separated: all, cgroup, ipc, net, pid, time, uts. all includes time only if the container has its own
+    #[arg(long, value_delimiter = ',', default_value = "all")]
+    pub ns: Vec<String>,
+
+    /// only mount the container fs & stay in the host namespaces, works on stopped containers
+    #[arg(long, default_value_t = false)]
+    pub fs_only: bool,
+
+    /// network namespace of the shell, host keeps the host's so tools reach the host network & local mirrors, the container's interfaces aren't visible then
+    #[arg(long, value_enum, default_value_t = NetworkMode::Container, conflicts_with = "fs_only")]
+    pub network: NetworkMode,
+
+    /// move the session into the container's cgroup so its resource limits apply, needs cgroup v2
+    #[arg(long, default_value_t = false, conflicts_with = "fs_only")]
+    pub join_cgroup: bool,
+
+    /// wait up to this long for a created or restarting container to run, e.g. 30s
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub wait: Option<Duration>,
+
+    /// end the session once the container stops
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub exit_with_container: bool,
+
     /// container fs mount path inside debug rootfs
     #[arg(long, default_value_t = String::from("/mnt/container"))]
     pub container_mount_path: String,
 
-    /// unmount mergedfs on exit
-    #[arg(long, default_value_t = true)]
+    /// mount the container fs readonly so the debug shell can't modify the live container
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub readonly_container: bool,
+
+    /// bind mount a host path into the rootfs as <host>:<dest>[:ro], repeatable
+    #[arg(long = "mount", value_name = "HOST:DEST[:ro]")]
+    pub mounts: Vec<HostMount>,
+
+    /// bind the container's volumes & bind mounts under the container mount, readonly if either is
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub mount_volumes: bool,
+
+    /// extra rootfs overlay mount option, e.g. userxattr or redirect_dir=off, repeatable
+    #[arg(long = "overlay-opt", value_name = "KEY[=VAL]")]
+    pub overlay_opts: Vec<String>,
+
+    /// bind mount the extracted rootfs instead of an overlay, for hosts without overlay support, changes land in the rootfs
+    #[arg(long, default_value_t = false, conflicts_with_all = ["overlay_opts", "layer_cache"])]
+    pub no_overlay: bool,
+
+    /// mount a fresh proc on /proc inside the rootfs
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub mount_proc: bool,
+
+    /// mount sysfs on /sys inside the rootfs
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub mount_sys: bool,
+
+    /// bind the host /dev on /dev inside the rootfs
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub mount_dev: bool,
+
+    /// copy the container's resolv.conf, or the host's, into the rootfs
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub copy_resolv_conf: bool,
+
+    /// install an embedded static busybox & its applets into the rootfs /bin, needs the busybox feature
+    #[arg(long, default_value_t = false)]
+    pub with_busybox: bool,
+
+    /// let busybox applets replace the image's own binaries
+    #[arg(long, default_value_t = false, requires = "with_busybox")]
+    pub force: bool,
+
+    /// apply the image's Env & WorkingDir to the shell
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub apply_image_env: bool,
+
+    /// run the shell as the image's User, needs setpriv in the rootfs
+    #[arg(long, default_value_t = false)]
+    pub apply_image_user: bool,
+
+    /// start the shell in the working directory of the container's main process, under the container mount
+    #[arg(long, default_value_t = false)]
+    pub cd_container: bool,
+
+    /// export the container process's environment into the shell, except PATH, HOME & the shell's own variables. secrets come along too
+    #[arg(long, default_value_t = false, conflicts_with = "fs_only")]
+    pub inherit_env: bool,
+
+    /// with --inherit-env, inherit PATH, HOME & the shell's own variables as well
+    #[arg(long, default_value_t = false, requires = "inherit_env")]
+    pub inherit_env_all: bool,
+
+    /// unmount mergedfs on exit, false keeps it mounted on the host
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
     pub unmount_on_exit: bool,
+
+    /// leave every mount & the workdir in place after the session for inspection, tear down with clean
+    #[arg(long, default_value_t = false)]
+    pub keep: bool,
+
+    /// make the session mounts in a private mount namespace so they vanish with the session, needs --unmount-on-exit
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub private_mounts: bool,
+
+    /// on exit, archive the debug rootfs changes as a tar layer diff with whiteouts, container writes aren't included
+    #[arg(long, value_name = "PATH")]
+    pub save_diff: Option<PathBuf>,
+
+    /// shell to start inside the rootfs
+    #[arg(long, default_value_t = String::from("/usr/bin/bash"))]
+    pub shell: String,
+
+    /// init script to run before the shell, relative to the rootfs root
+    #[arg(long, default_value_t = String::from("init.sh"))]
+    pub init_file: String,
+
+    /// startup file for the interactive shell in place of the image's ~/.bashrc, e.g. for aliases or PS1
+    #[arg(long, value_name = "PATH")]
+    pub rc_file: Option<PathBuf>,
+
+    /// source the image's own ~/.bashrc before --rc-file instead of replacing it
+    #[arg(long, default_value_t = false, requires = "rc_file")]
+    pub rc_append: bool,
+
+    /// run the shell on the inherited terminal instead of a pty, e.g. for piped input
+    #[arg(long, default_value_t = false)]
+    pub no_pty: bool,
+
+    /// command to run instead of an interactive shell, resolved via PATH inside the rootfs
+    #[arg(last = true)]
+    pub command: Vec<String>,
+
+    /// don't check for CAP_SYS_ADMIN up front, e.g. when capabilities are granted in unusual ways
+    #[arg(long, default_value_t = false)]
+    pub skip_privilege_check: bool,
+
+    /// print every mount, namespace & cache action without performing it
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// max concurrent layer decompression jobs
+    #[arg(short, long, default_value_t = default_jobs())]
+    pub jobs: usize,
+
+    /// progress output, json emits newline delimited events on stdout & logs on stderr
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    pub output: OutputFormat,
+}
+
+fn default_jobs() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
 }
 
-impl Args {
-    pub fn image_cache_filename(&self) -> String {
-        let (image_name, tag) = self
-            .image
-            .split_once(":")
-            .unwrap_or((&self.image, "latest"));
-        let image_name = image_name.replace("/", "_");
-        return format!("{}_{}.tar", image_name, tag);
+impl RunArgs {
+    pub fn session_builder(&self) -> DebugSessionBuilder {
+        DebugSession::builder()
+            .container_id(&self.id)
+            .runtime(self.runtime)
+            .docker_host(self.docker_host.clone())
+            .docker_timeout(self.docker_timeout)
+            .image(&self.image[0])
+            .auto_image(self.auto_image)
+            .stack_images(self.image[1..].to_vec())
+            .image_file(self.image_file.as_ref().map(PathBuf::from))
+            .oci_layout(self.oci_layout.clone())
+            .platform(self.platform.clone())
+            .strict_arch(self.strict_arch)
+            .pull_policy(self.pull_policy)
+            .pull(self.pull)
+            .pull_retries(self.pull_retries)
+            .registry_auth(
+                self.registry_auth
+                    .clone()
+                    .or_else(|| self.registry_token.clone().map(RegistryAuth::Token)),
+            )
+            .verify_layers(!self.no_verify_layers)
+            .layer_cache(self.layer_cache)
+            .jobs(self.jobs)
+            .workdir(&self.workdir)
+            .wait_lock(self.wait_lock)
+            .cache(self.cache)
+            .cache_dir(&self.cache_dir)
+            .cache_key(self.cache_key)
+            .cache_ttl(self.cache_ttl)
+            .cache_max_size(self.cache_max_size)
+            .verify_cache(self.verify_cache)
+            .namespaces(self.ns.clone())
+            .fs_only(self.fs_only)
+            .network(self.network)
+            .join_cgroup(self.join_cgroup)
+            .wait(self.wait)
+            .exit_with_container(self.exit_with_container)
+            .container_mount_path(&self.container_mount_path)
+            .readonly_container(self.readonly_container)
+            .mounts(self.mounts.clone())
+            .mount_volumes(self.mount_volumes)
+            .overlay_opts(self.overlay_opts.clone())
+            .no_overlay(self.no_overlay)
+            .unmount_on_exit(self.unmount_on_exit)
+            .keep(self.keep)
+            .private_mounts(self.private_mounts)
+            .save_diff(self.save_diff.clone())
+            .shell(&self.shell)
+            .init_file(&self.init_file)
+            .rc_file(self.rc_file.clone())
+            .rc_append(self.rc_append)
+            .command(self.command.clone())
+            .pty(!self.no_pty)
+            .output(self.output)
+            .mount_proc(self.mount_proc)
+            .mount_sys(self.mount_sys)
+            .mount_dev(self.mount_dev)
+            .copy_resolv_conf(self.copy_resolv_conf)
+            .with_busybox(self.with_busybox)
+            .busybox_force(self.force)
+            .apply_image_env(self.apply_image_env)
+            .apply_image_user(self.apply_image_user)
+            .cd_container(self.cd_container)
+            .inherit_env(self.inherit_env)
+            .inherit_env_all(self.inherit_env_all)
     }
 }
@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{CleanArgs, RunArgs};
+
+// defaults for common flags, keys are the flag names in snake_case. precedence:
+// built-in defaults < config file < environment < command line
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub log_level: Option<String>,
+    pub docker_host: Option<String>,
+    pub workdir: Option<String>,
+    pub cache_dir: Option<String>,
+    pub image: Option<Vec<String>>,
+    pub platform: Option<String>,
+    pub pull_retries: Option<u32>,
+    pub cache_max_size: Option<u64>,
+    pub ns: Option<Vec<String>>,
+    pub container_mount_path: Option<String>,
+    pub readonly_container: Option<bool>,
+    pub overlay_opts: Option<Vec<String>>,
+    pub shell: Option<String>,
+    pub jobs: Option<usize>,
+}
+
+impl FileConfig {
+    // an explicit path must exist, the default one is optional
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_config_path() {
+                Some(path) => (path, false),
+                None => return Ok(FileConfig::default()),
+            },
+        };
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+                return Ok(FileConfig::default());
+            }
+            Err(e) => return Err(anyhow::anyhow!("failed to read {}: {}", path.display(), e)),
+        };
+        toml::from_str(&content).context(format!("failed to parse {}", path.display()))
+    }
+}
+
+// $XDG_CONFIG_HOME/rustnsoverlay/config.toml, falling back to ~/.config
+fn default_config_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("rustnsoverlay").join("config.toml"))
+}
+
+// config values only fill in flags left at their built-in default
+pub fn is_default(matches: &ArgMatches, id: &str) -> bool {
+    matches!(
+        matches.value_source(id),
+        None | Some(ValueSource::DefaultValue)
+    )
+}
+
+macro_rules! merge {
+    ($args:ident, $config:ident, $matches:ident, $($field:ident),+) => {
+        $(
+            if let Some(value) = &$config.$field
+                && is_default($matches, stringify!($field))
+            {
+                $args.$field = value.clone();
+            }
+        )+
+    };
+}
+
+macro_rules! merge_option {
+    ($args:ident, $config:ident, $matches:ident, $($field:ident),+) => {
+        $(
+            if let Some(value) = &$config.$field
+                && is_default($matches, stringify!($field))
+            {
+                $args.$field = Some(value.clone());
+            }
+        )+
+    };
+}
+
+impl RunArgs {
+    pub fn apply_config(&mut self, config: &FileConfig, matches: &ArgMatches) {
+        merge!(
+            self,
+            config,
+            matches,
+            workdir,
+            cache_dir,
+            pull_retries,
+            ns,
+            container_mount_path,
+            readonly_container,
+            overlay_opts,
+            shell,
+            jobs
+        );
+        merge_option!(self, config, matches, docker_host, platform, cache_max_size);
+        // an image file or oci layout replaces the image altogether
+        if self.image_file.is_none() && self.oci_layout.is_none() {
+            merge!(self, config, matches, image);
+        }
+    }
+}
+
+impl CleanArgs {
+    pub fn apply_config(&mut self, config: &FileConfig, matches: &ArgMatches) {
+        merge!(self, config, matches, workdir, cache_dir);
+    }
+}
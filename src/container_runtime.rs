@@ -0,0 +1,222 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::auth_helper::RegistryAuth;
+use crate::docker_helper::{ContainerInfo, DockerHelper, PullPolicy};
+use crate::events::EventEmitter;
+use crate::timing::PhaseTimings;
+
+/// Container engine holding the target container & the debug images.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum RuntimeKind {
+    /// docker engine API
+    #[default]
+    Docker,
+    /// podman's docker compatible API, on the rootful podman socket by default
+    Podman,
+}
+
+impl RuntimeKind {
+    // endpoint used when none is given, none leaves it to the client defaults
+    pub fn default_host(&self) -> Option<&'static str> {
+        match self {
+            RuntimeKind::Docker => None,
+            RuntimeKind::Podman => Some("unix:///run/podman/podman.sock"),
+        }
+    }
+}
+
+// everything a session asks of the container engine
+pub trait ContainerRuntime {
+    async fn ping(&self) -> Result<()>;
+
+    // require_running is off for fs only sessions
+    async fn get_container_info(
+        &self,
+        container_id: &str,
+        require_running: bool,
+    ) -> Result<ContainerInfo>;
+
+    async fn wait_running(&self, container_id: &str, timeout: Duration) -> Result<()>;
+
+    // config digest of a local image, none if it isn't there
+    async fn get_image_digest(&self, image: &str) -> Result<Option<String>>;
+
+    // pull as the policy says & write the image as a `docker save` tar into tmp_dir
+    async fn export_image_tar(
+        &self,
+        image: &str,
+        tmp_dir: &Path,
+        pull_policy: PullPolicy,
+        pull_retries: u32,
+        events: &EventEmitter,
+        timings: &PhaseTimings,
+    ) -> Result<PathBuf>;
+
+    // credentials for the next pulls from host, none drops earlier ones
+    fn set_registry_auth(&mut self, auth: Option<&RegistryAuth>, host: &str) -> Result<()>;
+}
+
+// docker & podman share the client, podman serves the docker API too
+pub fn connect(
+    kind: RuntimeKind,
+    host: Option<&str>,
+    api_timeout: Duration,
+) -> Result<impl ContainerRuntime> {
+    DockerHelper::new(host.or(kind.default_host()), api_timeout)
+}
+
+impl ContainerRuntime for DockerHelper {
+    async fn ping(&self) -> Result<()> {
+        DockerHelper::ping(self).await
+    }
+
+    async fn get_container_info(
+        &self,
+        container_id: &str,
+        require_running: bool,
+    ) -> Result<ContainerInfo> {
+        DockerHelper::get_container_info(self, container_id, require_running).await
+    }
+
+    async fn wait_running(&self, container_id: &str, timeout: Duration) -> Result<()> {
+        DockerHelper::wait_running(self, container_id, timeout).await
+    }
+
+    async fn get_image_digest(&self, image: &str) -> Result<Option<String>> {
+        DockerHelper::get_image_digest(self, image).await
+    }
+
+    async fn export_image_tar(
+        &self,
+        image: &str,
+        tmp_dir: &Path,
+        pull_policy: PullPolicy,
+        pull_retries: u32,
+        events: &EventEmitter,
+        timings: &PhaseTimings,
+    ) -> Result<PathBuf> {
+        DockerHelper::export_image_tar(
+            self,
+            image,
+            tmp_dir,
+            pull_policy,
+            pull_retries,
+            events,
+            timings,
+        )
+        .await
+    }
+
+    fn set_registry_auth(&mut self, auth: Option<&RegistryAuth>, host: &str) -> Result<()> {
+        DockerHelper::set_registry_auth(self, auth, host)
+    }
+}
+
+// canned answers & a log of every call, for tests of code generic over the
+// runtime
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    pub struct MockRuntime {
+        pub running: bool,
+        // image to config digest, missing images aren't local
+        pub digests: HashMap<String, String>,
+        pub calls: RefCell<Vec<String>>,
+    }
+
+    impl MockRuntime {
+        fn record(&self, call: String) {
+            self.calls.borrow_mut().push(call);
+        }
+    }
+
+    impl ContainerRuntime for MockRuntime {
+        async fn ping(&self) -> Result<()> {
+            self.record(String::from("ping"));
+            Ok(())
+        }
+
+        async fn get_container_info(
+            &self,
+            container_id: &str,
+            require_running: bool,
+        ) -> Result<ContainerInfo> {
+            self.record(format!("info {} {}", container_id, require_running));
+            if require_running && !self.running {
+                return Err(anyhow::anyhow!("container is not running"));
+            }
+            Ok(ContainerInfo {
+                id: container_id.to_string(),
+                pid: 42,
+                running: self.running,
+                ..ContainerInfo::default()
+            })
+        }
+
+        async fn wait_running(&self, container_id: &str, _timeout: Duration) -> Result<()> {
+            self.record(format!("wait {}", container_id));
+            Ok(())
+        }
+
+        async fn get_image_digest(&self, image: &str) -> Result<Option<String>> {
+            self.record(format!("digest {}", image));
+            Ok(self.digests.get(image).cloned())
+        }
+
+        async fn export_image_tar(
+            &self,
+            image: &str,
+            tmp_dir: &Path,
+            _pull_policy: PullPolicy,
+            _pull_retries: u32,
+            _events: &EventEmitter,
+            _timings: &PhaseTimings,
+        ) -> Result<PathBuf> {
+            self.record(format!("export {}", image));
+            Ok(tmp_dir.join("image.tar"))
+        }
+
+        fn set_registry_auth(&mut self, auth: Option<&RegistryAuth>, host: &str) -> Result<()> {
+            self.record(format!("auth {} {}", host, auth.is_some()));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockRuntime;
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn podman_defaults_to_its_socket() {
+        assert_eq!(RuntimeKind::Docker.default_host(), None);
+        assert_eq!(
+            RuntimeKind::Podman.default_host(),
+            Some("unix:///run/podman/podman.sock")
+        );
+    }
+
+    #[test]
+    fn mock_refuses_stopped_containers_when_running_is_required() {
+        let rt = Runtime::new().unwrap();
+        let runtime = MockRuntime::default();
+        assert!(rt.block_on(runtime.get_container_info("c1", true)).is_err());
+        let info = rt
+            .block_on(runtime.get_container_info("c1", false))
+            .unwrap();
+        assert_eq!(info.id, "c1");
+        assert!(!info.running);
+        assert_eq!(
+            *runtime.calls.borrow(),
+            vec!["info c1 true", "info c1 false"]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use std::fs::{File, read_dir};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::time::SystemTime;
+use tar::{Builder, EntryType, Header, HeaderMode};
+use tracing::warn;
+
+// markers written into the archive, same as image layers, see
+// https://github.com/opencontainers/image-spec/blob/main/layer.md#whiteouts
+const WHITEOUT_PREFIX: &str = ".wh.";
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+const OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+const REDIRECT_XATTR: &str = "trusted.overlay.redirect";
+
+// tar the entries of an overlay upper dir changed since `since` as a layer
+// diff: overlay whiteouts become `.wh.` files & opaque dirs get an opaque
+// marker. returns the number of entries written
+pub fn archive_upper_diff(upper_dir: &Path, since: SystemTime, diff_path: &Path) -> Result<usize> {
+    let since = since
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let f = File::create(diff_path).context(format!("failed to create {}", diff_path.display()))?;
+    let mut archive = Builder::new(f);
+    archive.mode(HeaderMode::Complete);
+    archive.follow_symlinks(false);
+    let count = append_changes(&mut archive, upper_dir, Path::new(""), since)?;
+    archive.finish()?;
+    Ok(count)
+}
+
+fn append_changes(
+    archive: &mut Builder<File>,
+    upper_dir: &Path,
+    relative_dir: &Path,
+    since: i64,
+) -> Result<usize> {
+    let mut entries = read_dir(upper_dir.join(relative_dir))?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    let mut count = 0;
+    for file_name in entries {
+        let relative_path = relative_dir.join(&file_name);
+        let path = upper_dir.join(&relative_path);
+        let metadata = path.symlink_metadata()?;
+        // ctime catches chmod & chown too, unchanged image files are older.
+        // directories are walked regardless, a change deep down leaves the
+        // parents untouched
+        let changed = metadata.ctime() >= since;
+
+        // a 0:0 char device is how overlay records a deletion
+        if metadata.file_type().is_char_device() && metadata.rdev() == 0 {
+            if changed {
+                let whiteout_name = format!("{}{}", WHITEOUT_PREFIX, file_name.to_string_lossy());
+                append_marker(archive, &relative_dir.join(whiteout_name), &metadata)?;
+                count += 1;
+            }
+            continue;
+        }
+
+        if metadata.is_dir() {
+            if changed {
+                archive.append_path_with_name(&path, &relative_path)?;
+                count += 1;
+                // an opaque dir was deleted & recreated, hiding what was below
+                if xattr::get(&path, OPAQUE_XATTR)?.as_deref() == Some(b"y") {
+                    append_marker(archive, &relative_path.join(WHITEOUT_OPAQUE), &metadata)?;
+                    count += 1;
+                }
+                if xattr::get(&path, REDIRECT_XATTR)?.is_some() {
+                    warn!(
+                        "{} is a renamed directory, the diff only has its changed entries",
+                        relative_path.display()
+                    );
+                }
+            }
+            count += append_changes(archive, upper_dir, &relative_path, since)?;
+        } else if changed {
+            archive.append_path_with_name(&path, &relative_path)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// empty regular file standing for a whiteout
+fn append_marker(
+    archive: &mut Builder<File>,
+    path: &Path,
+    metadata: &std::fs::Metadata,
+) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_metadata_in_mode(metadata, HeaderMode::Complete);
+    header.set_entry_type(EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_size(0);
+    archive.append_data(&mut header, path, std::io::empty())?;
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::docker_helper::ContainerInfo;
+use crate::utils;
+
+const OS_RELEASE_PATHS: [&str; 2] = ["etc/os-release", "usr/lib/os-release"];
+
+/// `ID` & `VERSION_ID` from a container's os-release.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsRelease {
+    pub id: String,
+    pub version_id: Option<String>,
+}
+
+impl std::fmt::Display for OsRelease {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.version_id {
+            Some(version_id) => write!(f, "{} {}", self.id, version_id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+// read from the merged dir, or the topmost layer holding one when only the
+// layers are known. none when missing or unreadable
+pub fn read_os_release(container_info: &ContainerInfo) -> Option<OsRelease> {
+    let dirs = match &container_info.merged_dir {
+        Some(merged_dir) => vec![merged_dir.as_str()],
+        None => container_info
+            .upper_dir
+            .iter()
+            .chain(container_info.lower_dir.iter())
+            .flat_map(|dirs| dirs.split(':'))
+            .collect(),
+    };
+    dirs.into_iter().find_map(|dir| {
+        OS_RELEASE_PATHS.iter().find_map(|path| {
+            let path = utils::rootfs_path(Path::new(dir), path).ok()?;
+            parse_os_release(&std::fs::read_to_string(path).ok()?)
+        })
+    })
+}
+
+fn parse_os_release(content: &str) -> Option<OsRelease> {
+    let fields = content
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim().trim_matches(['"', '\''])))
+        .collect::<HashMap<_, _>>();
+    let id = fields.get("ID").filter(|id| !id.is_empty())?;
+    Some(OsRelease {
+        id: id.to_string(),
+        version_id: fields
+            .get("VERSION_ID")
+            .filter(|version_id| !version_id.is_empty())
+            .map(|version_id| version_id.to_string()),
+    })
+}
+
+// official image matching a distro release, none for unknown distros
+pub fn debug_image_for(os_release: &OsRelease) -> Option<String> {
+    let version_id = os_release.version_id.as_deref();
+    // most images are tagged by major or major.minor version only
+    let major = version_id.and_then(|version_id| version_id.split('.').next());
+    let major_minor = version_id.map(|version_id| {
+        version_id
+            .splitn(3, '.')
+            .take(2)
+            .collect::<Vec<_>>()
+            .join(".")
+    });
+    let image = match os_release.id.as_str() {
+        "debian" => format!("debian:{}", major?),
+        "ubuntu" => format!("ubuntu:{}", version_id?),
+        "alpine" => format!("alpine:{}", major_minor?),
+        "fedora" => format!("fedora:{}", major?),
+        "rocky" => format!("rockylinux:{}", major?),
+        "almalinux" => format!("almalinux:{}", major?),
+        "centos" => format!("quay.io/centos/centos:stream{}", major?),
+        "rhel" => format!("registry.access.redhat.com/ubi{}/ubi", major?),
+        "amzn" => format!("amazonlinux:{}", major?),
+        "opensuse-leap" => format!("opensuse/leap:{}", major_minor?),
+        "arch" => String::from("archlinux:latest"),
+        _ => return None,
+    };
+    Some(image)
+}
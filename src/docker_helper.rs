@@ -1,18 +1,46 @@
 use anyhow::{Context, Result};
 use dockworker::Docker;
+use dockworker::container::ContainerFilters;
+use dockworker::credentials::{Credential, IdentityToken, UserPassword};
 use dockworker::image::ListImageFilters;
 use dockworker::response::Response;
+use flate2::read::GzDecoder;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
-use oci_spec::image::MediaType;
+use oci_spec::image::{
+    Descriptor, ImageConfiguration, ImageIndex, ImageManifest, MediaType, Platform,
+};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
-use std::io::Read;
-use std::{collections::HashMap, path::Path};
+use std::future::Future;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use tar::Archive;
+use tracing::{debug, info, warn};
 
 use serde::{self, Deserialize, Serialize};
 
-use crate::utils;
+use crate::auth_helper::RegistryAuth;
+use crate::events::{Event, EventEmitter};
+use crate::mount_helper::HostMount;
+use crate::namespace_helper;
+use crate::progress_helper::{self, PullBars};
+use crate::timing::PhaseTimings;
+use crate::utils::{self, Compression};
+
+// first pull retry delay, doubled on every attempt
+const PULL_BACKOFF_BASE: Duration = Duration::from_secs(1);
+
+// delay between container state polls while waiting for it to run
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// registry address docker hub credentials are sent with
+const DOCKER_HUB_SERVER_ADDRESS: &str = "https://index.docker.io/v1/";
+
+// docker v2 schema media type, registry pulls are exported with this one
+const DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -22,190 +50,1885 @@ pub struct DockerManifestLayerSource {
     pub digest: String,
 }
 
+// `docker save` writes PascalCase keys, other exporters camelCase ones
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct DockerManifest {
+    #[serde(alias = "config")]
     pub config: String,
+    #[serde(alias = "repoTags", default)]
     pub repo_tags: Vec<String>,
+    #[serde(alias = "layers")]
     pub layers: Vec<String>,
+    #[serde(alias = "layerSources", default)]
     pub layer_sources: HashMap<String, DockerManifestLayerSource>,
 }
 
-#[derive(Debug, Default)]
+/// When the debug image is pulled.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum PullPolicy {
+    /// pull every time, the rootfs cache is rebuilt from the fresh image
+    Always,
+    /// pull only if neither the rootfs cache nor a local image exists
+    #[default]
+    Missing,
+    /// never pull, fail if the image is neither cached nor local
+    Never,
+}
+
+// os/arch[/variant] an image is selected for
+#[derive(Debug, Clone)]
+pub struct PlatformSpec {
+    pub os: String,
+    pub arch: String,
+    pub variant: Option<String>,
+}
+
+impl PlatformSpec {
+    pub fn parse(platform: &str) -> Result<Self> {
+        let mut parts = platform.split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(os), Some(arch), variant, None) if !os.is_empty() && !arch.is_empty() => {
+                Ok(PlatformSpec {
+                    os: os.to_string(),
+                    arch: arch.to_string(),
+                    variant: variant.map(|v| v.to_string()),
+                })
+            }
+            _ => Err(anyhow::anyhow!(
+                "invalid platform: {}, expect os/arch[/variant]",
+                platform
+            )),
+        }
+    }
+
+    // host platform using the GOARCH names images are tagged with
+    pub fn host() -> Self {
+        let arch = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" => "ppc64le",
+            "loongarch64" => "loong64",
+            arch => arch,
+        };
+        PlatformSpec {
+            os: std::env::consts::OS.to_string(),
+            arch: arch.to_string(),
+            variant: None,
+        }
+    }
+
+    fn matches(&self, platform: &Platform) -> bool {
+        let variant_matches = match &self.variant {
+            Some(variant) => platform.variant().as_ref() == Some(variant),
+            None => true,
+        };
+        platform.os().to_string() == self.os
+            && platform.architecture().to_string() == self.arch
+            && variant_matches
+    }
+}
+
+impl std::fmt::Display for PlatformSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.arch)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
 pub struct ContainerInfo {
+    pub id: String,
+    pub name: String,
+    pub image: String,
     pub pid: u64,
-    pub merged_dir: String,
+    pub running: bool,
+    pub driver: String,
+    pub merged_dir: Option<String>,
+    // overlay layers, used when the driver reports no MergedDir
+    pub upper_dir: Option<String>,
+    pub lower_dir: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    // namespace name to its /proc/<pid>/ns link, e.g. net:[4026531840],
+    // empty when the container isn't running
+    pub namespaces: BTreeMap<String, String>,
+    pub cgroup: Option<String>,
+    // cwd of the main process, none when the container isn't running
+    pub working_dir: Option<String>,
+    // named volumes & bind mounts, dest is the path inside the container.
+    // tmpfs mounts have no host source & are left out
+    pub volumes: Vec<HostMount>,
+}
+
+// runtime settings from the image config blob
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ImageConfig {
+    pub env: Vec<String>,
+    pub working_dir: Option<String>,
+    pub user: Option<String>,
+    // GOARCH name like amd64, missing from caches saved before it was recorded
+    pub architecture: Option<String>,
+}
+
+impl ImageConfig {
+    fn from_file(config_path: &Path) -> Result<Self> {
+        let image_config = ImageConfiguration::from_file(config_path)
+            .context(format!("failed to parse {}", config_path.display()))?;
+        let architecture = Some(image_config.architecture().to_string());
+        let Some(config) = image_config.config() else {
+            return Ok(ImageConfig {
+                architecture,
+                ..ImageConfig::default()
+            });
+        };
+        Ok(ImageConfig {
+            env: config.env().clone().unwrap_or_default(),
+            working_dir: config.working_dir().clone().filter(|dir| !dir.is_empty()),
+            user: config.user().clone().filter(|user| !user.is_empty()),
+            architecture,
+        })
+    }
 }
 
 pub struct DockerHelper {
     docker: Docker,
+    host: String,
+    docker_host: Option<String>,
+    has_credential: bool,
+    api_timeout: Duration,
 }
 
 impl DockerHelper {
-    pub fn new() -> Result<Self> {
-        let docker = Docker::connect_with_defaults()?;
-        Ok(DockerHelper { docker })
+    // api_timeout bounds every daemon call, for pulls the wait for the next
+    // progress update
+    pub fn new(docker_host: Option<&str>, api_timeout: Duration) -> Result<Self> {
+        let (docker, host) = connect(docker_host)?;
+        Ok(DockerHelper {
+            docker,
+            host,
+            docker_host: docker_host.map(String::from),
+            has_credential: false,
+            api_timeout,
+        })
+    }
+
+    // an unresponsive daemon would otherwise hang us for good
+    async fn with_timeout<T, E>(
+        &self,
+        action: &str,
+        call: impl Future<Output = std::result::Result<T, E>>,
+    ) -> Result<T>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        match tokio::time::timeout(self.api_timeout, call).await {
+            Ok(res) => res.context(action.to_string()),
+            Err(_) => Err(self.timeout_error(action)),
+        }
+    }
+
+    fn timeout_error(&self, action: &str) -> anyhow::Error {
+        anyhow::anyhow!(
+            "docker API timed out after {} on {}",
+            humantime::format_duration(self.api_timeout),
+            action
+        )
+    }
+
+    // credentials for the next pulls, none drops earlier ones so they never
+    // reach another registry
+    pub fn set_registry_auth(&mut self, auth: Option<&RegistryAuth>, host: &str) -> Result<()> {
+        let server_address = if host == "docker.io" {
+            DOCKER_HUB_SERVER_ADDRESS
+        } else {
+            host
+        };
+        match auth {
+            Some(RegistryAuth::Password { username, password }) => {
+                self.docker
+                    .set_credential(Credential::with_password(UserPassword::new(
+                        username.clone(),
+                        password.clone(),
+                        String::new(),
+                        server_address.to_string(),
+                    )));
+                self.has_credential = true;
+            }
+            Some(RegistryAuth::Token(token)) => {
+                self.docker
+                    .set_credential(Credential::with_token(IdentityToken::from(token.clone())));
+                self.has_credential = true;
+            }
+            // the client can't unset a credential, start over with a fresh one
+            None if self.has_credential => {
+                (self.docker, _) = connect(self.docker_host.as_deref())?;
+                self.has_credential = false;
+            }
+            None => {}
+        }
+        Ok(())
     }
 
-    pub async fn get_container_info(&self, container_id: &str) -> Result<ContainerInfo> {
-        let container_info = self
-            .docker
-            .container_info(container_id)
+    // connections are lazy, check the daemon up front for a readable error
+    pub async fn ping(&self) -> Result<()> {
+        self.with_timeout("ping", self.docker.ping())
             .await
-            .context("inspect container")?;
+            .context(format!("unable to reach docker daemon at {}", self.host))?;
+        Ok(())
+    }
 
-        if !container_info.State.Running {
+    // require_running is off for fs only sessions, a stopped container has no
+    // namespaces but its layers are still on disk
+    pub async fn get_container_info(
+        &self,
+        container_id: &str,
+        require_running: bool,
+    ) -> Result<ContainerInfo> {
+        let container_info = self.inspect_container(container_id).await?;
+
+        let running = container_info.State.Running;
+        if require_running && !running {
             return Err(anyhow::anyhow!("container is not running"));
         }
-        if container_info.Driver != "overlay2" {
-            return Err(anyhow::anyhow!(
-                "only overlay2 driver is supported, found: {}",
-                container_info.Driver
-            ));
-        }
 
         let pid = container_info.State.Pid as u64;
-        let merged_dir = container_info
-            .GraphDriver
-            .Data
-            .get("MergedDir")
-            .context("expect MergedDir in GraphDriver setting")?;
+        let graph_data = &container_info.GraphDriver.Data;
+        let (merged_dir, upper_dir, lower_dir) =
+            container_fs_dirs(&container_info.Driver, running, |key| {
+                graph_data.get(key).cloned()
+            })?;
+
+        let (namespaces, cgroup, working_dir) = if running {
+            (
+                namespace_helper::namespace_ids(pid),
+                namespace_helper::cgroup_path(pid),
+                namespace_helper::process_cwd(pid),
+            )
+        } else {
+            (BTreeMap::new(), None, None)
+        };
 
         Ok(ContainerInfo {
+            id: container_info.Id.clone(),
+            name: container_info.Name.trim_start_matches('/').to_string(),
+            image: container_info.Config.Image.clone(),
             pid,
-            merged_dir: merged_dir.to_string(),
+            running,
+            driver: container_info.Driver.clone(),
+            merged_dir,
+            upper_dir,
+            lower_dir,
+            labels: container_info
+                .Config
+                .Labels
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+            namespaces,
+            cgroup,
+            working_dir,
+            volumes: container_info
+                .Mounts
+                .iter()
+                .filter(|mount| !mount.Source.is_empty())
+                .map(|mount| HostMount {
+                    source: PathBuf::from(&mount.Source),
+                    dest: mount.Destination.clone(),
+                    readonly: !mount.RW,
+                })
+                .collect(),
         })
     }
 
-    pub async fn export_overlay_image(
+    // poll until the container runs, created & restarting containers are
+    // waited on, any other state fails right away
+    pub async fn wait_running(&self, container_id: &str, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let state = self.inspect_container(container_id).await?.State;
+            if state.Running {
+                return Ok(());
+            }
+            if state.Status != "created" && state.Status != "restarting" {
+                return Err(anyhow::anyhow!(
+                    "container is {}, not waiting for it to run",
+                    state.Status
+                ));
+            }
+            if Instant::now() >= deadline {
+                return Err(anyhow::anyhow!(
+                    "container still {} after waiting {}",
+                    state.Status,
+                    humantime::format_duration(timeout)
+                ));
+            }
+            debug!("container is {}, waiting for it to run", state.Status);
+            tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+        }
+    }
+
+    // exact lookup first, then a name or short id from `docker ps`
+    async fn inspect_container(
+        &self,
+        container_id: &str,
+    ) -> Result<dockworker::container::ContainerInfo> {
+        match tokio::time::timeout(self.api_timeout, self.docker.container_info(container_id)).await
+        {
+            Ok(Ok(container_info)) => Ok(container_info),
+            Ok(Err(_)) => {
+                let resolved_id = self.resolve_container_id(container_id).await?;
+                self.with_timeout(
+                    "inspect container",
+                    self.docker.container_info(&resolved_id),
+                )
+                .await
+            }
+            Err(_) => Err(self.timeout_error("inspect container")),
+        }
+    }
+
+    // full id of the container matching a name or short id prefix from `docker ps`
+    async fn resolve_container_id(&self, query: &str) -> Result<String> {
+        let containers = self
+            .with_timeout(
+                "list containers",
+                self.docker
+                    .list_containers(Some(true), None, None, ContainerFilters::default()),
+            )
+            .await?;
+        let candidates = containers
+            .into_iter()
+            .map(|container| (container.Id, container.Names))
+            .collect::<Vec<_>>();
+        match_container_id(&candidates, query)
+    }
+
+    // config digest of a local image, none if it hasn't been pulled yet
+    pub async fn get_image_digest(&self, image: &str) -> Result<Option<String>> {
+        let mut image_filter = ListImageFilters::default();
+        image_filter.reference = Some(vec![image.to_string()]);
+        let list_image_info = self
+            .with_timeout("list images", self.docker.images(false, Some(image_filter)))
+            .await?;
+        Ok(list_image_info.first().map(|info| info.Id.clone()))
+    }
+
+    // a digest pin must resolve to a local image carrying that repo digest,
+    // anything else means the daemon handed us a different image
+    async fn verify_pinned_digest(&self, image: &str) -> Result<()> {
+        let (repository, _, Some(pinned_digest)) = split_image_reference(image) else {
+            return Ok(());
+        };
+        let mut image_filter = ListImageFilters::default();
+        image_filter.reference = Some(vec![image.to_string()]);
+        let list_image_info = self
+            .with_timeout("list images", self.docker.images(false, Some(image_filter)))
+            .await?;
+        let repo_digests = list_image_info
+            .iter()
+            .flat_map(|info| info.RepoDigests.iter())
+            .collect::<Vec<_>>();
+        let expected = format!("{}@{}", repository, pinned_digest);
+        // the daemon may qualify the repository, e.g. docker.io/library/debian
+        if !repo_digests
+            .iter()
+            .any(|digest| **digest == expected || digest.ends_with(&format!("/{}", expected)))
+        {
+            return Err(anyhow::anyhow!(
+                "image {} does not match its pinned digest, local repo digests: {:?}",
+                image,
+                repo_digests
+            ));
+        }
+        debug!("image {} matches its pinned digest", image);
+        Ok(())
+    }
+
+    // errors reported inside the progress stream fail the pull as well
+    async fn pull_image(&self, image: &str, events: &EventEmitter) -> Result<()> {
+        let (repository, tag, pinned_digest) = split_image_reference(image);
+        let tag = pinned_digest.or(tag).unwrap_or("latest");
+        let mut download_stats = self
+            .with_timeout("pull image", self.docker.create_image(repository, tag))
+            .await?;
+        let mut pull_bars = PullBars::new(events);
+        // a pull may take long, but the daemon reports progress all along
+        loop {
+            let Some(stat) = tokio::time::timeout(self.api_timeout, download_stats.next())
+                .await
+                .map_err(|_| self.timeout_error("pull image"))?
+            else {
+                break;
+            };
+            match stat? {
+                Response::Status(status) => {
+                    debug!("{}", status.status);
+                }
+                Response::Progress(progress) => {
+                    if let Some(detail) = &progress.progress_detail {
+                        pull_bars.update(&progress.id, detail.current, detail.total);
+                        events.emit(Event::PullProgress {
+                            layer: &progress.id,
+                            done: detail.current,
+                            total: detail.total,
+                        });
+                    }
+                    if let Some(p) = progress.progress {
+                        debug!("{}", p);
+                    } else {
+                        debug!("{}", progress.status);
+                    }
+                }
+                Response::Error(err) => {
+                    return Err(anyhow::anyhow!("pull failed: {err:?}"));
+                }
+                _ => {}
+            }
+        }
+        pull_bars.finish();
+        Ok(())
+    }
+
+    // pull image as the policy says & export it as a `docker save` tar into
+    // tmp_dir, see extract_image_tar for applying it
+    pub async fn export_image_tar(
         &self,
         image: &str,
         tmp_dir: &Path,
-        export_dir: &Path,
-        pull: bool,
-    ) -> Result<()> {
+        pull_policy: PullPolicy,
+        pull_retries: u32,
+        events: &EventEmitter,
+        timings: &PhaseTimings,
+    ) -> Result<PathBuf> {
         // check image exist
         let mut image_filter = ListImageFilters::default();
         image_filter.reference = Some(vec![image.to_string()]);
         let list_image_info = self
-            .docker
-            .images(false, Some(image_filter))
-            .await
-            .context("list images")?;
-
-        if list_image_info.len() == 0 || pull {
-            println!("pulling overlay image: {}", image);
-            let (image_name, tag) = image.split_once(":").unwrap_or((image, "latest"));
-            let mut download_stats = self.docker.create_image(image_name, tag).await?;
-            while let Some(Ok(stat)) = download_stats.next().await {
-                match stat {
-                    Response::Status(status) => {
-                        println!("{}", status.status);
-                    }
-                    Response::Progress(progress) => {
-                        if let Some(p) = progress.progress {
-                            println!("{}", p);
-                        } else {
-                            println!("{}", progress.status);
-                        }
-                    }
-                    Response::Error(err) => {
-                        println!("error: {err:?}");
-                    }
-                    _ => {}
+            .with_timeout("list images", self.docker.images(false, Some(image_filter)))
+            .await?;
+
+        if list_image_info.is_empty() && pull_policy == PullPolicy::Never {
+            return Err(anyhow::anyhow!(
+                "image {} is neither cached nor available locally & the pull policy is never",
+                image
+            ));
+        }
+        if list_image_info.is_empty() || pull_policy == PullPolicy::Always {
+            let pull_start = Instant::now();
+            let mut attempt = 0;
+            loop {
+                info!("pulling overlay image: {}", image);
+                let Err(err) = self.pull_image(image, events).await else {
+                    break;
+                };
+                if attempt >= pull_retries || !is_retryable_pull_error(&err) {
+                    return Err(err);
                 }
+                attempt += 1;
+                let backoff = PULL_BACKOFF_BASE * 2u32.pow(attempt - 1);
+                warn!(
+                    "pull failed: {:#}, retry {}/{} in {:?}",
+                    err, attempt, pull_retries, backoff
+                );
+                tokio::time::sleep(backoff).await;
             }
+            timings.record("pull", pull_start.elapsed());
         }
+        self.verify_pinned_digest(image).await?;
 
         // TODO: optimsie with tar stream decompression in memory?
         let tar_path = tmp_dir.join("temp.tar");
         {
-            println!("exporting overlay image: {}", image);
+            info!("exporting overlay image: {}", image);
+            let export_start = Instant::now();
             let mut tmp_file = tokio::fs::File::create(&tar_path).await?;
             let img_res = self
-                .docker
-                .export_image(image)
-                .await
-                .context("unable to export image")?;
+                .with_timeout("export image", self.docker.export_image(image))
+                .await?;
             let mut res = tokio_util::io::StreamReader::new(
                 img_res.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err)),
             );
-            tokio::io::copy(&mut res, &mut tmp_file).await.unwrap();
-        }
-
-        // manifest
-        let mut manifest: Vec<DockerManifest> = Vec::new();
-        let mut blob: HashMap<String, Vec<u8>> = HashMap::new();
-        println!("extracting raw overlay image to memory: {}", image);
-        let mut tar_archive = Archive::new(File::open(&tar_path)?);
-        for file in tar_archive.entries().unwrap() {
-            let mut tar_file = file?;
-            let path = tar_file.path()?;
-            let dst_path = tmp_dir.join(&path);
-
-            match tar_file.header().entry_type() {
-                tar::EntryType::Regular => {
-                    if path.ends_with("manifest.json") {
-                        manifest = serde_json::from_reader(&mut tar_file)?;
-                    } else if path.starts_with("blobs/sha256/") {
-                        let entry_name = path.to_str().unwrap().to_string();
-                        let mut content_buffer = Vec::new();
-                        tar_file.read_to_end(&mut content_buffer)?;
-                        blob.insert(entry_name, content_buffer);
-                    } else {
-                        let mut dst_file = File::create(dst_path)?;
-                        std::io::copy(&mut tar_file, &mut dst_file)?;
+            tokio::io::copy(&mut res, &mut tmp_file).await?;
+            timings.record("export", export_start.elapsed());
+        }
+        Ok(tar_path)
+    }
+}
+
+// docker_host accepts unix://, tcp:// and http:// endpoints, none uses the defaults
+fn connect(docker_host: Option<&str>) -> Result<(Docker, String)> {
+    let Some(host) = docker_host else {
+        let docker = Docker::connect_with_defaults()?;
+        return Ok((docker, String::from("default docker socket")));
+    };
+
+    let docker = if let Some(socket_path) = host.strip_prefix("unix://") {
+        Docker::connect_with_unix(socket_path)?
+    } else if let Some(addr) = host.strip_prefix("tcp://") {
+        Docker::connect_with_http(&format!("http://{}", addr))?
+    } else if host.starts_with("http://") {
+        Docker::connect_with_http(host)?
+    } else {
+        return Err(anyhow::anyhow!(
+            "unsupported docker host: {}, expect unix://, tcp:// or http://",
+            host
+        ));
+    };
+    Ok((docker, host.to_string()))
+}
+
+// repository, tag & pinned digest of an image reference, the tag separator is
+// only looked for after the last `/` so a registry port isn't taken for a tag
+pub fn split_image_reference(image: &str) -> (&str, Option<&str>, Option<&str>) {
+    let (name, pinned_digest) = match image.split_once('@') {
+        Some((name, digest)) => (name, Some(digest)),
+        None => (image, None),
+    };
+    let name_start = name.rfind('/').map_or(0, |idx| idx + 1);
+    match name[name_start..].rfind(':') {
+        Some(idx) => (
+            &name[..name_start + idx],
+            Some(&name[name_start + idx + 1..]),
+            pinned_digest,
+        ),
+        None => (name, None, pinned_digest),
+    }
+}
+
+// auth & missing image errors won't go away by retrying, anything else is
+// assumed to be a network or registry hiccup
+fn is_retryable_pull_error(err: &anyhow::Error) -> bool {
+    let message = format!("{:#}", err).to_lowercase();
+    ![
+        "unauthorized",
+        "denied",
+        "authentication required",
+        "not found",
+        "manifest unknown",
+        "no such image",
+        "invalid reference",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
+
+// merged, upper & lower dir of the container fs. overlay2 & fuse-overlayfs
+// expose the assembled rootfs, some drivers only report the layers, which
+// can be assembled into a merged view
+fn container_fs_dirs(
+    driver: &str,
+    running: bool,
+    graph_data: impl Fn(&str) -> Option<String>,
+) -> Result<(Option<String>, Option<String>, Option<String>)> {
+    let upper_dir = graph_data("UpperDir");
+    let lower_dir = graph_data("LowerDir");
+    // the driver unmounts MergedDir once the container stops, leaving it empty
+    let merged_dir =
+        graph_data("MergedDir").filter(|_| running || upper_dir.is_none() || lower_dir.is_none());
+    if merged_dir.is_none() && (upper_dir.is_none() || lower_dir.is_none()) {
+        return Err(anyhow::anyhow!(
+            "storage driver {} has neither MergedDir nor UpperDir & LowerDir to mount, supported drivers: overlay2, fuse-overlayfs",
+            driver
+        ));
+    }
+    Ok((merged_dir, upper_dir, lower_dir))
+}
+
+// exact name wins, otherwise the id prefix must be unambiguous
+fn match_container_id(candidates: &[(String, Vec<String>)], query: &str) -> Result<String> {
+    let query_name = query.trim_start_matches("/");
+    if let Some((id, _)) = candidates.iter().find(|(_, names)| {
+        names
+            .iter()
+            .any(|name| name.trim_start_matches("/") == query_name)
+    }) {
+        return Ok(id.clone());
+    }
+    let matched = candidates
+        .iter()
+        .filter(|(id, _)| id.starts_with(query))
+        .map(|(id, _)| id.as_str())
+        .collect::<Vec<_>>();
+    match matched.as_slice() {
+        [] => Err(anyhow::anyhow!("no container found matching: {}", query)),
+        [id] => Ok(id.to_string()),
+        _ => Err(anyhow::anyhow!(
+            "container id prefix {} is ambiguous, matches: {}",
+            query,
+            matched.join(", ")
+        )),
+    }
+}
+
+// where image layers are extracted to
+pub enum LayerTarget<'a> {
+    // applied one after the other onto a single rootfs dir
+    Rootfs(&'a Path),
+    // each layer into its own overlay lower dir under <dir>/<digest hex>,
+    // layers already there are reused
+    LayerCache(&'a Path),
+}
+
+// apply the layers of a `docker save` tar onto target, tmp_dir holds the
+// intermediate blobs. image picks the entry of a tar holding several images.
+// returns the image config & the cached layer dirs from the bottom up, none
+// for a rootfs target
+pub fn extract_image_tar(
+    reader: impl Read,
+    image: &str,
+    tmp_dir: &Path,
+    target: &LayerTarget,
+    platform: &PlatformSpec,
+    jobs: usize,
+    verify_layers: bool,
+    events: &EventEmitter,
+) -> Result<(ImageConfig, Vec<PathBuf>)> {
+    // manifest, layer blobs are written to tmp_dir and opened one at a time,
+    // hashed on the way so verification needs no second pass
+    let mut manifest: Vec<DockerManifest> = Vec::new();
+    let mut blob_digests: HashMap<PathBuf, String> = HashMap::new();
+    std::fs::create_dir_all(tmp_dir)?;
+    let canonical_tmp_dir = tmp_dir.canonicalize()?;
+    let mut tar_archive = Archive::new(reader);
+    for file in tar_archive.entries()? {
+        let mut tar_file = file?;
+        let path = tar_file.path()?.into_owned();
+        // image tars can come from any file, never write outside tmp_dir
+        let dst_path = utils::safe_join(tmp_dir, &canonical_tmp_dir, &path)
+            .context(format!("malformed image tar entry: {}", path.display()))?;
+
+        match tar_file.header().entry_type() {
+            tar::EntryType::Regular => {
+                if path.ends_with("manifest.json") {
+                    manifest = serde_json::from_reader(&mut tar_file)?;
+                } else {
+                    if let Some(parent) = dst_path.parent() {
+                        std::fs::create_dir_all(parent)?;
                     }
+                    let mut dst_file = utils::Sha256Writer::new(File::create(&dst_path)?);
+                    std::io::copy(&mut tar_file, &mut dst_file)?;
+                    blob_digests.insert(dst_path, dst_file.finish());
                 }
-                tar::EntryType::Directory => {
-                    tokio::fs::create_dir_all(dst_path).await?;
+            }
+            tar::EntryType::Directory => {
+                std::fs::create_dir_all(dst_path)?;
+            }
+            _ => warn!(
+                "skipping entry type: {:?} for {}",
+                tar_file.header().entry_type(),
+                dst_path.display()
+            ),
+        }
+    }
+
+    // layer blobs go as their layers are applied, this clears the rest & what
+    // a failed apply leaves behind
+    let blob_paths = blob_digests.keys().cloned().collect::<HashSet<_>>();
+    let applied = apply_image_layers(
+        tmp_dir,
+        &manifest,
+        Some(image),
+        blob_digests,
+        &blob_paths,
+        &tmp_dir.join("staging"),
+        target,
+        platform,
+        jobs,
+        verify_layers,
+        events,
+    );
+    for blob_path in blob_paths {
+        let _ = std::fs::remove_file(blob_path);
+    }
+    applied
+}
+
+// apply the image of an OCI image layout directory, e.g. from `skopeo copy
+// ... oci:<dir>`, onto target. the layout is only read, tmp_dir holds the
+// decompressed layers
+pub fn extract_oci_layout(
+    layout_dir: &Path,
+    tmp_dir: &Path,
+    target: &LayerTarget,
+    platform: &PlatformSpec,
+    jobs: usize,
+    verify_layers: bool,
+    events: &EventEmitter,
+) -> Result<(ImageConfig, Vec<PathBuf>)> {
+    if !layout_dir.join("index.json").exists() {
+        return Err(anyhow::anyhow!(
+            "{} is not an oci layout, index.json not found",
+            layout_dir.display()
+        ));
+    }
+    apply_image_layers(
+        layout_dir,
+        &[],
+        None,
+        HashMap::new(),
+        &HashSet::new(),
+        &tmp_dir.join("staging"),
+        target,
+        platform,
+        jobs,
+        verify_layers,
+        events,
+    )
+}
+
+// apply the layers of an image laid out under blob_dir, by its OCI index or a
+// `docker save` manifest. blob_digests holds blobs hashed as they were written,
+// any other layer is hashed here when verifying
+fn apply_image_layers(
+    blob_dir: &Path,
+    manifest: &[DockerManifest],
+    image: Option<&str>,
+    mut blob_digests: HashMap<PathBuf, String>,
+    owned_blobs: &HashSet<PathBuf>,
+    staging_dir: &Path,
+    target: &LayerTarget,
+    platform: &PlatformSpec,
+    jobs: usize,
+    verify_layers: bool,
+    events: &EventEmitter,
+) -> Result<(ImageConfig, Vec<PathBuf>)> {
+    // multi-arch exports carry an OCI index, otherwise fall back to manifest.json
+    let index_path = blob_dir.join("index.json");
+    let mut selected = None;
+    // the index can't tell the images of a multi image tar apart, their
+    // manifest.json entries can
+    if index_path.exists() && manifest.len() <= 1 {
+        selected = platform_layers(blob_dir, &index_path, platform)?;
+        // an OCI layout of a single image may not label its platform
+        if selected.is_none() && manifest.is_empty() {
+            selected = first_manifest_layers(blob_dir, &index_path)?;
+        }
+    }
+    let (config_path, layers) = match selected {
+        Some(selected) => selected,
+        None => manifest_layers(blob_dir, manifest, image)?,
+    };
+    let image_config = ImageConfig::from_file(&config_path)?;
+    if verify_layers {
+        for layer in &layers {
+            if !blob_digests.contains_key(&layer.path) {
+                let mut blob = File::open(&layer.path)
+                    .context(format!("layer blob not found: {}", layer.path.display()))?;
+                let mut hasher = utils::Sha256Writer::new(std::io::sink());
+                std::io::copy(&mut blob, &mut hasher)?;
+                blob_digests.insert(layer.path.clone(), hasher.finish());
+            }
+        }
+        verify_layer_digests(blob_dir, &layers, &blob_digests)?;
+    }
+
+    let layer_dirs = match target {
+        LayerTarget::Rootfs(_) => Vec::new(),
+        LayerTarget::LayerCache(layers_dir) => layers
+            .iter()
+            .map(|layer| cached_layer_dir(layers_dir, layer))
+            .collect::<Result<Vec<_>>>()?,
+    };
+    // layers extracted by an earlier run are used as they are
+    let pending_layers = layers
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| layer_dirs.get(*idx).is_none_or(|dir| !dir.exists()))
+        .collect::<Vec<_>>();
+    if pending_layers.len() < layers.len() {
+        info!(
+            "reusing {} cached layers",
+            layers.len() - pending_layers.len()
+        );
+    }
+
+    // blobs the caller wrote are removed as soon as they're decompressed or
+    // applied, so the image isn't on disk twice. paths only named by the
+    // manifest are never removed, a blob listed for several layers is left to
+    // the caller
+    let disposable = |blob_path: &Path| {
+        owned_blobs.contains(blob_path)
+            && layers
+                .iter()
+                .filter(|layer| layer.path == blob_path)
+                .count()
+                == 1
+    };
+
+    // decompress concurrently, but whiteouts depend on lower layers so the
+    // apply below must stay in manifest order
+    info!(
+        "decompressing {} layers with {} jobs",
+        pending_layers.len(),
+        jobs
+    );
+    std::fs::create_dir_all(staging_dir)?;
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+    let staged_layers = pool.install(|| {
+        pending_layers
+            .par_iter()
+            .map(|(idx, layer)| {
+                let staged_path = staging_dir.join(format!("{}.tar", idx));
+                let staged_path = decompress_layer(&layer.path, &layer.media_type, &staged_path)?;
+                if staged_path != layer.path && disposable(&layer.path) {
+                    std::fs::remove_file(&layer.path)?;
                 }
-                _ => println!(
-                    "warning: skipping entry type: {:?} for {}",
-                    tar_file.header().entry_type(),
-                    dst_path.display()
-                ),
-            }
-        }
-
-        println!("parsing manifest & extract rootfs");
-        if manifest.len() == 0 {
-            return Err(anyhow::anyhow!("no manifest found"));
-        }
-        // TODO: usually manifest only has one entry?
-        if manifest.len() > 1 {
-            println!("warning: multiple manifest entries found, only the first one will be used");
-        }
-        let manifest = manifest.first().unwrap();
-        for layer in manifest.layers.iter() {
-            let layer_blob = blob
-                .get(layer)
-                .ok_or(anyhow::anyhow!("layer blob not found"))?;
-            let layer_entry_name = layer
-                .splitn(2, '/')
-                .nth(1)
-                .unwrap()
-                .replace("/", ":")
-                .to_string();
-            let layer_info = manifest
-                .layer_sources
-                .get(&layer_entry_name)
-                .ok_or(anyhow::anyhow!("layer info not found"))?;
-
-            // TODO: support other format
-            let layer_type = MediaType::from(&layer_info.media_type[..]);
-            if layer_type != MediaType::ImageLayer {
+                Ok(staged_path)
+            })
+            .collect::<Result<Vec<PathBuf>>>()
+    })?;
+
+    // extract archive
+    let mut total_size = 0;
+    for staged_path in &staged_layers {
+        total_size += staged_path.metadata()?.len();
+    }
+    let extract_bar = progress_helper::extract_bar(events, total_size);
+    for ((idx, layer), staged_path) in pending_layers.iter().zip(staged_layers.iter()) {
+        // metadata only layers may be a zero byte blob or an empty tar, both
+        // extract to nothing
+        if staged_path.metadata()?.len() == 0 {
+            debug!("layer {} is empty", layer.path.display());
+        }
+        let mut layer_reader = BufReader::new(extract_bar.wrap_read(File::open(staged_path)?));
+        match target {
+            LayerTarget::Rootfs(export_dir) => {
+                utils::extract_archive(&mut layer_reader, export_dir)?
+            }
+            LayerTarget::LayerCache(_) => {
+                extract_cached_layer(&mut layer_reader, &layer_dirs[*idx])?
+            }
+        }
+        // plain tar layers are read from the blob itself, which isn't ours to
+        // remove for an oci layout
+        if *staged_path != layer.path || disposable(&layer.path) {
+            std::fs::remove_file(staged_path)?;
+        }
+    }
+    extract_bar.finish_and_clear();
+
+    Ok((image_config, layer_dirs))
+}
+
+// <layers_dir>/<digest hex>, layers are content addressed so any image
+// sharing the layer can use the dir
+fn cached_layer_dir(layers_dir: &Path, layer: &LayerBlob) -> Result<PathBuf> {
+    let digest = layer.digest.as_deref().context(format!(
+        "layer {} has no digest to cache it by",
+        layer.path.display()
+    ))?;
+    let (_, hex) = digest.split_once(':').unwrap_or(("sha256", digest));
+    Ok(layers_dir.join(hex))
+}
+
+// extract next to layer_dir & rename it in place, so an interrupted or
+// concurrent extraction never leaves a partial layer behind
+fn extract_cached_layer(reader: &mut dyn Read, layer_dir: &Path) -> Result<()> {
+    let mut tmp_name = layer_dir.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".tmp{}", std::process::id()));
+    let tmp_dir = layer_dir.with_file_name(tmp_name);
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir)?;
+    }
+    std::fs::create_dir_all(&tmp_dir)?;
+    utils::extract_layer(reader, &tmp_dir)?;
+    if let Err(e) = std::fs::rename(&tmp_dir, layer_dir) {
+        std::fs::remove_dir_all(&tmp_dir)?;
+        // another session got there first
+        if !layer_dir.exists() {
+            return Err(e).context(format!("failed to cache layer {}", layer_dir.display()));
+        }
+    }
+    Ok(())
+}
+
+// a layer blob written to tmp_dir & the digest its manifest claims
+struct LayerBlob {
+    path: PathBuf,
+    media_type: String,
+    digest: Option<String>,
+}
+
+// compare each layer blob against the manifest digest & the digest embedded in
+// its blobs/<algorithm>/<hex> path
+fn verify_layer_digests(
+    tmp_dir: &Path,
+    layers: &[LayerBlob],
+    blob_digests: &HashMap<PathBuf, String>,
+) -> Result<()> {
+    for layer in layers {
+        let actual = blob_digests
+            .get(&layer.path)
+            .context(format!("layer blob not found: {}", layer.path.display()))?;
+        let path_digest = layer
+            .path
+            .strip_prefix(tmp_dir.join("blobs"))
+            .ok()
+            .and_then(|relative| {
+                let (algorithm, hex) = (relative.parent()?, relative.file_name()?);
+                Some(format!("{}:{}", algorithm.display(), hex.to_string_lossy()))
+            });
+        for expected in layer.digest.iter().chain(path_digest.iter()) {
+            if !expected.starts_with("sha256:") {
+                warn!("can't verify {} digest, only sha256 is supported", expected);
+                continue;
+            }
+            if expected != actual {
                 return Err(anyhow::anyhow!(
-                    "unsupported layer type: {}",
-                    layer_info.media_type
+                    "layer {} digest mismatch, expected {}, got {}",
+                    layer.path.display(),
+                    expected,
+                    actual
                 ));
             }
+        }
+    }
+    Ok(())
+}
+
+// config blob path & layer blobs from a `docker save` manifest.json
+fn manifest_layers(
+    tmp_dir: &Path,
+    manifest: &[DockerManifest],
+    image: Option<&str>,
+) -> Result<(PathBuf, Vec<LayerBlob>)> {
+    info!("parsing manifest & extract rootfs");
+    let manifest = select_manifest_entry(manifest, image)?;
+    let mut layers = Vec::new();
+    for layer in manifest.layers.iter() {
+        let (_, layer_entry_name) = layer
+            .split_once('/')
+            .context(format!("malformed manifest layer path: {}", layer))?;
+        // docker before 25 & podman write <id>/layer.tar without layer sources,
+        // the digest of a blobs/sha256/<hex> path is still verified & the
+        // compression is sniffed from the blob
+        let (media_type, digest) = match manifest
+            .layer_sources
+            .get(&layer_entry_name.replace("/", ":"))
+        {
+            Some(layer_info) => (
+                layer_info.media_type.clone(),
+                Some(layer_info.digest.clone()),
+            ),
+            None => {
+                debug!("no layer source for {}, digest taken from its path", layer);
+                (String::new(), None)
+            }
+        };
+        layers.push(LayerBlob {
+            path: tmp_dir.join(layer),
+            media_type,
+            digest,
+        });
+    }
+    Ok((tmp_dir.join(&manifest.config), layers))
+}
 
-            // extract archive
-            let mut blob_reader = std::io::Cursor::new(layer_blob);
-            utils::extract_archive(&mut blob_reader, &export_dir)?;
+// config digest of the entry image selects in a `docker save` tar, the cache
+// key of an image file. entries are skipped with seek, no layer is read
+pub fn image_tar_config_digest(image_tar: &Path, image: &str) -> Result<String> {
+    let manifest: Vec<DockerManifest> =
+        serde_json::from_slice(&read_image_tar_entry(image_tar, "manifest.json")?)
+            .context("failed to parse manifest.json")?;
+    let entry = select_manifest_entry(&manifest, Some(image))?;
+    let mut hasher = utils::Sha256Writer::new(std::io::sink());
+    std::io::copy(
+        &mut read_image_tar_entry(image_tar, &entry.config)?.as_slice(),
+        &mut hasher,
+    )?;
+    Ok(hasher.finish())
+}
+
+fn read_image_tar_entry(image_tar: &Path, name: &str) -> Result<Vec<u8>> {
+    let image_file =
+        File::open(image_tar).context(format!("failed to open {}", image_tar.display()))?;
+    // manifest paths & tar entries may each carry a leading ./
+    let without_cur_dir = |path: &Path| path.strip_prefix(".").unwrap_or(path).to_path_buf();
+    let name = without_cur_dir(Path::new(name));
+    let mut tar_archive = Archive::new(image_file);
+    for entry in tar_archive.entries_with_seek()? {
+        let mut entry = entry?;
+        if without_cur_dir(&entry.path()?) == name {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            return Ok(content);
         }
+    }
+    Err(anyhow::anyhow!(
+        "{} not found in {}",
+        name.display(),
+        image_tar.display()
+    ))
+}
 
-        Ok(())
+// the entry tagged as image, a lone entry is taken as is. untagged entries
+// fall back to the first one, tagged ones that don't match are an error
+fn select_manifest_entry<'a>(
+    manifest: &'a [DockerManifest],
+    image: Option<&str>,
+) -> Result<&'a DockerManifest> {
+    let Some(first) = manifest.first() else {
+        return Err(anyhow::anyhow!("no manifest found"));
+    };
+    if manifest.len() == 1 {
+        return Ok(first);
+    }
+    if let Some(image) = image
+        && let Some(entry) = manifest.iter().find(|entry| {
+            entry
+                .repo_tags
+                .iter()
+                .any(|repo_tag| repo_tag_matches(repo_tag, image))
+        })
+    {
+        info!("selected manifest entry tagged {}", image);
+        return Ok(entry);
+    }
+    let repo_tags = manifest
+        .iter()
+        .flat_map(|entry| entry.repo_tags.iter())
+        .collect::<Vec<_>>();
+    if repo_tags.is_empty() {
+        warn!("multiple untagged manifest entries found, only the first one will be used");
+        return Ok(first);
+    }
+    Err(anyhow::anyhow!(
+        "no image matching {} in the tar, pick one of {:?} with --image",
+        image.unwrap_or_default(),
+        repo_tags
+    ))
+}
+
+// docker save keeps tags as given, e.g. debian:12 for docker.io/library/debian:12
+fn repo_tag_matches(repo_tag: &str, image: &str) -> bool {
+    let (repository, tag, _) = split_image_reference(image);
+    let (tag_repository, tag_tag, _) = split_image_reference(repo_tag);
+    let short_name = |repository: &str| {
+        repository
+            .trim_start_matches("docker.io/")
+            .trim_start_matches("library/")
+            .to_string()
+    };
+    short_name(repository) == short_name(tag_repository)
+        && tag.unwrap_or("latest") == tag_tag.unwrap_or("latest")
+}
+
+// config & layers of the manifest matching platform, none if the index carries no
+// platform info
+fn platform_layers(
+    tmp_dir: &Path,
+    index_path: &Path,
+    platform: &PlatformSpec,
+) -> Result<Option<(PathBuf, Vec<LayerBlob>)>> {
+    let index = ImageIndex::from_file(index_path)?;
+    let mut seen_platforms = Vec::new();
+    let Some(manifest) =
+        find_platform_manifest(tmp_dir, index.manifests(), platform, &mut seen_platforms)?
+    else {
+        if seen_platforms.is_empty() {
+            return Ok(None);
+        }
+        return Err(anyhow::anyhow!(
+            "no image for platform {} found, available: {}",
+            platform,
+            seen_platforms.join(", ")
+        ));
+    };
+
+    info!("selected image for platform {}", platform);
+    Ok(Some(image_manifest_layers(tmp_dir, &manifest)))
+}
+
+// config & layers of the first image manifest in an index, none if it has none
+fn first_manifest_layers(
+    tmp_dir: &Path,
+    index_path: &Path,
+) -> Result<Option<(PathBuf, Vec<LayerBlob>)>> {
+    let index = ImageIndex::from_file(index_path)?;
+    let Some(descriptor) = index
+        .manifests()
+        .iter()
+        .find(|descriptor| *descriptor.media_type() == MediaType::ImageManifest)
+    else {
+        return Ok(None);
+    };
+    let manifest = ImageManifest::from_file(blob_path(tmp_dir, &descriptor.digest().to_string()))?;
+    Ok(Some(image_manifest_layers(tmp_dir, &manifest)))
+}
+
+fn image_manifest_layers(tmp_dir: &Path, manifest: &ImageManifest) -> (PathBuf, Vec<LayerBlob>) {
+    let layers = manifest
+        .layers()
+        .iter()
+        .map(|layer| LayerBlob {
+            path: blob_path(tmp_dir, &layer.digest().to_string()),
+            media_type: layer.media_type().to_string(),
+            digest: Some(layer.digest().to_string()),
+        })
+        .collect();
+    let config_path = blob_path(tmp_dir, &manifest.config().digest().to_string());
+    (config_path, layers)
+}
+
+fn find_platform_manifest(
+    tmp_dir: &Path,
+    descriptors: &[Descriptor],
+    platform: &PlatformSpec,
+    seen_platforms: &mut Vec<String>,
+) -> Result<Option<ImageManifest>> {
+    for descriptor in descriptors {
+        // docker save only includes content that is present locally
+        let manifest_path = blob_path(tmp_dir, &descriptor.digest().to_string());
+        if !manifest_path.exists() {
+            continue;
+        }
+        match descriptor.media_type() {
+            MediaType::ImageIndex => {
+                let nested_index = ImageIndex::from_file(&manifest_path)?;
+                let manifest = find_platform_manifest(
+                    tmp_dir,
+                    nested_index.manifests(),
+                    platform,
+                    seen_platforms,
+                )?;
+                if manifest.is_some() {
+                    return Ok(manifest);
+                }
+            }
+            MediaType::ImageManifest => {
+                let Some(descriptor_platform) = descriptor.platform() else {
+                    continue;
+                };
+                if platform.matches(descriptor_platform) {
+                    return Ok(Some(ImageManifest::from_file(&manifest_path)?));
+                }
+                // attestation manifests are tagged unknown/unknown
+                if descriptor_platform.os().to_string() != "unknown" {
+                    seen_platforms.push(format!(
+                        "{}/{}",
+                        descriptor_platform.os(),
+                        descriptor_platform.architecture()
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+// blobs/<algorithm>/<hex> path of a digest
+fn blob_path(tmp_dir: &Path, digest: &str) -> PathBuf {
+    let (algorithm, hex) = digest.split_once(':').unwrap_or(("sha256", digest));
+    tmp_dir.join("blobs").join(algorithm).join(hex)
+}
+
+// decompress a layer blob into a plain tar, returns the path to extract from.
+// the compression is sniffed from the blob, mislabeled layers are common enough
+// that the media type is only a hint
+fn decompress_layer(blob_path: &Path, media_type: &str, staged_path: &Path) -> Result<PathBuf> {
+    let layer_blob =
+        File::open(blob_path).context(format!("layer blob not found: {}", blob_path.display()))?;
+    let mut blob_reader = BufReader::new(layer_blob);
+    let compression = utils::sniff_compression(blob_reader.fill_buf()?);
+    if let Some(hint) = media_type_compression(media_type)
+        && hint != compression
+    {
+        debug!(
+            "layer {} is labeled {} but looks like {:?}",
+            blob_path.display(),
+            media_type,
+            compression
+        );
+    }
+    let mut decoder: Box<dyn Read> = match compression {
+        // already a plain tar, nothing to stage
+        Compression::None => return Ok(blob_path.to_path_buf()),
+        Compression::Gzip => Box::new(GzDecoder::new(blob_reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(blob_reader)?),
+    };
+    let mut staged_file = File::create(staged_path)?;
+    std::io::copy(&mut decoder, &mut staged_file)?;
+    Ok(staged_path.to_path_buf())
+}
+
+// compression a layer media type claims, none for unknown types
+fn media_type_compression(media_type: &str) -> Option<Compression> {
+    match MediaType::from(media_type) {
+        MediaType::ImageLayer => Some(Compression::None),
+        MediaType::ImageLayerGzip => Some(Compression::Gzip),
+        MediaType::ImageLayerZstd => Some(Compression::Zstd),
+        MediaType::Other(other) if other == DOCKER_LAYER_GZIP => Some(Compression::Gzip),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+
+    // tar::Builder refuses to write `..` & absolute paths, so the name goes
+    // into the header as is
+    fn tar_with_raw_path(path: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, data).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    const TEST_CONFIG: &str =
+        r#"{"architecture":"amd64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#;
+
+    // a `docker save` layout in dir with a single layer holding hello.txt,
+    // returns its manifest & the layer blob
+    fn write_saved_image(dir: &Path, gzip: bool) -> (Vec<DockerManifest>, PathBuf) {
+        let mut layer = hello_layer();
+        let media_type = if gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&layer).unwrap();
+            layer = encoder.finish().unwrap();
+            DOCKER_LAYER_GZIP
+        } else {
+            "application/vnd.oci.image.layer.v1.tar"
+        };
+        // digests aren't verified by these tests, only the path matters
+        let digest = format!("sha256:{}", "0".repeat(64));
+        let layer_name = digest.replace(':', "/");
+        let blob_path = dir.join("blobs").join(&layer_name);
+        std::fs::create_dir_all(blob_path.parent().unwrap()).unwrap();
+        std::fs::write(&blob_path, &layer).unwrap();
+        std::fs::write(dir.join("config.json"), TEST_CONFIG).unwrap();
+        let manifest = DockerManifest {
+            config: String::from("config.json"),
+            repo_tags: vec![String::from("test:latest")],
+            layers: vec![format!("blobs/{}", layer_name)],
+            layer_sources: HashMap::from([(
+                digest.clone(),
+                DockerManifestLayerSource {
+                    media_type: media_type.to_string(),
+                    size: layer.len() as u64,
+                    digest,
+                },
+            )]),
+        };
+        (vec![manifest], blob_path)
+    }
+
+    fn apply_saved_image(dir: &Path, gzip: bool, owned: bool) -> PathBuf {
+        let (manifest, blob_path) = write_saved_image(dir, gzip);
+        let owned_blobs = if owned {
+            HashSet::from([blob_path.clone()])
+        } else {
+            HashSet::new()
+        };
+        let rootfs_dir = dir.join("rootfs");
+        std::fs::create_dir_all(&rootfs_dir).unwrap();
+        apply_image_layers(
+            dir,
+            &manifest,
+            Some("test:latest"),
+            HashMap::new(),
+            &owned_blobs,
+            &dir.join("staging"),
+            &LayerTarget::Rootfs(&rootfs_dir),
+            &PlatformSpec::host(),
+            1,
+            false,
+            &EventEmitter::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(rootfs_dir.join("hello.txt")).unwrap(),
+            "hello"
+        );
+        blob_path
+    }
+
+    #[test]
+    fn owned_compressed_blobs_are_removed_once_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob_path = apply_saved_image(dir.path(), true, true);
+        assert!(!blob_path.exists());
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("staging"))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn owned_plain_tar_blobs_are_removed_once_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob_path = apply_saved_image(dir.path(), false, true);
+        assert!(!blob_path.exists());
+    }
+
+    #[test]
+    fn blobs_not_owned_are_kept() {
+        for gzip in [true, false] {
+            let dir = tempfile::tempdir().unwrap();
+            let blob_path = apply_saved_image(dir.path(), gzip, false);
+            assert!(blob_path.exists());
+        }
+    }
+
+    fn hello_layer() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "hello.txt", &b"hello"[..])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn gzip_layers_extract_like_plain_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let layer = hello_layer();
+        let plain_path = dir.path().join("plain");
+        std::fs::write(&plain_path, &layer).unwrap();
+        let gzip_path = dir.path().join("gzip");
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&layer).unwrap();
+        std::fs::write(&gzip_path, encoder.finish().unwrap()).unwrap();
+
+        // a plain tar is used in place, a gzip one is staged decompressed
+        let staged_plain = decompress_layer(
+            &plain_path,
+            "application/vnd.docker.image.rootfs.diff.tar",
+            &dir.path().join("plain.tar"),
+        )
+        .unwrap();
+        assert_eq!(staged_plain, plain_path);
+        let staged_gzip =
+            decompress_layer(&gzip_path, DOCKER_LAYER_GZIP, &dir.path().join("gzip.tar")).unwrap();
+        assert_eq!(std::fs::read(&staged_gzip).unwrap(), layer);
+
+        for staged in [staged_plain, staged_gzip] {
+            let rootfs_dir = tempfile::tempdir().unwrap();
+            let mut reader = File::open(&staged).unwrap();
+            utils::extract_archive(&mut reader, rootfs_dir.path()).unwrap();
+            assert_eq!(
+                std::fs::read(rootfs_dir.path().join("hello.txt")).unwrap(),
+                b"hello"
+            );
+        }
+    }
+
+    #[test]
+    fn extract_image_tar_rejects_escaping_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp_dir = dir.path().join("extract");
+        let rootfs_dir = dir.path().join("rootfs");
+        let absolute = dir.path().join("absolute");
+        for (path, escaped) in [
+            (b"../escape".to_vec(), dir.path().join("escape")),
+            (b"blobs/../../nested".to_vec(), dir.path().join("nested")),
+            (absolute.as_os_str().as_bytes().to_vec(), absolute.clone()),
+        ] {
+            let res = extract_image_tar(
+                tar_with_raw_path(&path, b"payload").as_slice(),
+                "test:latest",
+                &tmp_dir,
+                &LayerTarget::Rootfs(&rootfs_dir),
+                &PlatformSpec::host(),
+                1,
+                true,
+                &EventEmitter::default(),
+            );
+            assert!(res.is_err(), "{}", String::from_utf8_lossy(&path));
+            assert!(!escaped.exists(), "{}", escaped.display());
+        }
+    }
+
+    fn containers() -> Vec<(String, Vec<String>)> {
+        vec![
+            ("abc123".to_string(), vec!["/web".to_string()]),
+            ("abd456".to_string(), vec!["/db".to_string()]),
+            ("fff789".to_string(), vec!["/abc".to_string()]),
+        ]
+    }
+
+    #[test]
+    fn match_container_id_unique_prefix() {
+        assert_eq!(match_container_id(&containers(), "abd").unwrap(), "abd456");
+        assert_eq!(match_container_id(&containers(), "ff").unwrap(), "fff789");
+    }
+
+    #[test]
+    fn match_container_id_exact_name_wins() {
+        assert_eq!(match_container_id(&containers(), "web").unwrap(), "abc123");
+        assert_eq!(match_container_id(&containers(), "/db").unwrap(), "abd456");
+        // a name shadows an id prefix it happens to look like
+        assert_eq!(match_container_id(&containers(), "abc").unwrap(), "fff789");
+    }
+
+    #[test]
+    fn match_container_id_ambiguous_prefix() {
+        let err = match_container_id(&containers(), "ab").unwrap_err();
+        assert!(err.to_string().contains("ambiguous"), "{}", err);
+        assert!(err.to_string().contains("abc123, abd456"), "{}", err);
+    }
+
+    #[test]
+    fn match_container_id_no_match() {
+        assert!(match_container_id(&containers(), "zzz").is_err());
+        assert!(match_container_id(&[], "abc").is_err());
+    }
+
+    #[test]
+    fn decompress_layer_ignores_a_wrong_media_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob_path = dir.path().join("blob");
+        let staged_path = dir.path().join("staged");
+        std::fs::write(
+            &blob_path,
+            zstd::encode_all(hello_layer().as_slice(), 0).unwrap(),
+        )
+        .unwrap();
+        let path = decompress_layer(&blob_path, DOCKER_LAYER_GZIP, &staged_path).unwrap();
+        assert_eq!(path, staged_path);
+        assert_eq!(std::fs::read(&staged_path).unwrap(), hello_layer());
+        // a plain tar is extracted in place
+        std::fs::write(&blob_path, hello_layer()).unwrap();
+        let path = decompress_layer(&blob_path, DOCKER_LAYER_GZIP, &staged_path).unwrap();
+        assert_eq!(path, blob_path);
+    }
+
+    #[test]
+    fn split_image_reference_tags_and_digests() {
+        assert_eq!(split_image_reference("ubuntu"), ("ubuntu", None, None));
+        assert_eq!(
+            split_image_reference("ubuntu:22.04"),
+            ("ubuntu", Some("22.04"), None)
+        );
+        assert_eq!(
+            split_image_reference("alpine@sha256:abc"),
+            ("alpine", None, Some("sha256:abc"))
+        );
+        assert_eq!(
+            split_image_reference("alpine:3.20@sha256:abc"),
+            ("alpine", Some("3.20"), Some("sha256:abc"))
+        );
+    }
+
+    #[test]
+    fn split_image_reference_registry_ports() {
+        assert_eq!(
+            split_image_reference("localhost:5000/app"),
+            ("localhost:5000/app", None, None)
+        );
+        assert_eq!(
+            split_image_reference("registry.example.com:5000/team/app:v1"),
+            ("registry.example.com:5000/team/app", Some("v1"), None)
+        );
+        assert_eq!(
+            split_image_reference("localhost:5000/app@sha256:abc"),
+            ("localhost:5000/app", None, Some("sha256:abc"))
+        );
+    }
+
+    fn graph_data(entries: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let entries = entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        move |key| entries.get(key).cloned()
+    }
+
+    const OVERLAY_DIRS: [(&str, &str); 3] = [
+        ("MergedDir", "/var/lib/docker/overlay2/abc/merged"),
+        ("UpperDir", "/var/lib/docker/overlay2/abc/diff"),
+        (
+            "LowerDir",
+            "/var/lib/docker/overlay2/l/1:/var/lib/docker/overlay2/l/2",
+        ),
+    ];
+
+    #[test]
+    fn container_fs_dirs_prefers_merged_dir_while_running() {
+        let (merged_dir, upper_dir, lower_dir) =
+            container_fs_dirs("overlay2", true, graph_data(&OVERLAY_DIRS)).unwrap();
+        assert_eq!(merged_dir.as_deref(), Some(OVERLAY_DIRS[0].1));
+        assert_eq!(upper_dir.as_deref(), Some(OVERLAY_DIRS[1].1));
+        assert_eq!(lower_dir.as_deref(), Some(OVERLAY_DIRS[2].1));
+    }
+
+    #[test]
+    fn container_fs_dirs_falls_back_to_layers() {
+        // a stopped container's MergedDir is unmounted
+        let (merged_dir, upper_dir, lower_dir) =
+            container_fs_dirs("overlay2", false, graph_data(&OVERLAY_DIRS)).unwrap();
+        assert_eq!(merged_dir, None);
+        assert!(upper_dir.is_some() && lower_dir.is_some());
+        // a driver reporting only the layers
+        let (merged_dir, _, _) =
+            container_fs_dirs("overlay", true, graph_data(&OVERLAY_DIRS[1..])).unwrap();
+        assert_eq!(merged_dir, None);
+    }
+
+    #[test]
+    fn container_fs_dirs_any_driver_with_merged_dir() {
+        let dirs = [(
+            "MergedDir",
+            "/var/lib/containers/storage/overlay/abc/merged",
+        )];
+        for running in [true, false] {
+            let (merged_dir, upper_dir, _) =
+                container_fs_dirs("fuse-overlayfs", running, graph_data(&dirs)).unwrap();
+            assert_eq!(merged_dir.as_deref(), Some(dirs[0].1));
+            assert_eq!(upper_dir, None);
+        }
+    }
+
+    #[test]
+    fn container_fs_dirs_unsupported_driver() {
+        let err = container_fs_dirs("vfs", true, graph_data(&[])).unwrap_err();
+        assert!(err.to_string().contains("storage driver vfs"), "{}", err);
+        // an upper dir alone can't be mounted
+        let dirs = [("UpperDir", "/upper")];
+        assert!(container_fs_dirs("btrfs", false, graph_data(&dirs)).is_err());
+    }
+
+    #[test]
+    fn docker_manifest_pascal_case() {
+        let manifest: Vec<DockerManifest> = serde_json::from_str(
+            r#"[{
+                "Config": "blobs/sha256/cfg",
+                "RepoTags": ["app:1"],
+                "Layers": ["blobs/sha256/l1", "blobs/sha256/l2"],
+                "LayerSources": {
+                    "sha256:l1": {"mediaType": "application/vnd.oci.image.layer.v1.tar", "size": 10, "digest": "sha256:l1"}
+                }
+            }]"#,
+        )
+        .unwrap();
+        assert_eq!(manifest[0].config, "blobs/sha256/cfg");
+        assert_eq!(manifest[0].repo_tags, ["app:1"]);
+        assert_eq!(manifest[0].layers, ["blobs/sha256/l1", "blobs/sha256/l2"]);
+        assert_eq!(manifest[0].layer_sources["sha256:l1"].size, 10);
+    }
+
+    #[test]
+    fn docker_manifest_camel_case() {
+        let manifest: Vec<DockerManifest> = serde_json::from_str(
+            r#"[{
+                "config": "cfg.json",
+                "repoTags": ["app:1"],
+                "layers": ["l1/layer.tar"],
+                "layerSources": {
+                    "sha256:l1": {"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "size": 7, "digest": "sha256:l1"}
+                }
+            }]"#,
+        )
+        .unwrap();
+        assert_eq!(manifest[0].config, "cfg.json");
+        assert_eq!(manifest[0].repo_tags, ["app:1"]);
+        assert_eq!(manifest[0].layers, ["l1/layer.tar"]);
+        assert_eq!(manifest[0].layer_sources["sha256:l1"].digest, "sha256:l1");
+    }
+
+    #[test]
+    fn docker_manifest_optional_keys() {
+        // untagged images & older exporters leave these out
+        let manifest: Vec<DockerManifest> =
+            serde_json::from_str(r#"[{"config": "cfg.json", "Layers": []}]"#).unwrap();
+        assert!(manifest[0].repo_tags.is_empty());
+        assert!(manifest[0].layer_sources.is_empty());
+        assert!(serde_json::from_str::<Vec<DockerManifest>>(r#"[{"Layers": []}]"#).is_err());
+    }
+
+    fn manifest_entry(config: &str, repo_tags: &[&str]) -> DockerManifest {
+        DockerManifest {
+            config: config.to_string(),
+            repo_tags: repo_tags.iter().map(|tag| tag.to_string()).collect(),
+            layers: Vec::new(),
+            layer_sources: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn select_manifest_entry_by_tag() {
+        let manifest = [
+            manifest_entry("web.json", &["web:1", "web:latest"]),
+            manifest_entry("db.json", &["registry.local:5000/db:2"]),
+        ];
+        let select = |image| {
+            select_manifest_entry(&manifest, Some(image))
+                .unwrap()
+                .config
+                .as_str()
+        };
+        assert_eq!(select("web"), "web.json");
+        assert_eq!(select("web:1"), "web.json");
+        assert_eq!(select("docker.io/library/web:1"), "web.json");
+        assert_eq!(select("registry.local:5000/db:2"), "db.json");
+    }
+
+    #[test]
+    fn select_manifest_entry_no_match() {
+        let manifest = [
+            manifest_entry("web.json", &["web:1"]),
+            manifest_entry("db.json", &["db:2"]),
+        ];
+        let err = select_manifest_entry(&manifest, Some("web:2")).unwrap_err();
+        assert!(err.to_string().contains("db:2"), "{}", err);
+        assert!(select_manifest_entry(&manifest, None).is_err());
+        assert!(select_manifest_entry(&[], Some("web:1")).is_err());
+    }
+
+    #[test]
+    fn select_manifest_entry_untagged() {
+        // a lone entry is taken whatever its tags
+        let manifest = [manifest_entry("web.json", &["web:1"])];
+        let entry = select_manifest_entry(&manifest, Some("db:2")).unwrap();
+        assert_eq!(entry.config, "web.json");
+        let manifest = [manifest_entry("a.json", &[]), manifest_entry("b.json", &[])];
+        let entry = select_manifest_entry(&manifest, Some("web:1")).unwrap();
+        assert_eq!(entry.config, "a.json");
+    }
+
+    #[test]
+    fn manifest_layers_without_layer_sources() {
+        let tmp_dir = Path::new("/extract");
+        // docker before 25 & podman save
+        let manifest: Vec<DockerManifest> = serde_json::from_str(
+            r#"[{"Config": "abc.json", "RepoTags": ["app:1"], "Layers": ["0123abcd/layer.tar"]}]"#,
+        )
+        .unwrap();
+        let (config_path, layers) = manifest_layers(tmp_dir, &manifest, None).unwrap();
+        assert_eq!(config_path, tmp_dir.join("abc.json"));
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].path, tmp_dir.join("0123abcd/layer.tar"));
+        assert_eq!(layers[0].digest, None);
+        assert_eq!(layers[0].media_type, "");
+    }
+
+    #[test]
+    fn manifest_layers_verifies_blob_paths_without_layer_sources() {
+        let tmp_dir = Path::new("/extract");
+        let hex = "1".repeat(64);
+        let mut entry = manifest_entry("config.json", &[]);
+        entry.layers = vec![format!("blobs/sha256/{}", hex)];
+        let manifest = [entry];
+        let (_, layers) = manifest_layers(tmp_dir, &manifest, None).unwrap();
+        assert_eq!(layers[0].digest, None);
+        let mut blob_digests = HashMap::from([(layers[0].path.clone(), format!("sha256:{}", hex))]);
+        verify_layer_digests(tmp_dir, &layers, &blob_digests).unwrap();
+        blob_digests.insert(layers[0].path.clone(), format!("sha256:{}", "2".repeat(64)));
+        assert!(verify_layer_digests(tmp_dir, &layers, &blob_digests).is_err());
+    }
+
+    #[test]
+    fn manifest_layers_rejects_paths_without_a_dir() {
+        let manifest: Vec<DockerManifest> =
+            serde_json::from_str(r#"[{"Config": "abc.json", "Layers": ["layer.tar"]}]"#).unwrap();
+        let err = manifest_layers(Path::new("/extract"), &manifest, None).unwrap_err();
+        assert!(err.to_string().contains("layer.tar"), "{}", err);
+    }
+
+    fn tar_bytes(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn write_tar(path: &Path, entries: &[(&str, &[u8])]) {
+        std::fs::write(path, tar_bytes(entries)).unwrap();
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        let mut hasher = utils::Sha256Writer::new(std::io::sink());
+        hasher.write_all(data).unwrap();
+        hasher.finish()
+    }
+
+    #[test]
+    fn image_tar_config_digest_of_the_selected_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let image_tar = dir.path().join("bundle.tar");
+        let manifest = r#"[
+            {"Config": "web.json", "RepoTags": ["web:1"], "Layers": []},
+            {"Config": "db.json", "RepoTags": ["db:2"], "Layers": []}
+        ]"#;
+        // docker 25+ writes manifest.json after the blobs
+        write_tar(
+            &image_tar,
+            &[
+                ("web.json", &br#"{"os":"linux","architecture":"amd64"}"#[..]),
+                ("db.json", &br#"{"os":"linux","architecture":"arm64"}"#[..]),
+                ("manifest.json", manifest.as_bytes()),
+            ],
+        );
+        let web = image_tar_config_digest(&image_tar, "web:1").unwrap();
+        let db = image_tar_config_digest(&image_tar, "db:2").unwrap();
+        assert_eq!(web, sha256(br#"{"os":"linux","architecture":"amd64"}"#));
+        assert_eq!(db, sha256(br#"{"os":"linux","architecture":"arm64"}"#));
+        assert!(image_tar_config_digest(&image_tar, "cache:3").is_err());
+    }
+
+    #[test]
+    fn image_tar_config_digest_changes_with_the_tar() {
+        let dir = tempfile::tempdir().unwrap();
+        let image_tar = dir.path().join("app.tar");
+        let manifest = br#"[{"Config": "./config.json", "RepoTags": ["app:1"], "Layers": []}]"#;
+        write_tar(
+            &image_tar,
+            &[
+                ("./manifest.json", &manifest[..]),
+                ("./config.json", &b"{}"[..]),
+            ],
+        );
+        let before = image_tar_config_digest(&image_tar, "app:1").unwrap();
+        write_tar(
+            &image_tar,
+            &[
+                ("./manifest.json", &manifest[..]),
+                ("./config.json", &b"{ }"[..]),
+            ],
+        );
+        assert_ne!(
+            image_tar_config_digest(&image_tar, "app:1").unwrap(),
+            before
+        );
+    }
+
+    const FIXTURE_CONFIG: &str = r#"{
+        "architecture": "arm64",
+        "os": "linux",
+        "config": {"Env": ["PATH=/usr/bin", "APP_MODE=debug"], "WorkingDir": "/app", "User": "app"},
+        "rootfs": {"type": "layers", "diff_ids": []}
+    }"#;
+
+    fn fixture_layer() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        builder
+            .append_data(&mut header, "app", std::io::empty())
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o755);
+        builder
+            .append_data(&mut header, "app/run.sh", &b"echo"[..])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn extract_fixture(image_tar: &[u8]) -> Result<(tempfile::TempDir, ImageConfig)> {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs_dir = dir.path().join("rootfs");
+        std::fs::create_dir_all(&rootfs_dir).unwrap();
+        let (image_config, layer_dirs) = extract_image_tar(
+            image_tar,
+            "fixture:1",
+            &dir.path().join("extract"),
+            &LayerTarget::Rootfs(&rootfs_dir),
+            &PlatformSpec::host(),
+            1,
+            true,
+            &EventEmitter::default(),
+        )?;
+        assert!(layer_dirs.is_empty());
+        Ok((dir, image_config))
+    }
+
+    fn assert_fixture_extracted(dir: &Path, image_config: &ImageConfig) {
+        assert_eq!(
+            std::fs::read(dir.join("rootfs/app/run.sh")).unwrap(),
+            b"echo"
+        );
+        assert_eq!(image_config.env, ["PATH=/usr/bin", "APP_MODE=debug"]);
+        assert_eq!(image_config.working_dir.as_deref(), Some("/app"));
+        assert_eq!(image_config.user.as_deref(), Some("app"));
+        assert_eq!(image_config.architecture.as_deref(), Some("arm64"));
+    }
+
+    #[test]
+    fn extract_image_tar_docker_save_fixture() {
+        let layer = fixture_layer();
+        let layer_digest = sha256(&layer);
+        let config_digest = sha256(FIXTURE_CONFIG.as_bytes());
+        let layer_path = format!("blobs/{}", layer_digest.replace(':', "/"));
+        let config_path = format!("blobs/{}", config_digest.replace(':', "/"));
+        let manifest = serde_json::json!([{
+            "Config": config_path,
+            "RepoTags": ["fixture:1"],
+            "Layers": [layer_path],
+            "LayerSources": {
+                layer_digest.clone(): {
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                    "size": layer.len(),
+                    "digest": layer_digest,
+                }
+            }
+        }])
+        .to_string();
+        let image_tar = tar_bytes(&[
+            (config_path.as_str(), FIXTURE_CONFIG.as_bytes()),
+            (layer_path.as_str(), layer.as_slice()),
+            ("manifest.json", manifest.as_bytes()),
+        ]);
+        let (dir, image_config) = extract_fixture(&image_tar).unwrap();
+        assert_fixture_extracted(dir.path(), &image_config);
+    }
+
+    #[test]
+    fn extract_image_tar_legacy_docker_save_fixture() {
+        // docker before 25: <id>/layer.tar & no layer sources
+        let manifest = br#"[{"Config": "0123.json", "RepoTags": ["fixture:1"], "Layers": ["abcd/layer.tar"]}]"#;
+        let image_tar = tar_bytes(&[
+            ("manifest.json", &manifest[..]),
+            ("0123.json", FIXTURE_CONFIG.as_bytes()),
+            ("abcd/layer.tar", fixture_layer().as_slice()),
+        ]);
+        let (dir, image_config) = extract_fixture(&image_tar).unwrap();
+        assert_fixture_extracted(dir.path(), &image_config);
+    }
+
+    #[test]
+    fn extract_image_tar_rejects_a_tampered_layer() {
+        let layer = fixture_layer();
+        let layer_path = format!("blobs/sha256/{}", "0".repeat(64));
+        let manifest = serde_json::json!([{
+            "Config": "config.json",
+            "Layers": [layer_path],
+        }])
+        .to_string();
+        let image_tar = tar_bytes(&[
+            ("config.json", FIXTURE_CONFIG.as_bytes()),
+            (layer_path.as_str(), layer.as_slice()),
+            ("manifest.json", manifest.as_bytes()),
+        ]);
+        let err = extract_fixture(&image_tar).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("digest mismatch"),
+            "{:#}",
+            err
+        );
     }
 }
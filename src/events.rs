@@ -0,0 +1,74 @@
+use serde::Serialize;
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+
+/// How progress is reported on stdout.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    /// human readable logs
+    #[default]
+    Human,
+    /// newline delimited json events, logs go to stderr
+    Json,
+}
+
+/// Progress event, serialized as `{"event":"<snake_case name>", ...}`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    PullProgress {
+        layer: &'a str,
+        done: u64,
+        total: u64,
+    },
+    Extracted {
+        path: &'a Path,
+    },
+    Mounted {
+        path: &'a Path,
+    },
+    CacheSaved {
+        path: &'a Path,
+    },
+    DiffSaved {
+        path: &'a Path,
+        entries: usize,
+    },
+    PhaseTiming {
+        phase: &'a str,
+        seconds: f64,
+    },
+    SessionExit {
+        code: i32,
+    },
+}
+
+// writes events to stdout in json mode, human output is left to the logs
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EventEmitter {
+    format: OutputFormat,
+    quiet: bool,
+}
+
+impl EventEmitter {
+    pub fn new(format: OutputFormat, quiet: bool) -> Self {
+        EventEmitter { format, quiet }
+    }
+
+    // progress bars are drawn on stderr, only for a human at a terminal
+    pub fn shows_progress(&self) -> bool {
+        !self.quiet && self.format == OutputFormat::Human && std::io::stderr().is_terminal()
+    }
+
+    pub fn emit(&self, event: Event) {
+        if self.format != OutputFormat::Json {
+            return;
+        }
+        // a closed stdout must not abort the session
+        if let Ok(line) = serde_json::to_string(&event) {
+            let mut stdout = std::io::stdout().lock();
+            let _ = writeln!(stdout, "{}", line);
+            let _ = stdout.flush();
+        }
+    }
+}
@@ -2,10 +2,59 @@
 # https://www.kernel.org/doc/Documentation/filesystems/sharedsubtree.txt
 mount --make-rslave / # make mount not visible in parent
 mkdir -p tmp/old_root
-mount --rbind /dev dev/
-mount -t proc proc proc # virtual fs 
-mount -t sysfs sys sys # virtual fs
+# these live in our private mount namespace & go away with it
+if [ "${MOUNT_DEV:-1}" = 1 ] && [ -d dev ]; then
+    mount --rbind /dev dev/
+fi
+if [ "${MOUNT_PROC:-1}" = 1 ] && [ -d proc ]; then
+    mount -t proc proc proc # virtual fs
+fi
+if [ "${MOUNT_SYS:-1}" = 1 ] && [ -d sys ]; then
+    mount -t sysfs sys sys # virtual fs
+fi
+unset MOUNT_DEV MOUNT_PROC MOUNT_SYS
 pivot_root . tmp/old_root
 cd /
 umount -l /tmp/old_root
-bash
+# image config, handed over by rust-ns-overlay
+if [ -n "$IMAGE_WORKDIR" ]; then
+    cd "$IMAGE_WORKDIR" || cd /
+fi
+# where the container's main process runs, under the container mount
+if [ -n "$CONTAINER_WORKDIR" ]; then
+    cd "$CONTAINER_WORKDIR" || cd /
+fi
+run_as=""
+if [ -n "$IMAGE_USER" ]; then
+    if command -v setpriv >/dev/null 2>&1; then
+        user="${IMAGE_USER%%:*}"
+        group="${IMAGE_USER#*:}"
+        if [ "$group" = "$IMAGE_USER" ]; then
+            group="$(id -g "$user" 2>/dev/null || echo 0)"
+        fi
+        run_as="setpriv --reuid=$user --regid=$group --clear-groups"
+    else
+        echo "setpriv not found, running as root instead of $IMAGE_USER" >&2
+    fi
+fi
+rc_file="$RC_FILE"
+unset IMAGE_WORKDIR IMAGE_USER CONTAINER_WORKDIR RC_FILE
+# without a one-off command the shell is started
+if [ $# -eq 0 ]; then
+    set -- "${SHELL:-bash}"
+    if [ -n "$rc_file" ]; then
+        case "$1" in
+        *bash) set -- "$1" --rcfile "$rc_file" ;;
+        *) export ENV="$rc_file" ;; # read by interactive posix shells
+        esac
+    fi
+fi
+set -- $run_as "$@"
+# image & inherited env, exported last so it can't change anything above
+for env_name in "${!SESSION_ENV_@}"; do
+    env_value="${!env_name}"
+    unset "$env_name"
+    export "${env_name#SESSION_ENV_}=$env_value"
+done
+exec "$@"
+
@@ -0,0 +1,35 @@
+//! Debug a running container from a rootfs built out of any docker image, see
+//! [`DebugSession::builder`].
+
+mod auth_helper;
+mod busybox;
+mod cache_helper;
+mod container_runtime;
+mod diff_helper;
+mod distro_helper;
+mod docker_helper;
+mod events;
+mod lock_helper;
+pub mod maintenance;
+mod mount_helper;
+mod namespace_helper;
+mod progress_helper;
+mod pty_helper;
+mod session;
+mod signal_helper;
+mod timing;
+pub mod transfer;
+mod utils;
+mod watch_helper;
+
+pub use auth_helper::RegistryAuth;
+pub use cache_helper::CacheEntry;
+pub use container_runtime::RuntimeKind;
+pub use docker_helper::{ContainerInfo, PullPolicy};
+pub use events::{Event, OutputFormat};
+pub use mount_helper::HostMount;
+pub use namespace_helper::NetworkMode;
+pub use session::{CacheKey, DebugSession, DebugSessionBuilder};
+// for the extraction benchmark, not part of the supported api
+#[doc(hidden)]
+pub use utils::extract_archive;
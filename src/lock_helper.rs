@@ -0,0 +1,104 @@
+use anyhow::Result;
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+// advisory flock held for as long as a workdir is in use. the kernel drops it
+// when the last fd goes, so a killed session never leaves a stale lock
+#[derive(Debug)]
+pub struct WorkdirLock {
+    _file: File,
+}
+
+impl WorkdirLock {
+    // fails right away if another process holds the lock unless wait is set
+    pub fn acquire(work_dir: &Path, wait: bool) -> Result<Self> {
+        let lock_path = lock_path(work_dir);
+        if let Some(parent) = lock_path.parent() {
+            create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)?;
+        if try_flock(&file)? {
+            return Ok(WorkdirLock { _file: file });
+        }
+        if !wait {
+            return Err(anyhow::anyhow!(
+                "workdir {} is in use by another session, wait for it or use another workdir",
+                work_dir.display()
+            ));
+        }
+        info!("waiting for the session using {}", work_dir.display());
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(anyhow::anyhow!(
+                "failed to lock {}: {}",
+                lock_path.display(),
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(WorkdirLock { _file: file })
+    }
+}
+
+// next to the workdir rather than in it, the workdir is removed on start
+pub fn lock_path(work_dir: &Path) -> PathBuf {
+    let mut file_name = work_dir.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".lock");
+    work_dir.with_file_name(file_name)
+}
+
+fn try_flock(file: &File) -> Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        return Ok(false);
+    }
+    Err(anyhow::anyhow!("failed to lock workdir: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn lock_path_is_next_to_the_workdir() {
+        assert_eq!(
+            lock_path(Path::new("/tmp/work/session")),
+            Path::new("/tmp/work/session.lock")
+        );
+    }
+
+    // flock locks belong to the open file, two opens in one process contend
+    #[test]
+    fn second_session_fails_without_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let work_dir = dir.path().join("work");
+        let lock = WorkdirLock::acquire(&work_dir, false).unwrap();
+        let err = WorkdirLock::acquire(&work_dir, false).unwrap_err();
+        assert!(err.to_string().contains("in use"), "{}", err);
+        drop(lock);
+        WorkdirLock::acquire(&work_dir, false).unwrap();
+    }
+
+    #[test]
+    fn second_session_waits_for_the_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let work_dir = dir.path().join("work");
+        let lock = WorkdirLock::acquire(&work_dir, false).unwrap();
+        let waiter = {
+            let work_dir = work_dir.clone();
+            std::thread::spawn(move || WorkdirLock::acquire(&work_dir, true).map(|_| ()))
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!waiter.is_finished());
+        drop(lock);
+        waiter.join().unwrap().unwrap();
+    }
+}
@@ -1,44 +1,57 @@
 mod cli;
-mod docker_helper;
-mod namespace_helper;
-mod utils;
+mod config;
 
 use anyhow::{Context, Result};
-use clap::Parser;
-use cli::Args;
-use std::env::set_current_dir;
-use std::ffi::CString;
-use std::fs::Permissions;
-use std::fs::create_dir_all;
-use std::fs::{self, File};
-use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
+use caps::{CapSet, Capability};
+use clap::CommandFactory;
+use cli::{CacheCommand, CleanArgs, Cli, Command, CpArgs, InspectArgs, RunArgs};
+use rust_ns_overlay::{DebugSession, OutputFormat, maintenance, transfer};
+use std::ffi::OsStr;
 use std::path::Path;
-use std::path::absolute;
-use std::time::Duration;
-use sys_mount::MountFlags;
 use sys_mount::SupportedFilesystems;
-use sys_mount::UnmountFlags;
-use tokio::runtime::Runtime;
-
-use crate::cli::VerArgs;
+use tracing::error;
+use tracing_subscriber::EnvFilter;
 
 // this is necessary to force single thread for setns
 fn main() -> Result<()> {
-    let ver_args = VerArgs::try_parse();
-    if let Ok(arg) = ver_args
-        && arg.version
-    {
-        println!("version: {}", env!("PROGRAM_VERSION"));
-        println!("commit : {}", env!("VERGEN_GIT_SHA"));
-        return Ok(());
+    // exits on --help & --version
+    let cli = Cli::parse_compat()?;
+    init_logging(&cli.log_level, cli.quiet);
+
+    match cli.command {
+        Command::Run(args) => run(&args, cli.quiet),
+        Command::Inspect(args) => inspect(&args),
+        Command::Cp(args) => cp(&args, cli.quiet),
+        Command::Clean(args) => clean(&args, cli.quiet),
+        Command::Cache(command) => cache(&command, cli.quiet),
+        Command::Completions { shell } => {
+            completions(shell);
+            Ok(())
+        }
     }
+}
 
-    // check for overlay support
+fn run(args: &RunArgs, quiet: bool) -> Result<()> {
+    // check for overlay support, --no-overlay gets by with bind mounts
+    if !args.no_overlay {
+        check_overlay_support()?;
+    }
+    if args.dry_run {
+        return args.session_builder().quiet(quiet).dry_run();
+    }
+    if !args.skip_privilege_check {
+        check_privileges()?;
+    }
+    let session = args.session_builder().quiet(quiet).build()?;
+    let code = session.run()?;
+    std::process::exit(code);
+}
+
+fn check_overlay_support() -> Result<()> {
     let supported = match SupportedFilesystems::new() {
         Ok(supported) => supported,
         Err(why) => {
-            eprintln!("failed to get supported file systems: {}", why);
+            error!("failed to get supported file systems: {}", why);
             return Err(anyhow::anyhow!(
                 "failed to get supported file systems: {}",
                 why
@@ -46,203 +59,147 @@ fn main() -> Result<()> {
         }
     };
     if !supported.is_supported("overlay") {
-        return Err(anyhow::anyhow!("overlay is not supported"));
+        return Err(anyhow::anyhow!(
+            "overlay is not supported, try --no-overlay"
+        ));
     }
+    Ok(())
+}
 
-    // init
-    let rt = Runtime::new()?;
-    let args = Args::try_parse()?;
-    let docker = docker_helper::DockerHelper::new()?;
-
-    let cache_dir = Path::new(&args.cache_dir);
-    let work_dir = Path::new(&args.workdir);
-    let abs_workdir = absolute(&work_dir)?;
-
-    // get container info & unmount all previously mounted specs
-    let container_info = rt.block_on(docker.get_container_info(&args.id))?;
-    println!("container info: {:?}", container_info);
-    for mount_entry in procfs::mounts()? {
-        // unmount everything under workdir
-        if mount_entry
-            .fs_file
-            .starts_with(&abs_workdir.to_str().unwrap())
-        {
-            println!("unmounting: {}", mount_entry.fs_file);
-            sys_mount::unmount(mount_entry.fs_file, UnmountFlags::DETACH)?;
-        }
+// every mount & setns needs CAP_SYS_ADMIN, fail before pulling anything
+// rather than halfway through with a bare errno
+fn check_privileges() -> Result<()> {
+    let has_sys_admin = caps::has_cap(None, CapSet::Effective, Capability::CAP_SYS_ADMIN)
+        .context("failed to read process capabilities")?;
+    if !has_sys_admin {
+        return Err(anyhow::anyhow!(
+            "missing CAP_SYS_ADMIN (euid {}), run as root, e.g. with sudo, or pass --skip-privilege-check",
+            unsafe { libc::geteuid() }
+        ));
     }
+    Ok(())
+}
 
-    // prepare work directory
-    let image_extract_dir = work_dir.join("tmp_extract");
-    let overlay_lower_dir = work_dir.join("tmp_lower");
-    let overlay_work_dir = work_dir.join("tmp_work");
-    let rootfs_base_dir = work_dir.join("rootfs");
-    let abs_rootfs_base_dir = absolute(&rootfs_base_dir)?;
-    let mergedfs_dir = work_dir.join("mergedfs");
-    if work_dir.exists() {
-        let _ = fs::remove_dir_all(work_dir);
+fn inspect(args: &InspectArgs) -> Result<()> {
+    let container_info = DebugSession::builder()
+        .container_id(&args.id)
+        .runtime(args.runtime)
+        .docker_host(args.docker_host.clone())
+        .inspect()?;
+    if args.output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&container_info)?);
+        return Ok(());
     }
-    create_dir_all(&overlay_lower_dir)?;
-    create_dir_all(&cache_dir)?;
-    create_dir_all(&work_dir)?;
-    create_dir_all(&image_extract_dir)?;
-    create_dir_all(&rootfs_base_dir)?;
-    create_dir_all(&overlay_work_dir)?;
-    create_dir_all(&mergedfs_dir)?;
-
-    // image preparation
-    let mut found_cache = false;
-    if args.cache {
-        let cache_path = cache_dir.join(args.image_cache_filename());
-        if cache_path.exists() {
-            found_cache = true;
-            println!("found cache: {}", cache_path.display());
-            let mut f = File::open(cache_path)?;
-            utils::extract_archive(&mut f, &rootfs_base_dir)?;
+    println!("id        : {}", container_info.id);
+    println!("name      : {}", container_info.name);
+    println!("image     : {}", container_info.image);
+    println!("pid       : {}", container_info.pid);
+    println!("running   : {}", container_info.running);
+    println!("driver    : {}", container_info.driver);
+    match &container_info.merged_dir {
+        Some(merged_dir) => println!("merged dir: {}", merged_dir),
+        None => {
+            println!(
+                "upper dir : {}",
+                container_info.upper_dir.as_deref().unwrap_or("-")
+            );
+            println!(
+                "lower dir : {}",
+                container_info.lower_dir.as_deref().unwrap_or("-")
+            );
         }
     }
-
-    if !found_cache {
-        rt.block_on(docker.export_overlay_image(
-            &args.image,
-            &image_extract_dir,
-            &rootfs_base_dir,
-            args.pull,
-        ))?;
-    }
-    rt.shutdown_timeout(Duration::from_secs(0));
-
-    // build rootfs mount
-    let mount_opt = format!(
-        "lowerdir={},upperdir={},workdir={}",
-        absolute(&overlay_lower_dir)?.display(),
-        &abs_rootfs_base_dir.display(),
-        absolute(&overlay_work_dir)?.display(),
+    println!(
+        "cgroup    : {}",
+        container_info.cgroup.as_deref().unwrap_or("-")
     );
-    sys_mount::Mount::builder()
-        .fstype("overlay")
-        .data(&mount_opt)
-        .mount(&rootfs_base_dir, &mergedfs_dir)
-        .context("failed to mount overlayfs")?;
-    // TODO: make mount temporary?
-    // mount_res.into_unmount_drop(UnmountFlags::DETACH);
-
-    // build container mount
-
-    // container dir preparation
-    // TODO: readonly mount ?
-    let container_mount_path =
-        absolute(mergedfs_dir.join(&args.container_mount_path.trim_start_matches("/")))?;
-    create_dir_all(&container_mount_path)?;
-    sys_mount::Mount::builder()
-        .flags(MountFlags::BIND)
-        .mount(&container_info.merged_dir, &container_mount_path)
-        .context("failed to mount container fs")?;
+    for (name, id) in &container_info.namespaces {
+        println!("ns {:<7}: {}", name, id);
+    }
+    for (key, value) in &container_info.labels {
+        println!("label     : {}={}", key, value);
+    }
+    Ok(())
+}
 
-    // prepare init script
-    {
-        let init_script_content = include_str!("init.sh");
-        let mut init_script_file = File::create(mergedfs_dir.join("init.sh"))?;
-        init_script_file.write_all(init_script_content.as_bytes())?;
-        init_script_file.set_permissions(Permissions::from_mode(0o755))?;
+fn cp(args: &CpArgs, quiet: bool) -> Result<()> {
+    let container_info = DebugSession::builder()
+        .container_id(&args.id)
+        .runtime(args.runtime)
+        .docker_host(args.docker_host.clone())
+        .inspect()?;
+    let dest =
+        transfer::copy_from_container(&container_info, &args.container_path, &args.host_path)?;
+    if !quiet {
+        println!("copied {} to {}", args.container_path, dest.display());
     }
+    Ok(())
+}
 
-    // enter container namespace
-    namespace_helper::enter_namespace(
-        container_info.pid as i32,
-        libc::CLONE_NEWCGROUP
-                | libc::CLONE_NEWIPC
-                | libc::CLONE_NEWNET
-                // | libc::CLONE_NEWNS // we will enter mount from host
-                | libc::CLONE_NEWPID
-                | libc::CLONE_NEWUTS,
-    )?;
+fn completions(shell: clap_complete::Shell) {
+    let mut command = Cli::command();
+    let bin_name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, bin_name, &mut std::io::stdout());
+}
 
-    // fork 1
-    let fork_res = unsafe { libc::fork() };
-    match fork_res {
-        // In the child process
-        0 => {
-            // println!("fork 1 child");
-        }
-        // In the parent process
-        pid if pid > 0 => {
-            // println!("fork 1 parent");
-            unsafe {
-                libc::wait(0 as *mut i32);
-            }
-            if args.cache {
-                let cache_path = cache_dir.join(args.image_cache_filename());
-                println!("saving work cache to: {}", cache_path.display());
-                let f = File::create(cache_path)?;
-                let mut archive = tar::Builder::new(f);
-                archive.follow_symlinks(false);
-                archive
-                    .append_dir_all("", &abs_rootfs_base_dir)
-                    .context(format!(
-                        "failed to append dir all, path: {}",
-                        &abs_rootfs_base_dir.display(),
-                    ))?;
-            }
-            // unmount
-            sys_mount::unmount(&container_mount_path, UnmountFlags::DETACH)?;
-            if args.unmount_on_exit {
-                sys_mount::unmount(&mergedfs_dir, UnmountFlags::DETACH)?;
-            }
-            return Ok(());
+fn clean(args: &CleanArgs, quiet: bool) -> Result<()> {
+    if !args.cache || args.all {
+        let report = maintenance::clean_workdir(Path::new(&args.workdir))?;
+        for path in report.unmounted.iter().filter(|_| !quiet) {
+            println!("unmounted: {}", path.display());
         }
-        // If fork fails
-        _ => {
-            eprintln!("Fork failed");
-            return Err(anyhow::anyhow!("Fork failed"));
+        for path in report.removed.iter().filter(|_| !quiet) {
+            println!("removed: {}", path.display());
         }
     }
-
-    // clone mount namespace
-    let enter_res = unsafe { libc::unshare(libc::CLONE_NEWNS) };
-    if enter_res != 0 {
-        eprintln!("Failed to unshare namespaces, {}", enter_res);
-        return Err(anyhow::anyhow!(
-            "Failed to unshare namespaces, {}",
-            enter_res
-        ));
+    if args.cache || args.all {
+        let removed = maintenance::clean_cache(Path::new(&args.cache_dir))?;
+        for path in removed.iter().filter(|_| !quiet) {
+            println!("removed: {}", path.display());
+        }
     }
+    Ok(())
+}
 
-    // fork 2
-    let fork_res = unsafe { libc::fork() };
-    match fork_res {
-        // In the child process
-        0 => {
-            // println!("Child process 2");
-            set_current_dir(&mergedfs_dir)?;
-            let exec_res = unsafe {
-                let cmd = CString::new("/usr/bin/bash").expect("CString::new failed");
-                let arg1 = CString::new("--init-file").expect("CString::new failed");
-                let arg2 = CString::new("init.sh").expect("CString::new failed");
-                let args = [
-                    arg1.as_ptr(),
-                    arg2.as_ptr(),
-                    std::ptr::null(), // Null-terminated argument list
-                ];
-                libc::execv(cmd.as_ptr(), args.as_ptr())
-            };
-            println!("Exec failed: {}", exec_res);
-        }
-        // In the parent process
-        pid if pid > 0 => {
-            // println!("Parent process 2");
-            unsafe {
-                libc::wait(0 as *mut i32);
+fn cache(command: &CacheCommand, quiet: bool) -> Result<()> {
+    match command {
+        CacheCommand::Ls { cache_dir } => {
+            for entry in maintenance::cache_entries(Path::new(cache_dir))? {
+                let last_used = humantime::format_rfc3339_seconds(entry.last_used);
+                println!(
+                    "{}\t{}\t{}",
+                    entry.path.file_name().unwrap_or_default().to_string_lossy(),
+                    entry.size,
+                    last_used
+                );
             }
-            // println!("Parent process exit ");
-            return Ok(());
         }
-        // If fork fails
-        _ => {
-            eprintln!("Fork failed");
-            return Err(anyhow::anyhow!("Fork failed"));
+        CacheCommand::Rm { cache_dir, names } => {
+            for name in names {
+                // names only, never a path out of the cache dir
+                if Path::new(name).file_name() != Some(OsStr::new(name)) {
+                    return Err(anyhow::anyhow!("invalid cache name: {}", name));
+                }
+                let cache_path = Path::new(cache_dir).join(name);
+                maintenance::remove_cache_entry(&cache_path)
+                    .context(format!("failed to remove {}", cache_path.display()))?;
+                if !quiet {
+                    println!("removed: {}", cache_path.display());
+                }
+            }
         }
     }
-
     Ok(())
 }
+
+// RUST_LOG wins over --log-level & --quiet so module level filters stay available
+fn init_logging(log_level: &str, quiet: bool) {
+    let log_level = if quiet { "error" } else { log_level };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(log_level));
+    // stdout is reserved for command output & json events
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .init();
+}
@@ -0,0 +1,67 @@
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf, absolute};
+
+use crate::cache_helper::{self, CacheEntry};
+use crate::lock_helper::WorkdirLock;
+use crate::mount_helper;
+
+/// Cache entries in `cache_dir`, least recently used first.
+pub fn cache_entries(cache_dir: &Path) -> Result<Vec<CacheEntry>> {
+    if !cache_dir.exists() {
+        return Ok(Vec::new());
+    }
+    cache_helper::list_cache(cache_dir)
+}
+
+/// Remove a cache entry & its sidecar files.
+pub fn remove_cache_entry(cache_path: &Path) -> Result<()> {
+    cache_helper::remove_cache_entry(cache_path)
+}
+
+/// What [`clean_workdir`] took down.
+#[derive(Debug, Default)]
+pub struct CleanReport {
+    pub unmounted: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+/// Unmount everything under `workdir` & remove it. Refuses while a session
+/// holds the workdir or a mount is still busy, so a live session's files are
+/// never deleted from under it.
+pub fn clean_workdir(workdir: &Path) -> Result<CleanReport> {
+    let workdir = absolute(workdir)?;
+    let mut report = CleanReport::default();
+    if !workdir.exists() {
+        return Ok(report);
+    }
+    let _lock = WorkdirLock::acquire(&workdir, false)?;
+    report.unmounted = mount_helper::unmount_all_under_unless_busy(&workdir)?;
+    // remove_dir_all would descend into anything still mounted
+    if let Some(mount_point) = mount_helper::mount_points_under(&workdir)?.first() {
+        return Err(anyhow::anyhow!(
+            "{} is still mounted, refusing to remove {}",
+            mount_point.display(),
+            workdir.display()
+        ));
+    }
+    fs::remove_dir_all(&workdir)?;
+    report.removed.push(workdir);
+    Ok(report)
+}
+
+/// Remove every cache entry & the layer cache in `cache_dir`, returns the
+/// removed paths.
+pub fn clean_cache(cache_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for entry in cache_entries(cache_dir)? {
+        cache_helper::remove_cache_entry(&entry.path)?;
+        removed.push(entry.path);
+    }
+    let layers_dir = cache_dir.join("layers");
+    if layers_dir.exists() {
+        fs::remove_dir_all(&layers_dir)?;
+        removed.push(layers_dir);
+    }
+    Ok(removed)
+}
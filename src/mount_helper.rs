@@ -0,0 +1,443 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::ffi::CString;
+use std::fs::{File, create_dir_all};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf, absolute};
+use sys_mount::{MountFlags, UnmountFlags};
+use tracing::{info, warn};
+
+use crate::utils;
+
+/// Host path bind mounted into the debug rootfs, parsed from
+/// `<host>:<dest>[:ro]`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HostMount {
+    pub source: PathBuf,
+    pub dest: String,
+    pub readonly: bool,
+}
+
+impl std::str::FromStr for HostMount {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let parts = spec.split(':').collect::<Vec<_>>();
+        let (source, dest, readonly) = match parts.as_slice() {
+            [source, dest] => (source, dest, false),
+            [source, dest, "ro"] => (source, dest, true),
+            [source, dest, "rw"] => (source, dest, false),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "invalid mount: {}, expect <host>:<dest>[:ro]",
+                    spec
+                ));
+            }
+        };
+        if source.is_empty() || dest.is_empty() {
+            return Err(anyhow::anyhow!(
+                "invalid mount: {}, expect <host>:<dest>[:ro]",
+                spec
+            ));
+        }
+        Ok(HostMount {
+            source: PathBuf::from(source),
+            dest: dest.to_string(),
+            readonly,
+        })
+    }
+}
+
+// detaches the mount on drop, forked children inherit a copy of the guard so
+// only the process that mounted it unmounts
+pub struct MountGuard {
+    target: PathBuf,
+    owner_pid: libc::pid_t,
+    active: bool,
+}
+
+impl MountGuard {
+    fn new(target: &Path) -> Result<Self> {
+        Ok(MountGuard {
+            target: absolute(target)?,
+            owner_pid: unsafe { libc::getpid() },
+            active: true,
+        })
+    }
+
+    // keep the mount alive after we exit
+    pub fn leak(&mut self) {
+        self.active = false;
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        if !self.active || unsafe { libc::getpid() } != self.owner_pid {
+            return;
+        }
+        if let Err(e) = sys_mount::unmount(&self.target, UnmountFlags::DETACH) {
+            warn!("failed to unmount {}: {}", self.target.display(), e);
+        }
+    }
+}
+
+// move into a mount namespace of our own with private propagation, mounts made
+// afterwards are never seen by the host & go away with the last process using
+// them, forked children inherit the namespace. needs a single threaded process
+pub fn unshare_private_mounts() -> Result<()> {
+    if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+        return Err(anyhow::anyhow!(
+            "failed to unshare mount namespace: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    // a shared root would still propagate our mounts back to the host
+    let root = c"/";
+    let res = unsafe {
+        libc::mount(
+            std::ptr::null(),
+            root.as_ptr(),
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        )
+    };
+    if res != 0 {
+        return Err(anyhow::anyhow!(
+            "failed to make mounts private: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+pub fn mount_overlay(mount_opt: &str, source: &Path, target: &Path) -> Result<MountGuard> {
+    check_mount_data_len(mount_opt)?;
+    if let Some(upper_dir) = overlay_option(mount_opt, "upperdir")
+        && is_on_overlayfs(Path::new(upper_dir))
+    {
+        warn!(
+            "upper dir {} is on overlayfs itself, nested overlays often fail, e.g. when running inside a container",
+            upper_dir
+        );
+    }
+    if let Err(e) = sys_mount::Mount::builder()
+        .fstype("overlay")
+        .data(mount_opt)
+        .mount(source, target)
+    {
+        // the errno alone rarely says which option the kernel disliked
+        let message = last_overlay_kernel_message();
+        let mut err = match &message {
+            Some(message) => {
+                anyhow::anyhow!("failed to mount overlayfs: {}, kernel: {}", e, message)
+            }
+            None => anyhow::anyhow!("failed to mount overlayfs: {}", e),
+        };
+        if let Some(hint) = overlay_mount_hint(e.raw_os_error(), message.as_deref()) {
+            err = err.context(hint);
+        }
+        return Err(err);
+    }
+    MountGuard::new(target)
+}
+
+// value of key=value in an overlay option string
+fn overlay_option<'a>(mount_opt: &'a str, key: &str) -> Option<&'a str> {
+    mount_opt
+        .split(',')
+        .find_map(|opt| opt.strip_prefix(key)?.strip_prefix('='))
+}
+
+fn is_on_overlayfs(path: &Path) -> bool {
+    const OVERLAYFS_SUPER_MAGIC: libc::c_long = 0x794c7630;
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    unsafe { stat.assume_init() }.f_type as libc::c_long == OVERLAYFS_SUPER_MAGIC
+}
+
+// what to do about the usual overlay mount failures, by kernel message first
+// & errno otherwise
+fn overlay_mount_hint(errno: Option<i32>, kernel_message: Option<&str>) -> Option<&'static str> {
+    if let Some(message) = kernel_message {
+        let hint = if message.contains("maximum fs stacking depth exceeded") {
+            Some(
+                "the workdir is on a nested overlay, move --workdir to a filesystem like ext4 or xfs",
+            )
+        } else if message.contains("not supported as upperdir")
+            || message.contains("does not support xattr")
+            || message.contains("does not support tmpfile")
+        {
+            Some(
+                "the workdir filesystem can't hold an overlay upper dir, move --workdir to ext4, xfs or btrfs, or try --overlay-opt userxattr",
+            )
+        } else if message.contains("must reside under the same mount")
+            || message.contains("workdir and upperdir must be separate")
+        {
+            Some("upper & work dir must be separate dirs on the same filesystem")
+        } else if message.contains("unrecognized mount option") || message.contains("bad option") {
+            Some("the kernel doesn't know one of the overlay options, drop the --overlay-opt")
+        } else if message.contains("conflicting options") {
+            Some(
+                "two overlay options conflict, e.g. metacopy=on with redirect_dir=off, check --overlay-opt",
+            )
+        } else {
+            None
+        };
+        if hint.is_some() {
+            return hint;
+        }
+    }
+    match errno {
+        Some(libc::ENODEV) => {
+            Some("the overlay module isn't loaded, try `modprobe overlay` or --no-overlay")
+        }
+        Some(libc::EPERM) => Some(
+            "mounting overlay needs CAP_SYS_ADMIN in the initial user namespace, run as root on the host",
+        ),
+        Some(libc::EINVAL) => Some(
+            "the kernel refused the mount options, check its log with `dmesg | grep overlayfs` or try --no-overlay",
+        ),
+        _ => None,
+    }
+}
+
+// overlay options the running kernel knows about, a parameter under
+// /sys/module/overlay means the matching mount option is supported. metacopy
+// stays off so the upper dir, which is what gets cached, holds whole files
+pub fn default_overlay_opts() -> Vec<String> {
+    let parameters_dir = Path::new("/sys/module/overlay/parameters");
+    let mut opts = Vec::new();
+    if parameters_dir.join("redirect_dir").exists() {
+        opts.push(String::from("redirect_dir=on"));
+    }
+    if parameters_dir.join("metacopy").exists() {
+        opts.push(String::from("metacopy=off"));
+    }
+    opts
+}
+
+// append extra options to a mount_opt string, options given by the user
+// replace automatic ones with the same key
+pub fn with_overlay_opts(mount_opt: &str, auto_opts: &[String], user_opts: &[String]) -> String {
+    let key = |opt: &str| opt.split_once('=').map_or(opt, |(key, _)| key).to_string();
+    let user_keys = user_opts.iter().map(|opt| key(opt)).collect::<Vec<_>>();
+    let mut opts = vec![mount_opt.to_string()];
+    opts.extend(
+        auto_opts
+            .iter()
+            .filter(|opt| !user_keys.contains(&key(opt)))
+            .cloned(),
+    );
+    opts.extend(user_opts.iter().cloned());
+    opts.join(",")
+}
+
+// latest overlayfs line from the kernel log, none when it can't be read
+fn last_overlay_kernel_message() -> Option<String> {
+    let mut kmsg = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/kmsg")
+        .ok()?;
+    let mut message = None;
+    // every read returns one record, EAGAIN once the buffer is drained
+    let mut record = [0u8; 8192];
+    while let Ok(len) = kmsg.read(&mut record) {
+        if len == 0 {
+            break;
+        }
+        let record = String::from_utf8_lossy(&record[..len]);
+        // <prefix>;<text>
+        if let Some((_, text)) = record.split_once(';')
+            && text.starts_with("overlayfs:")
+        {
+            message = Some(text.trim_end().to_string());
+        }
+    }
+    message
+}
+
+// the kernel copies at most one page of mount data, longer options are cut
+// short & fail with a confusing error
+pub fn check_mount_data_len(mount_opt: &str) -> Result<()> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    let page_size = if page_size > 0 {
+        page_size as usize
+    } else {
+        4096
+    };
+    // leave room for the trailing nul
+    if mount_opt.len() >= page_size {
+        return Err(anyhow::anyhow!(
+            "overlay mount options are {} bytes, over the {} byte kernel limit, use a shorter --workdir",
+            mount_opt.len(),
+            page_size - 1
+        ));
+    }
+    Ok(())
+}
+
+pub fn bind_mount(source: &Path, target: &Path, readonly: bool) -> Result<MountGuard> {
+    sys_mount::Mount::builder()
+        .flags(MountFlags::BIND)
+        .mount(source, target)
+        .context(format!("failed to bind mount {}", source.display()))?;
+    let guard = MountGuard::new(target)?;
+    // bind mounts ignore RDONLY on creation, it only applies on remount
+    if readonly {
+        sys_mount::Mount::builder()
+            .flags(MountFlags::BIND | MountFlags::RDONLY | MountFlags::REMOUNT)
+            .mount(source, target)
+            .context(format!("failed to remount {} readonly", target.display()))?;
+    }
+    Ok(guard)
+}
+
+// bind a host path onto dest inside rootfs_dir, creating the mount point
+pub fn bind_host_mount(host_mount: &HostMount, rootfs_dir: &Path) -> Result<MountGuard> {
+    let source = absolute(&host_mount.source)?;
+    let metadata = source
+        .metadata()
+        .context(format!("mount source {} not found", source.display()))?;
+    let target = utils::rootfs_path(rootfs_dir, &host_mount.dest)?;
+    // a file can only be bound onto a file
+    if metadata.is_dir() {
+        create_dir_all(&target)?;
+    } else {
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        if target.symlink_metadata().is_err() {
+            File::create(&target)?;
+        }
+    }
+    info!("bind mounting {} on {}", source.display(), target.display());
+    bind_mount(&source, &target, host_mount.readonly)
+}
+
+// mount points under dir (component wise, /work2 is not under /work), deepest
+// first and most recent first so nested & stacked mounts come down cleanly
+pub fn mounts_under(mount_points: Vec<PathBuf>, dir: &Path) -> Vec<PathBuf> {
+    let mut nested = mount_points
+        .into_iter()
+        .rev()
+        .filter(|mount_point| mount_point.starts_with(dir))
+        .collect::<Vec<_>>();
+    nested.sort_by_key(|mount_point| Reverse(mount_point.components().count()));
+    nested
+}
+
+// current mount points under dir, in unmount order
+pub fn mount_points_under(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mount_points = procfs::mounts()?
+        .into_iter()
+        .map(|mount_entry| PathBuf::from(mount_entry.fs_file))
+        .collect();
+    Ok(mounts_under(mount_points, dir))
+}
+
+pub fn unmount_all_under(dir: &Path) -> Result<()> {
+    for mount_point in mount_points_under(dir)? {
+        info!("unmounting: {}", mount_point.display());
+        sys_mount::unmount(&mount_point, UnmountFlags::DETACH)?;
+    }
+    Ok(())
+}
+
+// unmount everything under dir without detaching, a mount still in use fails
+// with the busy path instead of being lazily hidden, returns the unmounted paths
+pub fn unmount_all_under_unless_busy(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut unmounted = Vec::new();
+    for mount_point in mount_points_under(dir)? {
+        info!("unmounting: {}", mount_point.display());
+        if let Err(e) = sys_mount::unmount(&mount_point, UnmountFlags::empty()) {
+            if e.raw_os_error() == Some(libc::EBUSY) {
+                return Err(anyhow::anyhow!(
+                    "{} is still busy, exit the debug session using it first",
+                    mount_point.display()
+                ));
+            }
+            return Err(e).context(format!("failed to unmount {}", mount_point.display()));
+        }
+        unmounted.push(mount_point);
+    }
+    Ok(unmounted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn mounts_under_matches_whole_components() {
+        let mount_points = paths(&[
+            "/",
+            "/var/lib/rustnsoverlay/work",
+            "/var/lib/rustnsoverlay/work2",
+            "/var/lib/rustnsoverlay/work2/mergedfs",
+            "/var/lib/rustnsoverlay/work/mergedfs",
+        ]);
+        assert_eq!(
+            mounts_under(mount_points, Path::new("/var/lib/rustnsoverlay/work")),
+            paths(&[
+                "/var/lib/rustnsoverlay/work/mergedfs",
+                "/var/lib/rustnsoverlay/work",
+            ])
+        );
+    }
+
+    #[test]
+    fn mounts_under_unmounts_deepest_then_latest_first() {
+        // /proc/mounts lists mounts in mount order
+        let mount_points = paths(&[
+            "/work/mergedfs",
+            "/work/mergedfs/mnt/container",
+            "/work/mergedfs/mnt/container/data",
+            "/work/mergedfs/tmp",
+            "/work/mergedfs",
+        ]);
+        assert_eq!(
+            mounts_under(mount_points, Path::new("/work")),
+            paths(&[
+                "/work/mergedfs/mnt/container/data",
+                "/work/mergedfs/mnt/container",
+                "/work/mergedfs/tmp",
+                "/work/mergedfs",
+                "/work/mergedfs",
+            ])
+        );
+    }
+
+    #[test]
+    fn check_mount_data_len_rejects_over_a_page() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let workdir = format!("/{}", "w".repeat(page_size));
+        let mount_opt = format!(
+            "lowerdir=/lower,upperdir={0}/upper,workdir={0}/work",
+            workdir
+        );
+        let err = check_mount_data_len(&mount_opt).unwrap_err();
+        assert!(err.to_string().contains("--workdir"), "{}", err);
+        assert!(check_mount_data_len(&"o".repeat(page_size - 1)).is_ok());
+        assert!(check_mount_data_len(&"o".repeat(page_size)).is_err());
+    }
+
+    #[test]
+    fn check_mount_data_len_accepts_short_options() {
+        assert!(check_mount_data_len("lowerdir=/a:/b,upperdir=/c,workdir=/d").is_ok());
+    }
+}
@@ -1,23 +1,498 @@
 use anyhow::{Ok, Result};
 use libc::c_int;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use tracing::{debug, error, info};
 
+// namespace flag & its file under /proc/<pid>/ns
+const NAMESPACE_FILES: [(c_int, &str); 6] = [
+    (libc::CLONE_NEWCGROUP, "cgroup"),
+    (libc::CLONE_NEWIPC, "ipc"),
+    (libc::CLONE_NEWNET, "net"),
+    (libc::CLONE_NEWPID, "pid"),
+    (libc::CLONE_NEWTIME, "time"),
+    (libc::CLONE_NEWUTS, "uts"),
+];
+
+/// Network namespace the debug shell runs in.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum NetworkMode {
+    /// the container's, as selected by the namespaces to enter
+    #[default]
+    Container,
+    /// the host's, the container's interfaces aren't visible
+    Host,
+}
+
+// build setns flags from namespace names of pid, "all" selects every supported
+// namespace. time is only part of "all" when pid has a time namespace of its
+// own, most containers share the host's
+pub fn ns_flags_from_names(names: &[String], pid: i32) -> Result<c_int> {
+    let mut ns_flags = 0;
+    for name in names {
+        if name == "all" {
+            ns_flags |= NAMESPACE_FILES
+                .iter()
+                .filter(|(ns_flag, _)| *ns_flag != libc::CLONE_NEWTIME)
+                .fold(0, |flags, (ns_flag, _)| flags | ns_flag);
+            if has_own_time_namespace(pid) {
+                debug!("target has its own time namespace");
+                ns_flags |= libc::CLONE_NEWTIME;
+            }
+            continue;
+        }
+        if name == "time" && !Path::new("/proc/self/ns/time").exists() {
+            return Err(anyhow::anyhow!(
+                "time namespaces are not supported by this kernel, linux 5.6 or later is needed"
+            ));
+        }
+        let Some((ns_flag, _)) = NAMESPACE_FILES.iter().find(|(_, ns_name)| ns_name == name) else {
+            let valid_names = NAMESPACE_FILES
+                .iter()
+                .map(|(_, ns_name)| *ns_name)
+                .collect::<Vec<_>>();
+            return Err(anyhow::anyhow!(
+                "unknown namespace: {}, valid values: all, {}",
+                name,
+                valid_names.join(", ")
+            ));
+        };
+        ns_flags |= ns_flag;
+    }
+    Ok(ns_flags)
+}
+
+// every namespace of pid as its /proc/<pid>/ns link target, unreadable ones
+// are left out
+pub fn namespace_ids(pid: u64) -> BTreeMap<String, String> {
+    let Some(entries) = std::fs::read_dir(format!("/proc/{}/ns", pid)).ok() else {
+        return BTreeMap::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let target = std::fs::read_link(entry.path()).ok()?;
+            Some((
+                entry.file_name().to_string_lossy().to_string(),
+                target.to_string_lossy().to_string(),
+            ))
+        })
+        .collect()
+}
+
+// cgroup v2 path of pid, or the hierarchies joined by ; on v1 hosts
+pub fn cgroup_path(pid: u64) -> Option<String> {
+    let content = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    let hierarchies = content
+        .lines()
+        .filter_map(|line| {
+            let (_, rest) = line.split_once(':')?;
+            let (controllers, path) = rest.split_once(':')?;
+            Some((controllers, path))
+        })
+        .collect::<Vec<_>>();
+    if let Some((_, path)) = hierarchies
+        .iter()
+        .find(|(controllers, _)| controllers.is_empty())
+    {
+        return Some(path.to_string());
+    }
+    if hierarchies.is_empty() {
+        return None;
+    }
+    Some(
+        hierarchies
+            .iter()
+            .map(|(controllers, path)| format!("{}:{}", controllers, path))
+            .collect::<Vec<_>>()
+            .join(";"),
+    )
+}
+
+// working directory of pid as seen from inside its mount namespace
+pub fn process_cwd(pid: u64) -> Option<String> {
+    let cwd = std::fs::read_link(format!("/proc/{}/cwd", pid)).ok()?;
+    Some(cwd.to_string_lossy().to_string())
+}
+
+// environment pid was started with, entries without = are skipped
+pub fn process_environ(pid: u64) -> Result<Vec<(String, String)>> {
+    let environ_path = format!("/proc/{}/environ", pid);
+    let content = std::fs::read(&environ_path)
+        .map_err(|e| anyhow::anyhow!("failed to read {}: {}", environ_path, e))?;
+    Ok(content
+        .split(|byte| *byte == 0)
+        .filter_map(|entry| {
+            let entry = String::from_utf8_lossy(entry);
+            let (key, value) = entry.split_once('=')?;
+            if key.is_empty() {
+                return None;
+            }
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect())
+}
+
+// move the calling process into a cgroup v2 path as read by cgroup_path,
+// must happen before setns since a cgroup namespace hides the host hierarchy
+pub fn join_cgroup(cgroup: &str) -> Result<()> {
+    let cgroup_root = Path::new("/sys/fs/cgroup");
+    if cgroup.contains(':') || !cgroup_root.join("cgroup.controllers").exists() {
+        return Err(anyhow::anyhow!(
+            "joining a cgroup needs the cgroup v2 unified hierarchy at {}",
+            cgroup_root.display()
+        ));
+    }
+    let procs_path = cgroup_root
+        .join(cgroup.trim_start_matches('/'))
+        .join("cgroup.procs");
+    std::fs::write(&procs_path, std::process::id().to_string())
+        .map_err(|e| anyhow::anyhow!("failed to join cgroup {}: {}", procs_path.display(), e))?;
+    info!("joined cgroup {}", cgroup);
+    Ok(())
+}
+
+// on failure every namespace already switched is set back to the caller's,
+// so an error never leaves the process half in the target
 pub fn enter_namespace(pid: i32, ns_flags: c_int) -> Result<()> {
-    println!("entering target process namespace",);
+    info!("entering target process namespace");
+    let saved = SavedNamespaces::open(ns_flags)?;
+    let Err(err) =
+        enter_namespace_by_pidfd(pid, ns_flags).and_then(|_| verify_namespaces(pid, ns_flags))
+    else {
+        return Ok(());
+    };
+    let failed = saved.restore();
+    if failed.is_empty() {
+        return Err(err.context("rolled back to the original namespaces"));
+    }
+    error!("failed to roll back namespaces: {}", failed.join(", "));
+    Err(err.context(format!(
+        "rollback failed, still in the target {} namespaces",
+        failed.join(", ")
+    )))
+}
+
+// the caller's namespaces from before setns
+struct SavedNamespaces {
+    files: Vec<(c_int, &'static str, File, u64)>,
+}
+
+impl SavedNamespaces {
+    fn open(ns_flags: c_int) -> Result<Self> {
+        let mut files = Vec::new();
+        for (ns_flag, ns_name) in NAMESPACE_FILES {
+            if ns_flags & ns_flag == 0 {
+                continue;
+            }
+            let ns_path = format!("/proc/self/ns/{}", self_ns_name(ns_flag, ns_name));
+            let ns_file = File::open(&ns_path)
+                .map_err(|e| anyhow::anyhow!("failed to open {}: {}", ns_path, e))?;
+            let inode = ns_file.metadata()?.ino();
+            files.push((ns_flag, ns_name, ns_file, inode));
+        }
+        Ok(SavedNamespaces { files })
+    }
+
+    // switch back every namespace that changed, returns the ones that
+    // couldn't be, e.g. the kernel refuses to go back up a pid namespace
+    fn restore(&self) -> Vec<&'static str> {
+        self.restore_with(
+            |ns_flag, ns_name| {
+                read_ns_inode(&format!("/proc/self/ns/{}", self_ns_name(ns_flag, ns_name))).ok()
+            },
+            |ns_file, ns_flag| {
+                if unsafe { libc::setns(ns_file.as_raw_fd(), ns_flag) } != 0 {
+                    return Err(std::io::Error::last_os_error().into());
+                }
+                Ok(())
+            },
+        )
+    }
+
+    // restore with the current inode lookup & setns passed in, newest first
+    fn restore_with(
+        &self,
+        current_inode: impl Fn(c_int, &'static str) -> Option<u64>,
+        mut setns: impl FnMut(&File, c_int) -> Result<()>,
+    ) -> Vec<&'static str> {
+        let mut failed = Vec::new();
+        for (ns_flag, ns_name, ns_file, inode) in self.files.iter().rev() {
+            if current_inode(*ns_flag, ns_name) == Some(*inode) {
+                continue;
+            }
+            if let Err(e) = setns(ns_file, *ns_flag) {
+                debug!("restore {} namespace failed: {}", ns_name, e);
+                failed.push(*ns_name);
+                continue;
+            }
+            debug!("restored {} namespace", ns_name);
+        }
+        failed
+    }
+}
+
+// setns on a pid namespace only applies to children, a time namespace is only
+// sure to apply to them
+fn self_ns_name(ns_flag: c_int, ns_name: &'static str) -> &'static str {
+    match ns_flag {
+        libc::CLONE_NEWPID => "pid_for_children",
+        libc::CLONE_NEWTIME => "time_for_children",
+        _ => ns_name,
+    }
+}
+
+// one namespace at a time through a pidfd, which can't be fooled by pid reuse
+fn enter_namespace_by_pidfd(pid: i32, ns_flags: c_int) -> Result<()> {
     let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
     if pidfd == -1 {
-        println!("pidfd_open failed: {}", pidfd);
-        return Err(anyhow::anyhow!("pidfd_open failed"));
+        let err = std::io::Error::last_os_error();
+        if use_ns_file_fallback(err.raw_os_error()) {
+            debug!("pidfd_open unavailable ({}), using /proc ns files", err);
+            return enter_namespace_by_ns_files(pid, ns_flags);
+        }
+        error!("pidfd_open failed: {}", err);
+        return Err(anyhow::anyhow!("pidfd_open failed: {}", err));
     }
-    let err_no = unsafe { libc::setns(pidfd as i32, ns_flags) };
-    if err_no != 0 {
-        println!("setns failed: {}", err_no);
-        return Err(anyhow::anyhow!("setns failed"));
+    // closed on drop
+    let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as i32) };
+    let mut setns_calls = 0;
+    let mut pidfd_setns_unsupported = false;
+    let res = enter_each_namespace(ns_flags, |ns_flag, _| {
+        setns_calls += 1;
+        if unsafe { libc::setns(pidfd.as_raw_fd(), ns_flag) } != 0 {
+            let err = std::io::Error::last_os_error();
+            // kernels before 5.8 have pidfd_open but can't setns on a pidfd,
+            // the very first setns fails then & nothing was entered
+            pidfd_setns_unsupported = setns_calls == 1 && err.raw_os_error() == Some(libc::EINVAL);
+            return Err(err.into());
+        }
+        Ok(())
+    });
+    if res.is_err() && pidfd_setns_unsupported {
+        debug!("setns on pidfd unsupported, using /proc ns files");
+        return enter_namespace_by_ns_files(pid, ns_flags);
     }
-    let close_res = unsafe { libc::close(pidfd as i32) };
-    if close_res != 0 {
-        println!("close pidfd failed: {}", close_res);
-        return Err(anyhow::anyhow!("close pidfd failed"));
+    res
+}
+
+// setns every requested namespace in turn, the error names the namespace that
+// failed & the ones already entered, which enter_namespace rolls back
+fn enter_each_namespace(
+    ns_flags: c_int,
+    mut setns: impl FnMut(c_int, &'static str) -> Result<()>,
+) -> Result<()> {
+    let mut entered = Vec::new();
+    for (ns_flag, ns_name) in NAMESPACE_FILES {
+        if ns_flags & ns_flag == 0 {
+            continue;
+        }
+        if let Err(e) = setns(ns_flag, ns_name) {
+            if entered.is_empty() {
+                return Err(anyhow::anyhow!("setns {} failed: {}", ns_name, e));
+            }
+            return Err(anyhow::anyhow!(
+                "setns {} failed: {}, already entered: {}",
+                ns_name,
+                e,
+                entered.join(", ")
+            ));
+        }
+        debug!("entered {} namespace", ns_name);
+        entered.push(ns_name);
     }
+    Ok(())
+}
 
+// pidfd_open is missing before linux 5.3 and often blocked by seccomp sandboxes
+fn use_ns_file_fallback(errno: Option<i32>) -> bool {
+    matches!(errno, Some(libc::ENOSYS) | Some(libc::EPERM))
+}
+
+// compare every requested namespace with the target's once entered, so the
+// shell never silently stays in a host namespace
+fn verify_namespaces(pid: i32, ns_flags: c_int) -> Result<()> {
+    let mut missed = Vec::new();
+    for (ns_flag, ns_name) in NAMESPACE_FILES {
+        if ns_flags & ns_flag == 0 {
+            continue;
+        }
+        let self_inode =
+            read_ns_inode(&format!("/proc/self/ns/{}", self_ns_name(ns_flag, ns_name)))?;
+        let target_inode = read_ns_inode(&format!("/proc/{}/ns/{}", pid, ns_name))?;
+        debug!(
+            "{} namespace inode: {}, target: {}",
+            ns_name, self_inode, target_inode
+        );
+        if self_inode != target_inode {
+            missed.push(ns_name);
+        }
+    }
+    if !missed.is_empty() {
+        return Err(anyhow::anyhow!(
+            "failed to enter target namespaces: {}",
+            missed.join(", ")
+        ));
+    }
     Ok(())
 }
+
+// false as well on kernels without time namespaces
+fn has_own_time_namespace(pid: i32) -> bool {
+    let Some(self_inode) = read_ns_inode("/proc/self/ns/time").ok() else {
+        return false;
+    };
+    read_ns_inode(&format!("/proc/{}/ns/time", pid))
+        .is_ok_and(|target_inode| target_inode != self_inode)
+}
+
+// namespaces are identified by the inode of their /proc ns file
+pub fn read_ns_inode(path: &str) -> Result<u64> {
+    let metadata =
+        std::fs::metadata(path).map_err(|e| anyhow::anyhow!("failed to stat {}: {}", path, e))?;
+    Ok(metadata.ino())
+}
+
+fn enter_namespace_by_ns_files(pid: i32, ns_flags: c_int) -> Result<()> {
+    enter_each_namespace(ns_flags, |ns_flag, ns_name| {
+        let ns_path = format!("/proc/{}/ns/{}", pid, ns_name);
+        let ns_file = File::open(&ns_path)
+            .map_err(|e| anyhow::anyhow!("failed to open {}: {}", ns_path, e))?;
+        if unsafe { libc::setns(ns_file.as_raw_fd(), ns_flag) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_or_blocked_pidfd_open_uses_ns_files() {
+        assert!(use_ns_file_fallback(Some(libc::ENOSYS)));
+        assert!(use_ns_file_fallback(Some(libc::EPERM)));
+    }
+
+    #[test]
+    fn other_pidfd_open_errors_are_reported() {
+        for errno in [
+            Some(libc::ESRCH),
+            Some(libc::EINVAL),
+            Some(libc::EMFILE),
+            None,
+        ] {
+            assert!(!use_ns_file_fallback(errno), "{:?}", errno);
+        }
+    }
+
+    // bookkeeping only, setns is replaced so no namespace is actually changed
+    fn saved(entries: &[(c_int, &'static str, u64)]) -> SavedNamespaces {
+        SavedNamespaces {
+            files: entries
+                .iter()
+                .map(|(ns_flag, ns_name, inode)| {
+                    (*ns_flag, *ns_name, File::open("/dev/null").unwrap(), *inode)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn restore_switches_back_changed_namespaces_newest_first() {
+        let saved = saved(&[
+            (libc::CLONE_NEWIPC, "ipc", 1),
+            (libc::CLONE_NEWNET, "net", 2),
+            (libc::CLONE_NEWUTS, "uts", 3),
+        ]);
+        let mut restored = Vec::new();
+        // net is still the original, ipc & uts were switched
+        let failed = saved.restore_with(
+            |ns_flag, _| Some(if ns_flag == libc::CLONE_NEWNET { 2 } else { 99 }),
+            |_, ns_flag| {
+                restored.push(ns_flag);
+                Ok(())
+            },
+        );
+        assert!(failed.is_empty());
+        assert_eq!(restored, [libc::CLONE_NEWUTS, libc::CLONE_NEWIPC]);
+    }
+
+    #[test]
+    fn restore_reports_namespaces_it_cant_switch_back() {
+        let saved = saved(&[
+            (libc::CLONE_NEWNET, "net", 2),
+            (libc::CLONE_NEWPID, "pid", 4),
+        ]);
+        let mut attempts = 0;
+        let failed = saved.restore_with(
+            // an unreadable ns link counts as changed
+            |_, _| None,
+            |_, ns_flag| {
+                attempts += 1;
+                if ns_flag == libc::CLONE_NEWPID {
+                    return Err(anyhow::anyhow!("EINVAL"));
+                }
+                Ok(())
+            },
+        );
+        assert_eq!(attempts, 2);
+        assert_eq!(failed, ["pid"]);
+    }
+
+    #[test]
+    fn restore_without_changes_is_a_no_op() {
+        let saved = SavedNamespaces::open(libc::CLONE_NEWNET | libc::CLONE_NEWPID).unwrap();
+        let ns_names = saved
+            .files
+            .iter()
+            .map(|(_, ns_name, _, _)| *ns_name)
+            .collect::<Vec<_>>();
+        assert_eq!(ns_names, ["net", "pid"]);
+        assert!(saved.restore().is_empty());
+    }
+
+    #[test]
+    fn pid_and_time_namespaces_apply_to_children() {
+        assert_eq!(self_ns_name(libc::CLONE_NEWPID, "pid"), "pid_for_children");
+        assert_eq!(
+            self_ns_name(libc::CLONE_NEWTIME, "time"),
+            "time_for_children"
+        );
+        assert_eq!(self_ns_name(libc::CLONE_NEWNET, "net"), "net");
+    }
+
+    #[test]
+    fn enter_each_namespace_one_at_a_time() {
+        let mut entered = Vec::new();
+        enter_each_namespace(libc::CLONE_NEWNET | libc::CLONE_NEWUTS, |ns_flag, _| {
+            entered.push(ns_flag);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(entered, [libc::CLONE_NEWNET, libc::CLONE_NEWUTS]);
+    }
+
+    #[test]
+    fn enter_each_namespace_names_the_failing_one() {
+        let ns_flags = libc::CLONE_NEWIPC | libc::CLONE_NEWNET | libc::CLONE_NEWPID;
+        let mut attempts = Vec::new();
+        let err = enter_each_namespace(ns_flags, |ns_flag, ns_name| {
+            attempts.push(ns_name);
+            if ns_flag == libc::CLONE_NEWNET {
+                return Err(std::io::Error::from_raw_os_error(libc::EPERM).into());
+            }
+            Ok(())
+        })
+        .unwrap_err();
+        // nothing after the failing namespace is tried
+        assert_eq!(attempts, ["ipc", "net"]);
+        let message = err.to_string();
+        assert!(message.starts_with("setns net failed"), "{}", message);
+        assert!(message.ends_with("already entered: ipc"), "{}", message);
+    }
+}
@@ -0,0 +1,63 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::collections::HashMap;
+
+use crate::events::EventEmitter;
+
+const PULL_TEMPLATE: &str = "{prefix} [{bar:30}] {bytes}/{total_bytes}";
+const EXTRACT_TEMPLATE: &str = "extracting [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec}";
+
+// one download bar per layer, keyed by the layer id the daemon reports
+pub struct PullBars {
+    multi: MultiProgress,
+    bars: HashMap<String, ProgressBar>,
+}
+
+impl PullBars {
+    pub fn new(events: &EventEmitter) -> Self {
+        let multi = MultiProgress::new();
+        if !events.shows_progress() {
+            multi.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        PullBars {
+            multi,
+            bars: HashMap::new(),
+        }
+    }
+
+    // the daemon reports 0 totals for steps without a known size, e.g. verifying
+    pub fn update(&mut self, layer: &str, done: u64, total: u64) {
+        if total == 0 {
+            return;
+        }
+        let bar = self.bars.entry(layer.to_string()).or_insert_with(|| {
+            let bar = self.multi.add(ProgressBar::new(total));
+            bar.set_style(style(PULL_TEMPLATE));
+            bar.set_prefix(layer.to_string());
+            bar
+        });
+        bar.set_length(total);
+        bar.set_position(done);
+    }
+
+    pub fn finish(self) {
+        for bar in self.bars.values() {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+// bar over the bytes of every layer to extract, hidden when progress isn't shown
+pub fn extract_bar(events: &EventEmitter, total: u64) -> ProgressBar {
+    if !events.shows_progress() {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new(total);
+    bar.set_style(style(EXTRACT_TEMPLATE));
+    bar
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ")
+}
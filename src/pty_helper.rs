@@ -0,0 +1,124 @@
+use anyhow::Result;
+use nix::pty::{OpenptyResult, Winsize, openpty};
+use nix::sys::termios::{SetArg, Termios, cfmakeraw, tcgetattr, tcsetattr};
+use signal_hook::{consts::SIGWINCH, iterator::Signals};
+use std::{
+    fs::File,
+    io::{IsTerminal, Read, Write, stdin, stdout},
+    os::fd::{AsRawFd, OwnedFd, RawFd},
+    thread,
+};
+
+use crate::utils;
+
+// pty for the debug shell, slave inherits the host terminal settings & size
+pub fn open_pty() -> Result<OpenptyResult> {
+    let host_termios = host_termios()?;
+    let host_winsize = host_winsize();
+    Ok(openpty(host_winsize.as_ref(), host_termios.as_ref())?)
+}
+
+fn host_winsize() -> Option<Winsize> {
+    if !stdin().is_terminal() {
+        return None;
+    }
+    let mut winsize = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { libc::ioctl(libc::STDIN_FILENO, libc::TIOCGWINSZ, &mut winsize) } != 0 {
+        return None;
+    }
+    Some(winsize)
+}
+
+// resizing the master makes the kernel send SIGWINCH to the shell
+fn apply_host_winsize(master_fd: RawFd) {
+    if let Some(winsize) = host_winsize() {
+        unsafe {
+            libc::ioctl(master_fd, libc::TIOCSWINSZ, &winsize);
+        }
+    }
+}
+
+fn host_termios() -> Result<Option<Termios>> {
+    if !stdin().is_terminal() {
+        return Ok(None);
+    }
+    Ok(Some(tcgetattr(stdin())?))
+}
+
+// make the pty slave our controlling terminal & stdio, call in the child before exec
+pub fn attach_slave(pty: OpenptyResult) -> Result<()> {
+    drop(pty.master);
+    nix::unistd::setsid()?;
+    let slave_fd = pty.slave.as_raw_fd();
+    if unsafe { libc::ioctl(slave_fd, libc::TIOCSCTTY, 0) } != 0 {
+        return Err(anyhow::anyhow!(
+            "failed to set controlling terminal: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    for target_fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(slave_fd, target_fd) } == -1 {
+            return Err(anyhow::anyhow!(
+                "failed to dup pty slave: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    // dropping the slave closes it unless it's one of the stdio fds now
+    if slave_fd <= libc::STDERR_FILENO {
+        std::mem::forget(pty.slave);
+    }
+    Ok(())
+}
+
+// relay host stdio to the pty master until the child exits, returns its wait status
+pub fn relay_until_exit(master: OwnedFd, child_pid: libc::pid_t) -> Result<libc::c_int> {
+    // raw host terminal, the pty slave does the line discipline
+    let saved_termios = host_termios()?;
+    if let Some(saved_termios) = &saved_termios {
+        let mut raw_termios = saved_termios.clone();
+        cfmakeraw(&mut raw_termios);
+        tcsetattr(stdin(), SetArg::TCSANOW, &raw_termios)?;
+    }
+
+    // follow host terminal resizes, left behind when we exit like the stdin relay
+    if saved_termios.is_some() {
+        let master_fd = master.try_clone()?;
+        let mut signals = Signals::new([SIGWINCH])?;
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                apply_host_winsize(master_fd.as_raw_fd());
+            }
+        });
+    }
+
+    let mut master_writer = File::from(master.try_clone()?);
+    let mut master_reader = File::from(master);
+    // blocks on stdin forever, left behind when we exit
+    thread::spawn(move || {
+        let _ = std::io::copy(&mut stdin(), &mut master_writer);
+    });
+    // ends with EIO once every slave fd is closed
+    let output_thread = thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        let mut out = stdout();
+        while let Ok(n) = master_reader.read(&mut buf) {
+            if n == 0 || out.write_all(&buf[..n]).is_err() || out.flush().is_err() {
+                break;
+            }
+        }
+    });
+
+    let status = utils::wait_child(child_pid);
+    let _ = output_thread.join();
+
+    if let Some(saved_termios) = &saved_termios {
+        tcsetattr(stdin(), SetArg::TCSANOW, saved_termios)?;
+    }
+    Ok(status)
+}
@@ -0,0 +1,2091 @@
+use anyhow::{Context, Result};
+use std::env::set_current_dir;
+use std::ffi::CString;
+use std::fs::Permissions;
+use std::fs::create_dir_all;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf, absolute};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::runtime::Runtime;
+use tracing::{debug, error, info, warn};
+
+use crate::auth_helper::{self, RegistryAuth};
+use crate::container_runtime::{self, ContainerRuntime, RuntimeKind};
+use crate::docker_helper::{ContainerInfo, ImageConfig, LayerTarget, PlatformSpec, PullPolicy};
+use crate::events::{Event, EventEmitter, OutputFormat};
+use crate::lock_helper::{self, WorkdirLock};
+use crate::mount_helper::{HostMount, MountGuard};
+use crate::namespace_helper::NetworkMode;
+use crate::signal_helper::MountCleanup;
+use crate::timing::PhaseTimings;
+use crate::watch_helper::ContainerWatcher;
+use crate::{
+    busybox, cache_helper, diff_helper, distro_helper, docker_helper, mount_helper,
+    namespace_helper, pty_helper, signal_helper, utils,
+};
+
+/// How rootfs cache entries are named.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum CacheKey {
+    /// image name & tag
+    #[default]
+    Tag,
+    /// image name, tag & config digest, a moved tag invalidates the cache
+    Digest,
+}
+
+/// Options for a [`DebugSession`], defaults match the CLI.
+#[derive(Debug, Clone)]
+pub struct DebugSessionBuilder {
+    container_id: Option<String>,
+    runtime: RuntimeKind,
+    docker_host: Option<String>,
+    docker_timeout: Duration,
+    image: String,
+    auto_image: bool,
+    stacked_images: Vec<String>,
+    image_file: Option<PathBuf>,
+    oci_layout: Option<PathBuf>,
+    platform: Option<String>,
+    strict_arch: bool,
+    pull_policy: PullPolicy,
+    pull_retries: u32,
+    registry_auth: Option<RegistryAuth>,
+    verify_layers: bool,
+    layer_cache: bool,
+    jobs: usize,
+    workdir: PathBuf,
+    wait_lock: bool,
+    cache: bool,
+    cache_dir: PathBuf,
+    cache_key: CacheKey,
+    cache_ttl: Option<Duration>,
+    cache_max_size: Option<u64>,
+    verify_cache: bool,
+    namespaces: Vec<String>,
+    fs_only: bool,
+    network: NetworkMode,
+    join_cgroup: bool,
+    wait: Option<Duration>,
+    exit_with_container: bool,
+    container_mount_path: String,
+    readonly_container: bool,
+    mounts: Vec<HostMount>,
+    mount_volumes: bool,
+    overlay_opts: Vec<String>,
+    no_overlay: bool,
+    unmount_on_exit: bool,
+    keep: bool,
+    private_mounts: bool,
+    save_diff: Option<PathBuf>,
+    shell: String,
+    init_file: String,
+    rc_file: Option<PathBuf>,
+    rc_append: bool,
+    command: Vec<String>,
+    pty: bool,
+    output: OutputFormat,
+    quiet: bool,
+    mount_proc: bool,
+    mount_sys: bool,
+    mount_dev: bool,
+    copy_resolv_conf: bool,
+    with_busybox: bool,
+    busybox_force: bool,
+    apply_image_env: bool,
+    apply_image_user: bool,
+    cd_container: bool,
+    inherit_env: bool,
+    inherit_env_all: bool,
+}
+
+impl Default for DebugSessionBuilder {
+    fn default() -> Self {
+        DebugSessionBuilder {
+            container_id: None,
+            runtime: RuntimeKind::Docker,
+            docker_host: None,
+            docker_timeout: Duration::from_secs(30),
+            image: String::from("debian:12"),
+            auto_image: false,
+            stacked_images: Vec::new(),
+            image_file: None,
+            oci_layout: None,
+            platform: None,
+            strict_arch: false,
+            pull_policy: PullPolicy::Missing,
+            pull_retries: 3,
+            registry_auth: None,
+            verify_layers: true,
+            layer_cache: false,
+            jobs: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            workdir: PathBuf::from("/var/lib/rustnsoverlay/work"),
+            wait_lock: false,
+            cache: true,
+            cache_dir: PathBuf::from("/var/cache/rustnsoverlay"),
+            cache_key: CacheKey::Tag,
+            cache_ttl: None,
+            cache_max_size: None,
+            verify_cache: true,
+            namespaces: vec![String::from("all")],
+            fs_only: false,
+            network: NetworkMode::Container,
+            join_cgroup: false,
+            wait: None,
+            exit_with_container: true,
+            container_mount_path: String::from("/mnt/container"),
+            readonly_container: true,
+            mounts: Vec::new(),
+            mount_volumes: true,
+            overlay_opts: Vec::new(),
+            no_overlay: false,
+            unmount_on_exit: true,
+            keep: false,
+            private_mounts: true,
+            save_diff: None,
+            shell: String::from("/usr/bin/bash"),
+            init_file: String::from("init.sh"),
+            rc_file: None,
+            rc_append: false,
+            command: Vec::new(),
+            pty: true,
+            output: OutputFormat::Human,
+            quiet: false,
+            mount_proc: true,
+            mount_sys: true,
+            mount_dev: true,
+            copy_resolv_conf: true,
+            with_busybox: false,
+            busybox_force: false,
+            apply_image_env: true,
+            apply_image_user: false,
+            cd_container: false,
+            inherit_env: false,
+            inherit_env_all: false,
+        }
+    }
+}
+
+impl DebugSessionBuilder {
+    /// Target container ID or name, required.
+    pub fn container_id(mut self, container_id: impl Into<String>) -> Self {
+        self.container_id = Some(container_id.into());
+        self
+    }
+
+    /// Container engine of the target container, see [`RuntimeKind`].
+    pub fn runtime(mut self, runtime: RuntimeKind) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// Docker daemon endpoint (unix://, tcp:// or http://), none uses the
+    /// runtime's default.
+    pub fn docker_host(mut self, docker_host: Option<String>) -> Self {
+        self.docker_host = docker_host;
+        self
+    }
+
+    /// Limit on every docker API call, for pulls the wait for progress.
+    pub fn docker_timeout(mut self, docker_timeout: Duration) -> Self {
+        self.docker_timeout = docker_timeout;
+        self
+    }
+
+    /// Image to act as rootfs.
+    pub fn image(mut self, image: impl Into<String>) -> Self {
+        self.image = image.into();
+        self
+    }
+
+    /// Replace `image` with the official image of the container's distro, as
+    /// read from its os-release. `image` stays when the distro is unknown.
+    pub fn auto_image(mut self, auto_image: bool) -> Self {
+        self.auto_image = auto_image;
+        self
+    }
+
+    /// Images stacked on top of `image`, later ones take precedence. Each image
+    /// is extracted into its own overlay lower dir & the session's changes are
+    /// not cached.
+    pub fn stack_images(mut self, stacked_images: Vec<String>) -> Self {
+        self.stacked_images = stacked_images;
+        self
+    }
+
+    /// `docker save` tar to act as rootfs instead of pulling `image`, `image`
+    /// picks the image of a tar holding several.
+    pub fn image_file(mut self, image_file: Option<PathBuf>) -> Self {
+        self.image_file = image_file;
+        self
+    }
+
+    /// OCI image layout directory to act as rootfs instead of pulling `image`,
+    /// e.g. from `skopeo copy docker://<image> oci:<dir>`.
+    pub fn oci_layout(mut self, oci_layout: Option<PathBuf>) -> Self {
+        self.oci_layout = oci_layout;
+        self
+    }
+
+    /// Image platform as os/arch[/variant], none selects the host platform.
+    pub fn platform(mut self, platform: Option<String>) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// Fail instead of warning when an image isn't built for the host's
+    /// architecture.
+    pub fn strict_arch(mut self, strict_arch: bool) -> Self {
+        self.strict_arch = strict_arch;
+        self
+    }
+
+    /// Force repull image, short for [`PullPolicy::Always`].
+    pub fn pull(mut self, pull: bool) -> Self {
+        if pull {
+            self.pull_policy = PullPolicy::Always;
+        }
+        self
+    }
+
+    /// When to pull the debug image.
+    pub fn pull_policy(mut self, pull_policy: PullPolicy) -> Self {
+        self.pull_policy = pull_policy;
+        self
+    }
+
+    /// Retries for transient pull failures, with exponential backoff.
+    pub fn pull_retries(mut self, pull_retries: u32) -> Self {
+        self.pull_retries = pull_retries;
+        self
+    }
+
+    /// Credentials for every pull, none looks them up per registry in the
+    /// docker config (`~/.docker/config.json`).
+    pub fn registry_auth(mut self, registry_auth: Option<RegistryAuth>) -> Self {
+        self.registry_auth = registry_auth;
+        self
+    }
+
+    /// Check layer blobs against their manifest digests.
+    pub fn verify_layers(mut self, verify_layers: bool) -> Self {
+        self.verify_layers = verify_layers;
+        self
+    }
+
+    /// Extract each layer once into `cache_dir/layers/<digest>` & stack them
+    /// as overlay lower dirs, images sharing layers reuse them. Replaces the
+    /// rootfs cache.
+    pub fn layer_cache(mut self, layer_cache: bool) -> Self {
+        self.layer_cache = layer_cache;
+        self
+    }
+
+    /// Max concurrent layer decompression jobs.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Directory holding the rootfs & mounts, wiped on start.
+    pub fn workdir(mut self, workdir: impl Into<PathBuf>) -> Self {
+        self.workdir = workdir.into();
+        self
+    }
+
+    /// Wait for another session using the workdir to end instead of failing.
+    pub fn wait_lock(mut self, wait_lock: bool) -> Self {
+        self.wait_lock = wait_lock;
+        self
+    }
+
+    /// Reuse & save the rootfs cache.
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Directory holding the rootfs cache tars.
+    pub fn cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = cache_dir.into();
+        self
+    }
+
+    /// How rootfs cache entries are named, see [`CacheKey`].
+    pub fn cache_key(mut self, cache_key: CacheKey) -> Self {
+        self.cache_key = cache_key;
+        self
+    }
+
+    /// Ignore cache entries older than this, none or zero never expires.
+    pub fn cache_ttl(mut self, cache_ttl: Option<Duration>) -> Self {
+        self.cache_ttl = cache_ttl;
+        self
+    }
+
+    /// Max total cache size in bytes, least recently used entries are removed.
+    pub fn cache_max_size(mut self, cache_max_size: Option<u64>) -> Self {
+        self.cache_max_size = cache_max_size;
+        self
+    }
+
+    /// Verify the cache checksum before extracting it.
+    pub fn verify_cache(mut self, verify_cache: bool) -> Self {
+        self.verify_cache = verify_cache;
+        self
+    }
+
+    /// Container namespaces to enter: all, cgroup, ipc, net, pid, time, uts.
+    /// `all` includes time only if the container has a time namespace of its own.
+    pub fn namespaces(mut self, namespaces: Vec<String>) -> Self {
+        self.namespaces = namespaces;
+        self
+    }
+
+    /// Stay in the host network namespace with [`NetworkMode::Host`], so the
+    /// debug tools reach the host network while the other namespaces are the
+    /// container's.
+    pub fn network(mut self, network: NetworkMode) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Only mount the container fs & stay in the host namespaces, works on
+    /// stopped containers too.
+    pub fn fs_only(mut self, fs_only: bool) -> Self {
+        self.fs_only = fs_only;
+        self
+    }
+
+    /// Move the session into the container's cgroup so its limits & accounting
+    /// apply to the debug shell, needs cgroup v2.
+    pub fn join_cgroup(mut self, join_cgroup: bool) -> Self {
+        self.join_cgroup = join_cgroup;
+        self
+    }
+
+    /// Wait up to this long for a created or restarting container to run.
+    pub fn wait(mut self, wait: Option<Duration>) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    /// End the session once the container stops, ignored with `fs_only`.
+    pub fn exit_with_container(mut self, exit_with_container: bool) -> Self {
+        self.exit_with_container = exit_with_container;
+        self
+    }
+
+    /// Container fs mount path inside the debug rootfs.
+    pub fn container_mount_path(mut self, container_mount_path: impl Into<String>) -> Self {
+        self.container_mount_path = container_mount_path.into();
+        self
+    }
+
+    /// Mount the container fs readonly in the debug rootfs.
+    pub fn readonly_container(mut self, readonly_container: bool) -> Self {
+        self.readonly_container = readonly_container;
+        self
+    }
+
+    /// Host paths to bind mount into the debug rootfs.
+    pub fn mounts(mut self, mounts: Vec<HostMount>) -> Self {
+        self.mounts = mounts;
+        self
+    }
+
+    /// Bind the container's volumes & bind mounts over the container mount,
+    /// they aren't part of its layered fs.
+    pub fn mount_volumes(mut self, mount_volumes: bool) -> Self {
+        self.mount_volumes = mount_volumes;
+        self
+    }
+
+    /// Extra rootfs overlay mount options as `key=val` or flags like
+    /// `userxattr`, these override the detected defaults.
+    pub fn overlay_opts(mut self, overlay_opts: Vec<String>) -> Self {
+        self.overlay_opts = overlay_opts;
+        self
+    }
+
+    /// Bind mount the extracted rootfs instead of building an overlay, for
+    /// hosts where overlay mounts are unavailable. Changes go straight into
+    /// the rootfs, a single image & a driver reporting `MergedDir` are needed.
+    pub fn no_overlay(mut self, no_overlay: bool) -> Self {
+        self.no_overlay = no_overlay;
+        self
+    }
+
+    /// Unmount the merged rootfs when the session ends.
+    pub fn unmount_on_exit(mut self, unmount_on_exit: bool) -> Self {
+        self.unmount_on_exit = unmount_on_exit;
+        self
+    }
+
+    /// Leave every mount & the workdir in place when the session ends, for
+    /// poking around with other tools. `clean` tears them down later.
+    pub fn keep(mut self, keep: bool) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Make the session's mounts in a private mount namespace, so they vanish
+    /// with the session even if cleanup is skipped. Ignored without
+    /// `unmount_on_exit`, whose mounts must stay visible on the host.
+    pub fn private_mounts(mut self, private_mounts: bool) -> Self {
+        self.private_mounts = private_mounts;
+        self
+    }
+
+    /// Archive the rootfs changes made during the session here on exit, as a
+    /// layer diff with whiteouts. Writes to the container mount aren't included.
+    pub fn save_diff(mut self, save_diff: Option<PathBuf>) -> Self {
+        self.save_diff = save_diff;
+        self
+    }
+
+    /// Shell to start inside the rootfs.
+    pub fn shell(mut self, shell: impl Into<String>) -> Self {
+        self.shell = shell.into();
+        self
+    }
+
+    /// Init script to run before the shell, relative to the rootfs root.
+    pub fn init_file(mut self, init_file: impl Into<String>) -> Self {
+        self.init_file = init_file.into();
+        self
+    }
+
+    /// Startup file for the interactive shell in place of the image's
+    /// `~/.bashrc`.
+    pub fn rc_file(mut self, rc_file: Option<PathBuf>) -> Self {
+        self.rc_file = rc_file;
+        self
+    }
+
+    /// Source the image's own startup files before `rc_file`.
+    pub fn rc_append(mut self, rc_append: bool) -> Self {
+        self.rc_append = rc_append;
+        self
+    }
+
+    /// Command to run instead of an interactive shell.
+    pub fn command(mut self, command: Vec<String>) -> Self {
+        self.command = command;
+        self
+    }
+
+    /// Run the shell on its own pty instead of the inherited terminal.
+    pub fn pty(mut self, pty: bool) -> Self {
+        self.pty = pty;
+        self
+    }
+
+    /// Emit json events on stdout instead of leaving progress to the logs.
+    pub fn output(mut self, output: OutputFormat) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Hide progress bars, logging is left to the caller's subscriber.
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Mount a fresh proc on /proc inside the rootfs.
+    pub fn mount_proc(mut self, mount_proc: bool) -> Self {
+        self.mount_proc = mount_proc;
+        self
+    }
+
+    /// Mount sysfs on /sys inside the rootfs.
+    pub fn mount_sys(mut self, mount_sys: bool) -> Self {
+        self.mount_sys = mount_sys;
+        self
+    }
+
+    /// Bind the host /dev on /dev inside the rootfs.
+    pub fn mount_dev(mut self, mount_dev: bool) -> Self {
+        self.mount_dev = mount_dev;
+        self
+    }
+
+    /// Copy the container's resolv.conf (or the host's) into the rootfs so DNS
+    /// matches the container's view.
+    pub fn copy_resolv_conf(mut self, copy_resolv_conf: bool) -> Self {
+        self.copy_resolv_conf = copy_resolv_conf;
+        self
+    }
+
+    /// Install the embedded busybox & its applets into the rootfs /bin, needs
+    /// the `busybox` feature.
+    pub fn with_busybox(mut self, with_busybox: bool) -> Self {
+        self.with_busybox = with_busybox;
+        self
+    }
+
+    /// Let busybox applets replace the image's own binaries.
+    pub fn busybox_force(mut self, busybox_force: bool) -> Self {
+        self.busybox_force = busybox_force;
+        self
+    }
+
+    /// Apply the image's `Env` & `WorkingDir` to the shell.
+    pub fn apply_image_env(mut self, apply_image_env: bool) -> Self {
+        self.apply_image_env = apply_image_env;
+        self
+    }
+
+    /// Run the shell as the image's `User`, needs setpriv in the rootfs.
+    pub fn apply_image_user(mut self, apply_image_user: bool) -> Self {
+        self.apply_image_user = apply_image_user;
+        self
+    }
+
+    /// Start the shell in the working directory of the container's main
+    /// process under the container mount, its root when that is missing.
+    pub fn cd_container(mut self, cd_container: bool) -> Self {
+        self.cd_container = cd_container;
+        self
+    }
+
+    /// Export the environment of the container's main process into the shell,
+    /// secrets included. The variables the shell sets itself are left out.
+    pub fn inherit_env(mut self, inherit_env: bool) -> Self {
+        self.inherit_env = inherit_env;
+        self
+    }
+
+    /// Also inherit `PATH`, `HOME` & the other variables the shell sets itself,
+    /// see [`Self::inherit_env`].
+    pub fn inherit_env_all(mut self, inherit_env_all: bool) -> Self {
+        self.inherit_env_all = inherit_env_all;
+        self
+    }
+
+    /// Resolve the container & print every action [`Self::build`] and
+    /// [`DebugSession::run`] would take, without touching the filesystem or
+    /// namespaces.
+    pub fn dry_run(mut self) -> Result<()> {
+        let (rt, docker) = self.connect()?;
+        let container_info = self.container_info(&rt, &docker)?;
+        self.resolve_auto_image(&container_info);
+        let image_digests = self.image_digests(&rt, &docker)?;
+        let plan = self.plan(&image_digests)?;
+        self.ns_flags(container_info.pid as i32)?;
+
+        if container_info.running {
+            println!(
+                "container: {} (pid {})",
+                container_info.id, container_info.pid
+            );
+        } else {
+            println!("container: {} (not running)", container_info.id);
+        }
+        println!("lock {}", lock_helper::lock_path(&plan.work_dir).display());
+        println!("unmount everything under {}", plan.work_dir.display());
+        println!("remove & recreate {}", plan.work_dir.display());
+        for image_plan in &plan.images {
+            if self.cache && !self.layer_cache && self.pull_policy != PullPolicy::Always {
+                println!("reuse cache {} if valid", image_plan.cache_path.display());
+            }
+            let destination = if self.layer_cache {
+                format!(
+                    "{}/<digest> per layer unless already there",
+                    self.cache_dir.join("layers").display()
+                )
+            } else {
+                image_plan.rootfs_dir.display().to_string()
+            };
+            match (&self.image_file, &self.oci_layout) {
+                (Some(image_file), _) => println!(
+                    "extract image file {} into {}",
+                    image_file.display(),
+                    destination
+                ),
+                (None, Some(oci_layout)) => println!(
+                    "extract oci layout {} into {}",
+                    oci_layout.display(),
+                    destination
+                ),
+                (None, None) => println!(
+                    "{} image {} & extract it into {}",
+                    match self.pull_policy {
+                        PullPolicy::Always => "pull",
+                        PullPolicy::Missing => "pull if missing",
+                        PullPolicy::Never => "use local",
+                    },
+                    image_plan.image,
+                    destination
+                ),
+            }
+            if self.cache && !self.layer_cache && plan.cache_path.is_none() {
+                println!("save cache to {}", image_plan.cache_path.display());
+            }
+        }
+        if self.private_mounts && self.unmounts_merged_dir() {
+            println!("unshare a private mount namespace for the session mounts");
+        }
+        if self.no_overlay {
+            println!(
+                "bind mount {} on {}",
+                plan.rootfs_base_dir.display(),
+                plan.mergedfs_dir.display()
+            );
+        } else if self.layer_cache {
+            println!(
+                "mount overlay on {} with the cached layers as lowerdir & upperdir={}",
+                plan.mergedfs_dir.display(),
+                plan.rootfs_base_dir.display()
+            );
+        } else {
+            println!(
+                "mount overlay on {} with {}",
+                plan.mergedfs_dir.display(),
+                plan.mount_opt
+            );
+        }
+        match &container_info.merged_dir {
+            Some(merged_dir) => println!(
+                "bind mount {} on {}{}",
+                merged_dir,
+                plan.container_mount_path.display(),
+                if self.readonly_container {
+                    " readonly"
+                } else {
+                    ""
+                }
+            ),
+            None => println!(
+                "mount readonly overlay on {} with {}",
+                plan.container_mount_path.display(),
+                container_layers_mount_opt(&container_info)
+            ),
+        }
+        for host_mount in &self.mounts {
+            println!(
+                "bind mount {} on {}{}",
+                host_mount.source.display(),
+                plan.mergedfs_dir
+                    .join(host_mount.dest.trim_start_matches('/'))
+                    .display(),
+                if host_mount.readonly { " readonly" } else { "" }
+            );
+        }
+        for volume in self.volume_mounts(&container_info) {
+            println!(
+                "bind mount volume {} on {}{}",
+                volume.source.display(),
+                plan.container_mount_path
+                    .join(volume.dest.trim_start_matches('/'))
+                    .display(),
+                if volume.readonly { " readonly" } else { "" }
+            );
+        }
+        if self.with_busybox {
+            println!(
+                "install busybox into {}",
+                plan.mergedfs_dir.join("bin").display()
+            );
+        }
+        if self.copy_resolv_conf {
+            println!(
+                "copy resolv.conf into {}",
+                plan.mergedfs_dir.join("etc").display()
+            );
+        }
+        println!("write {}", plan.mergedfs_dir.join("init.sh").display());
+        if self.rc_script()?.is_some() {
+            println!("write {}", plan.mergedfs_dir.join(RC_FILE).display());
+        }
+        if self.fs_only {
+            println!("stay in the host namespaces");
+        } else {
+            if self.join_cgroup {
+                println!(
+                    "join cgroup {}",
+                    container_info.cgroup.as_deref().unwrap_or("-")
+                );
+            }
+            println!(
+                "enter {} namespaces of pid {}",
+                self.namespaces.join(","),
+                container_info.pid
+            );
+            if self.network == NetworkMode::Host {
+                println!("stay in the host network namespace");
+            }
+        }
+        println!("unshare mount namespace");
+        for (enabled, mount) in [
+            (self.mount_dev, "bind /dev on dev"),
+            (self.mount_proc, "mount proc on proc"),
+            (self.mount_sys, "mount sysfs on sys"),
+        ] {
+            if enabled {
+                println!("{}", mount);
+            }
+        }
+        println!("pivot_root into {}", plan.mergedfs_dir.display());
+        if self.inherit_env {
+            println!(
+                "inherit environment of pid {}{}",
+                container_info.pid,
+                if self.inherit_env_all {
+                    ""
+                } else {
+                    " except the shell's own variables"
+                }
+            );
+        }
+        if self.cd_container {
+            println!(
+                "cd {}",
+                Path::new(&self.container_mount_path)
+                    .join(
+                        container_info
+                            .working_dir
+                            .as_deref()
+                            .unwrap_or_default()
+                            .trim_start_matches('/')
+                    )
+                    .display()
+            );
+        }
+        if self.command.is_empty() {
+            println!("exec {}", self.shell);
+        } else {
+            println!("exec {}", self.command.join(" "));
+        }
+        if let Some(diff_path) = &self.save_diff {
+            println!(
+                "archive changes under {} to {}",
+                plan.rootfs_base_dir.display(),
+                diff_path.display()
+            );
+        }
+        if self.cache
+            && let Some(cache_path) = &plan.cache_path
+        {
+            println!("save cache to {}", cache_path.display());
+        }
+        if self.keep {
+            println!("keep every mount, `clean` tears them down");
+            return Ok(());
+        }
+        for host_mount in self.mounts.iter().rev() {
+            println!(
+                "unmount {}",
+                plan.mergedfs_dir
+                    .join(host_mount.dest.trim_start_matches('/'))
+                    .display()
+            );
+        }
+        for volume in self.volume_mounts(&container_info).iter().rev() {
+            println!(
+                "unmount {}",
+                plan.container_mount_path
+                    .join(volume.dest.trim_start_matches('/'))
+                    .display()
+            );
+        }
+        println!("unmount {}", plan.container_mount_path.display());
+        if self.unmount_on_exit {
+            println!("unmount {}", plan.mergedfs_dir.display());
+        }
+        Ok(())
+    }
+
+    // keep overrides unmount_on_exit
+    fn unmounts_merged_dir(&self) -> bool {
+        self.unmount_on_exit && !self.keep
+    }
+
+    /// Resolve the target container without preparing anything.
+    pub fn inspect(&self) -> Result<ContainerInfo> {
+        let (rt, docker) = self.connect()?;
+        self.container_info(&rt, &docker)
+    }
+
+    fn connect(&self) -> Result<(Runtime, impl ContainerRuntime)> {
+        let rt = Runtime::new()?;
+        let docker = container_runtime::connect(
+            self.runtime,
+            self.docker_host.as_deref(),
+            self.docker_timeout,
+        )?;
+        rt.block_on(docker.ping())?;
+        Ok((rt, docker))
+    }
+
+    fn container_info(
+        &self,
+        rt: &Runtime,
+        docker: &impl ContainerRuntime,
+    ) -> Result<ContainerInfo> {
+        let container_id = self
+            .container_id
+            .as_deref()
+            .context("container id is required")?;
+        if let Some(wait) = self.wait
+            && !self.fs_only
+        {
+            rt.block_on(docker.wait_running(container_id, wait))?;
+        }
+        rt.block_on(docker.get_container_info(container_id, !self.fs_only))
+    }
+
+    // swap in the image matching the container's distro, see auto_image
+    fn resolve_auto_image(&mut self, container_info: &ContainerInfo) {
+        if !self.auto_image || self.local_image().is_some() {
+            return;
+        }
+        let Some(os_release) = distro_helper::read_os_release(container_info) else {
+            info!(
+                "no os-release found in the container, using image {}",
+                self.image
+            );
+            return;
+        };
+        match distro_helper::debug_image_for(&os_release) {
+            Some(image) => {
+                info!(
+                    "container runs {}, auto selected image {}",
+                    os_release, image
+                );
+                self.image = image;
+            }
+            None => info!(
+                "no known image for container distro {}, using image {}",
+                os_release, self.image
+            ),
+        }
+    }
+
+    // base image first, then the stacked ones from the bottom up
+    fn images(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.image.as_str()).chain(self.stacked_images.iter().map(String::as_str))
+    }
+
+    // rc file handed to the shell, read up front so a bad path fails before
+    // anything is mounted
+    fn rc_script(&self) -> Result<Option<String>> {
+        let Some(rc_file) = &self.rc_file else {
+            return Ok(None);
+        };
+        let content = fs::read_to_string(rc_file)
+            .context(format!("failed to read rc file {}", rc_file.display()))?;
+        if !self.rc_append {
+            return Ok(Some(content));
+        }
+        Ok(Some(format!("{}\n{}", RC_PRELUDE, content)))
+    }
+
+    // binaries of a foreign architecture fail with exec format errors once in
+    // the shell, so say so before anything is mounted
+    fn check_arch(&self, image: &str, image_config: &ImageConfig) -> Result<()> {
+        let host_arch = PlatformSpec::host().arch;
+        let Some(arch) = &image_config.architecture else {
+            return Ok(());
+        };
+        if *arch == host_arch {
+            return Ok(());
+        }
+        let message = format!(
+            "image {} is built for {} but the host is {}, its binaries won't run without emulation, pick a matching image or --platform linux/{}",
+            image, arch, host_arch, host_arch
+        );
+        if self.strict_arch {
+            return Err(anyhow::anyhow!(message));
+        }
+        warn!("{}", message);
+        Ok(())
+    }
+
+    // the selected namespaces, minus net for a host network session
+    fn ns_flags(&self, pid: i32) -> Result<libc::c_int> {
+        let ns_flags = namespace_helper::ns_flags_from_names(&self.namespaces, pid)?;
+        if self.network == NetworkMode::Host {
+            return Ok(ns_flags & !libc::CLONE_NEWNET);
+        }
+        Ok(ns_flags)
+    }
+
+    // the container's volumes to bind under the container mount, readonly
+    // when the volume or the container mount is
+    fn volume_mounts(&self, container_info: &ContainerInfo) -> Vec<HostMount> {
+        if !self.mount_volumes {
+            return Vec::new();
+        }
+        container_info
+            .volumes
+            .iter()
+            .map(|volume| HostMount {
+                readonly: volume.readonly || self.readonly_container,
+                ..volume.clone()
+            })
+            .collect()
+    }
+
+    // image file or oci layout used in place of the pulled image
+    fn local_image(&self) -> Option<&Path> {
+        self.image_file.as_deref().or(self.oci_layout.as_deref())
+    }
+
+    // config digest of every image for the digest cache key, none falls back
+    // to the tag
+    fn image_digests(
+        &self,
+        rt: &Runtime,
+        docker: &impl ContainerRuntime,
+    ) -> Result<Vec<Option<String>>> {
+        self.images()
+            .map(|image| {
+                // the tar's name says nothing of the entry image selects or
+                // of a tar replaced under the same name
+                if let Some(image_file) = &self.image_file {
+                    return docker_helper::image_tar_config_digest(image_file, image).map(Some);
+                }
+                if self.cache_key == CacheKey::Tag || self.local_image().is_some() {
+                    return Ok(None);
+                }
+                let digest = rt.block_on(docker.get_image_digest(image))?;
+                if digest.is_none() {
+                    warn!(
+                        "image {} is not pulled yet, falling back to tag cache key",
+                        image
+                    );
+                }
+                Ok(digest)
+            })
+            .collect()
+    }
+
+    fn plan(&self, image_digests: &[Option<String>]) -> Result<SessionPlan> {
+        if self.image_file.is_some() && self.oci_layout.is_some() {
+            return Err(anyhow::anyhow!(
+                "an image file & an oci layout are mutually exclusive"
+            ));
+        }
+        if self.local_image().is_some() && !self.stacked_images.is_empty() {
+            return Err(anyhow::anyhow!(
+                "an image file or oci layout can't be stacked with other images"
+            ));
+        }
+        if self.no_overlay && (!self.stacked_images.is_empty() || self.layer_cache) {
+            return Err(anyhow::anyhow!(
+                "stacked images & the layer cache need overlay, drop --no-overlay"
+            ));
+        }
+        let work_dir = absolute(&self.workdir)?;
+        let overlay_lower_dir = work_dir.join("tmp_lower");
+        let overlay_work_dir = work_dir.join("tmp_work");
+        let rootfs_base_dir = work_dir.join("rootfs");
+        let mergedfs_dir = work_dir.join("mergedfs");
+        let image_extract_dir = work_dir.join("tmp_extract");
+        let stacked = !self.stacked_images.is_empty();
+        let platform = self
+            .platform
+            .as_deref()
+            .map(PlatformSpec::parse)
+            .transpose()?;
+
+        // a single image is extracted into the upper dir so the session's
+        // changes are cached with it, stacked images each get a lower dir
+        let images = self
+            .images()
+            .zip(image_digests)
+            .enumerate()
+            .map(|(index, (image, image_digest))| ImagePlan {
+                image: image.to_string(),
+                rootfs_dir: if stacked {
+                    work_dir.join("lower").join(index.to_string())
+                } else {
+                    rootfs_base_dir.clone()
+                },
+                extract_dir: if stacked {
+                    image_extract_dir.join(index.to_string())
+                } else {
+                    image_extract_dir.clone()
+                },
+                cache_path: self.cache_dir.join(cache_helper::image_cache_filename(
+                    image,
+                    self.local_image(),
+                    image_digest.as_deref(),
+                    platform.as_ref(),
+                )),
+            })
+            .collect::<Vec<_>>();
+
+        // cached layers are only known once the images are prepared, build
+        // redoes the mount options then
+        let lower_dirs = if stacked {
+            images
+                .iter()
+                .map(|image_plan| image_plan.rootfs_dir.clone())
+                .collect::<Vec<_>>()
+        } else {
+            vec![overlay_lower_dir.clone()]
+        };
+        let mount_opt = self.overlay_mount_opt(&lower_dirs, &rootfs_base_dir, &overlay_work_dir)?;
+        Ok(SessionPlan {
+            container_mount_path: mergedfs_dir
+                .join(self.container_mount_path.trim_start_matches("/")),
+            cache_path: if stacked || self.layer_cache {
+                None
+            } else {
+                Some(images[0].cache_path.clone())
+            },
+            images,
+            work_dir,
+            image_extract_dir,
+            overlay_lower_dir,
+            overlay_work_dir,
+            rootfs_base_dir,
+            mergedfs_dir,
+            mount_opt,
+        })
+    }
+
+    // lower_dirs from the bottom up, overlay lists them from the top down with
+    // the rightmost one having the lowest priority
+    fn overlay_mount_opt(
+        &self,
+        lower_dirs: &[PathBuf],
+        upper_dir: &Path,
+        work_dir: &Path,
+    ) -> Result<String> {
+        let lower_dirs = lower_dirs
+            .iter()
+            .rev()
+            .map(|dir| dir.display().to_string())
+            .collect::<Vec<_>>()
+            .join(":");
+        let mount_opt = mount_helper::with_overlay_opts(
+            &format!(
+                "lowerdir={},upperdir={},workdir={}",
+                lower_dirs,
+                upper_dir.display(),
+                work_dir.display(),
+            ),
+            &mount_helper::default_overlay_opts(),
+            &self.overlay_opts,
+        );
+        mount_helper::check_mount_data_len(&mount_opt)?;
+        Ok(mount_opt)
+    }
+
+    // explicit credentials apply to every image, otherwise `docker login`'s
+    // for the image's registry
+    fn use_registry_auth(&self, docker: &mut impl ContainerRuntime, image: &str) -> Result<()> {
+        let host = auth_helper::registry_host(image);
+        let auth = match &self.registry_auth {
+            Some(auth) => Some(auth.clone()),
+            None => auth_helper::docker_config_auth(host)?,
+        };
+        if auth.is_some() {
+            info!("using registry credentials for {}", host);
+        }
+        docker.set_registry_auth(auth.as_ref(), host)
+    }
+
+    // extract an image into its rootfs dir, reusing the cache when valid. with
+    // the layer cache the layers are extracted into their own dirs instead,
+    // which are returned from the bottom up
+    fn prepare_image(
+        &self,
+        rt: &Runtime,
+        docker: &impl ContainerRuntime,
+        image_plan: &ImagePlan,
+        platform: &PlatformSpec,
+        events: &EventEmitter,
+        timings: &PhaseTimings,
+    ) -> Result<(ImageConfig, Vec<PathBuf>)> {
+        let cache_path = &image_plan.cache_path;
+        // a fresh pull must not be hidden behind the cache of the old image
+        if self.cache
+            && !self.layer_cache
+            && self.pull_policy != PullPolicy::Always
+            && cache_path.exists()
+        {
+            if utils::is_expired(cache_path, self.cache_ttl.unwrap_or_default())? {
+                info!("skipping expired cache: {}", cache_path.display());
+            } else if self.verify_cache && !cache_helper::verify_checksum(cache_path)? {
+                info!("skipping cache with bad checksum: {}", cache_path.display());
+            } else {
+                info!("found cache: {}", cache_path.display());
+                let mut f = File::open(cache_path)?;
+                timings.time("cache extract", || {
+                    utils::extract_archive(&mut f, &image_plan.rootfs_dir)
+                })?;
+                return Ok((cache_helper::read_image_config(cache_path)?, Vec::new()));
+            }
+        }
+
+        let layers_dir = self.cache_dir.join("layers");
+        let target = if self.layer_cache {
+            LayerTarget::LayerCache(&layers_dir)
+        } else {
+            LayerTarget::Rootfs(&image_plan.rootfs_dir)
+        };
+        let (image_config, layer_dirs) = if let Some(oci_layout) = &self.oci_layout {
+            info!("extracting oci layout: {}", oci_layout.display());
+            timings.time("extract", || {
+                docker_helper::extract_oci_layout(
+                    oci_layout,
+                    &image_plan.extract_dir,
+                    &target,
+                    platform,
+                    self.jobs,
+                    self.verify_layers,
+                    events,
+                )
+            })?
+        } else {
+            // a `docker save` tar, given or exported by the daemon
+            let image_tar = match &self.image_file {
+                Some(image_file) => image_file.clone(),
+                None => rt
+                    .block_on(docker.export_image_tar(
+                        &image_plan.image,
+                        &image_plan.extract_dir,
+                        self.pull_policy,
+                        self.pull_retries,
+                        events,
+                        timings,
+                    ))
+                    .context(format!("failed to export image {}", image_plan.image))?,
+            };
+            info!("extracting image tar: {}", image_tar.display());
+            let f = File::open(&image_tar)
+                .context(format!("failed to open {}", image_tar.display()))?;
+            timings.time("extract", || {
+                docker_helper::extract_image_tar(
+                    BufReader::new(f),
+                    &image_plan.image,
+                    &image_plan.extract_dir,
+                    &target,
+                    platform,
+                    self.jobs,
+                    self.verify_layers,
+                    events,
+                )
+            })?
+        };
+        // stacked lower dirs never see the session's changes, cache them now
+        if self.cache && !self.layer_cache && !self.stacked_images.is_empty() {
+            timings.time("cache save", || {
+                save_cache_entry(&image_plan.rootfs_dir, cache_path, &image_config, events)
+            })?;
+        }
+        Ok((image_config, layer_dirs))
+    }
+
+    /// Prepare the rootfs & mounts, nothing is entered until [`DebugSession::run`].
+    pub fn build(mut self) -> Result<DebugSession> {
+        // init
+        let events = EventEmitter::new(self.output, self.quiet);
+        let timings = PhaseTimings::default();
+        let rc_script = self.rc_script()?;
+        let (rt, mut docker) = self.connect()?;
+
+        // get container info & unmount all previously mounted specs
+        let container_info = timings.time("inspect", || self.container_info(&rt, &docker))?;
+        info!("container info: {:?}", container_info);
+        self.resolve_auto_image(&container_info);
+        let image_digests = self.image_digests(&rt, &docker)?;
+        let plan = self.plan(&image_digests)?;
+        // a concurrent session would lose its rootfs to the cleanup below
+        let workdir_lock = WorkdirLock::acquire(&plan.work_dir, self.wait_lock)?;
+        mount_helper::unmount_all_under(&plan.work_dir)?;
+
+        // prepare work directory
+        if plan.work_dir.exists() {
+            let _ = fs::remove_dir_all(&plan.work_dir);
+        }
+        create_dir_all(&plan.overlay_lower_dir)?;
+        create_dir_all(&self.cache_dir)?;
+        create_dir_all(&plan.work_dir)?;
+        create_dir_all(&plan.image_extract_dir)?;
+        create_dir_all(&plan.rootfs_base_dir)?;
+        create_dir_all(&plan.overlay_work_dir)?;
+        create_dir_all(&plan.mergedfs_dir)?;
+        for image_plan in &plan.images {
+            create_dir_all(&image_plan.rootfs_dir)?;
+            create_dir_all(&image_plan.extract_dir)?;
+        }
+
+        let SessionPlan {
+            images,
+            rootfs_base_dir,
+            overlay_work_dir,
+            mergedfs_dir,
+            container_mount_path,
+            cache_path,
+            image_extract_dir,
+            mut mount_opt,
+            ..
+        } = plan;
+
+        // image preparation, every image must be ready before anything is
+        // mounted, the topmost image's config applies
+        let platform = match &self.platform {
+            Some(platform) => PlatformSpec::parse(platform)?,
+            None => PlatformSpec::host(),
+        };
+        let mut image_config = ImageConfig::default();
+        let mut layer_dirs = Vec::new();
+        for image_plan in &images {
+            if self.local_image().is_none() {
+                self.use_registry_auth(&mut docker, &image_plan.image)?;
+            }
+            let (config, image_layer_dirs) =
+                self.prepare_image(&rt, &docker, image_plan, &platform, &events, &timings)?;
+            self.check_arch(&image_plan.image, &config)?;
+            image_config = config;
+            if image_layer_dirs.is_empty() {
+                events.emit(Event::Extracted {
+                    path: &image_plan.rootfs_dir,
+                });
+            }
+            for layer_dir in &image_layer_dirs {
+                events.emit(Event::Extracted { path: layer_dir });
+            }
+            layer_dirs.extend(image_layer_dirs);
+        }
+        // the export tar & blobs are spent once extracted, the overlay & the
+        // cache only read the rootfs & layer dirs
+        if !self.keep
+            && let Err(e) = fs::remove_dir_all(&image_extract_dir)
+        {
+            warn!("failed to remove {}: {}", image_extract_dir.display(), e);
+        }
+        // the cached layers stack below an upper dir holding only the session's changes
+        if !layer_dirs.is_empty() {
+            mount_opt = self.overlay_mount_opt(&layer_dirs, &rootfs_base_dir, &overlay_work_dir)?;
+        }
+        // no runtime threads may be left once we setns & fork
+        rt.shutdown_timeout(Duration::from_secs(0));
+
+        // past this point the host only sees our mounts if they must outlive us
+        if self.private_mounts && self.unmounts_merged_dir() {
+            match mount_helper::unshare_private_mounts() {
+                Ok(()) => info!("session mounts are private to this session"),
+                Err(e) => warn!("{:#}, session mounts are made on the host", e),
+            }
+        }
+
+        // detach our mounts if we get interrupted before the normal exit path
+        let mut cleanup_paths = Vec::new();
+        if self.unmounts_merged_dir() {
+            cleanup_paths.push(mergedfs_dir.clone());
+        }
+        if !self.keep {
+            cleanup_paths.push(container_mount_path.clone());
+            for host_mount in &self.mounts {
+                cleanup_paths.push(mergedfs_dir.join(host_mount.dest.trim_start_matches('/')));
+            }
+            for volume in self.volume_mounts(&container_info) {
+                cleanup_paths.push(container_mount_path.join(volume.dest.trim_start_matches('/')));
+            }
+        }
+        let mount_cleanup = MountCleanup::new(cleanup_paths)?;
+        signal_helper::install_cleanup_handler(&mount_cleanup)?;
+
+        // build rootfs mount
+        let mut overlay_mount = timings.time("overlay mount", || {
+            // pivot_root needs a mount point, a bind of the rootfs is enough
+            if self.no_overlay {
+                return mount_helper::bind_mount(&rootfs_base_dir, &mergedfs_dir, false);
+            }
+            mount_helper::mount_overlay(&mount_opt, &rootfs_base_dir, &mergedfs_dir)
+        })?;
+        if !self.unmounts_merged_dir() {
+            overlay_mount.leak();
+        }
+        events.emit(Event::Mounted {
+            path: &mergedfs_dir,
+        });
+
+        // build container mount
+        create_dir_all(&container_mount_path)?;
+        let container_mount_start = Instant::now();
+        let mut container_mount = match &container_info.merged_dir {
+            Some(merged_dir) => mount_helper::bind_mount(
+                Path::new(merged_dir),
+                &container_mount_path,
+                self.readonly_container,
+            ),
+            None => {
+                if self.no_overlay {
+                    return Err(anyhow::anyhow!(
+                        "storage driver {} reports no MergedDir, the container fs can only be assembled with overlay",
+                        container_info.driver
+                    ));
+                }
+                if !self.readonly_container {
+                    warn!("no MergedDir reported, container fs is mounted readonly");
+                }
+                mount_helper::mount_overlay(
+                    &container_layers_mount_opt(&container_info),
+                    Path::new("overlay"),
+                    &container_mount_path,
+                )
+            }
+        }
+        .context("failed to mount container fs")?;
+        timings.record("container mount", container_mount_start.elapsed());
+        if self.keep {
+            container_mount.leak();
+        }
+        events.emit(Event::Mounted {
+            path: &container_mount_path,
+        });
+
+        // volumes live outside the container's layers, bind them over the
+        // container fs where the container sees them
+        let mut host_mounts = Vec::new();
+        for volume in self.volume_mounts(&container_info) {
+            let mut guard = mount_helper::bind_host_mount(&volume, &container_mount_path).context(
+                format!("failed to mount volume {}", volume.source.display()),
+            )?;
+            if self.keep {
+                guard.leak();
+            }
+            events.emit(Event::Mounted {
+                path: &container_mount_path.join(volume.dest.trim_start_matches('/')),
+            });
+            host_mounts.push(guard);
+        }
+
+        // build host mounts
+        for host_mount in &self.mounts {
+            let mut guard = mount_helper::bind_host_mount(host_mount, &mergedfs_dir)
+                .context(format!("failed to mount {}", host_mount.source.display()))?;
+            if self.keep {
+                guard.leak();
+            }
+            events.emit(Event::Mounted {
+                path: &mergedfs_dir.join(host_mount.dest.trim_start_matches('/')),
+            });
+            host_mounts.push(guard);
+        }
+
+        if self.with_busybox {
+            busybox::install(&mergedfs_dir, self.busybox_force)?;
+        }
+
+        let shell = rootfs_shell(&mergedfs_dir, &self.shell)?;
+
+        if self.copy_resolv_conf {
+            copy_resolv_conf(&container_mount_path, &mergedfs_dir)?;
+        }
+
+        // prepare init script
+        {
+            let init_script_content = include_str!("init.sh");
+            let mut init_script_file = File::create(mergedfs_dir.join("init.sh"))?;
+            init_script_file.write_all(init_script_content.as_bytes())?;
+            init_script_file.set_permissions(Permissions::from_mode(0o755))?;
+        }
+        if let Some(rc_script) = &rc_script {
+            fs::write(mergedfs_dir.join(RC_FILE), rc_script)?;
+        }
+
+        // everything above is the image or ours, later upper dir changes are the session's
+        let started = SystemTime::now();
+
+        Ok(DebugSession {
+            options: self,
+            container_info,
+            rootfs_base_dir,
+            mergedfs_dir,
+            cache_path,
+            started,
+            shell,
+            image_config,
+            timings,
+            mount_cleanup,
+            _host_mounts: host_mounts,
+            _container_mount: container_mount,
+            _overlay_mount: overlay_mount,
+            _workdir_lock: workdir_lock,
+        })
+    }
+}
+
+// every path & mount a session touches, shared by build & dry_run so the dry
+// run output stays accurate
+struct SessionPlan {
+    work_dir: PathBuf,
+    images: Vec<ImagePlan>,
+    image_extract_dir: PathBuf,
+    overlay_lower_dir: PathBuf,
+    overlay_work_dir: PathBuf,
+    rootfs_base_dir: PathBuf,
+    mergedfs_dir: PathBuf,
+    container_mount_path: PathBuf,
+    // work cache saved on exit, none for stacked images
+    cache_path: Option<PathBuf>,
+    mount_opt: String,
+}
+
+struct ImagePlan {
+    image: String,
+    rootfs_dir: PathBuf,
+    extract_dir: PathBuf,
+    cache_path: PathBuf,
+}
+
+/// A prepared debug rootfs, mounts are detached when dropped.
+pub struct DebugSession {
+    options: DebugSessionBuilder,
+    container_info: ContainerInfo,
+    rootfs_base_dir: PathBuf,
+    mergedfs_dir: PathBuf,
+    cache_path: Option<PathBuf>,
+    started: SystemTime,
+    shell: String,
+    image_config: ImageConfig,
+    timings: PhaseTimings,
+    mount_cleanup: Arc<MountCleanup>,
+    // fields drop in declaration order, nested mounts first
+    _host_mounts: Vec<MountGuard>,
+    _container_mount: MountGuard,
+    _overlay_mount: MountGuard,
+    // released once everything above is unmounted
+    _workdir_lock: WorkdirLock,
+}
+
+impl DebugSession {
+    pub fn builder() -> DebugSessionBuilder {
+        DebugSessionBuilder::default()
+    }
+
+    /// Enter the container namespaces & run the shell until it exits, returns
+    /// its exit code. Forks, so it must be called from a single threaded process,
+    /// and the calling process is left in the container namespaces.
+    pub fn run(self) -> Result<i32> {
+        let container_pidfd = if self.options.exit_with_container && !self.options.fs_only {
+            match ContainerWatcher::open(self.container_info.pid as i32) {
+                Ok(pidfd) => Some(pidfd),
+                Err(e) => {
+                    warn!(
+                        "can't watch the container, session won't end with it: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // enter container namespace
+        // mount namespace is never entered, we enter mount from host
+        if self.options.fs_only {
+            info!("fs only session, staying in the host namespaces");
+        } else {
+            // the parent's cache save is charged to the container too, the
+            // child can't join once it's in the container's cgroup namespace
+            if self.options.join_cgroup {
+                let cgroup = self
+                    .container_info
+                    .cgroup
+                    .as_deref()
+                    .context("container cgroup is unknown")?;
+                namespace_helper::join_cgroup(cgroup)?;
+            }
+            let ns_flags = self.options.ns_flags(self.container_info.pid as i32)?;
+            namespace_helper::enter_namespace(self.container_info.pid as i32, ns_flags)?;
+        }
+
+        // fork 1
+        let fork_res = unsafe { libc::fork() };
+        match fork_res {
+            // In the child process
+            0 => {
+                // a forked child must never return into the caller
+                if let Err(e) = self.run_shell() {
+                    error!("debug shell failed: {:?}", e);
+                }
+                std::process::exit(1);
+            }
+            // In the parent process
+            pid if pid > 0 => {
+                // returning here would unmount under the running shell
+                let watcher = match container_pidfd
+                    .map(|pidfd| ContainerWatcher::spawn(pidfd, pid))
+                    .transpose()
+                {
+                    Ok(watcher) => watcher,
+                    Err(e) => {
+                        warn!("container exit won't end the session: {:?}", e);
+                        None
+                    }
+                };
+                // forwarded signals interrupt the wait, wait_child retries
+                let status = utils::wait_child(pid);
+                if let Some(watcher) = watcher {
+                    watcher.stop();
+                }
+                // a failed diff must not lose the cache
+                if let Some(diff_path) = &self.options.save_diff
+                    && let Err(e) = self.timings.time("save diff", || self.save_diff(diff_path))
+                {
+                    error!("failed to save diff to {}: {:?}", diff_path.display(), e);
+                }
+                // nor may a failed cache save lose the shell's exit code
+                if self.options.cache
+                    && let Err(e) = self.timings.time("cache save", || self.save_cache())
+                {
+                    error!("failed to save rootfs cache: {:?}", e);
+                }
+                self.timings.report(&self.events());
+                if self.options.keep {
+                    self.report_kept_mounts();
+                }
+                // unmount happens on drop
+                let code = utils::exit_code(status);
+                self.events().emit(Event::SessionExit { code });
+                Ok(code)
+            }
+            // If fork fails
+            _ => {
+                let err = std::io::Error::last_os_error();
+                error!("fork failed: {}", err);
+                Err(anyhow::anyhow!("Fork failed: {}", err))
+            }
+        }
+    }
+
+    fn report_kept_mounts(&self) {
+        info!("kept rootfs at {}", self.mergedfs_dir.display());
+        info!("kept mounts, run `clean` to tear them down:");
+        info!("  {}", self.mergedfs_dir.display());
+        info!(
+            "  {}",
+            self.mergedfs_dir
+                .join(self.options.container_mount_path.trim_start_matches('/'))
+                .display()
+        );
+        for volume in self.options.volume_mounts(&self.container_info) {
+            info!(
+                "  {}",
+                self.mergedfs_dir
+                    .join(self.options.container_mount_path.trim_start_matches('/'))
+                    .join(volume.dest.trim_start_matches('/'))
+                    .display()
+            );
+        }
+        for host_mount in &self.options.mounts {
+            info!(
+                "  {}",
+                self.mergedfs_dir
+                    .join(host_mount.dest.trim_start_matches('/'))
+                    .display()
+            );
+        }
+    }
+
+    fn events(&self) -> EventEmitter {
+        EventEmitter::new(self.options.output, self.options.quiet)
+    }
+
+    fn save_cache(&self) -> Result<()> {
+        // stacked images were cached as they were extracted
+        let Some(cache_path) = &self.cache_path else {
+            return Ok(());
+        };
+        save_cache_entry(
+            &self.rootfs_base_dir,
+            cache_path,
+            &self.image_config,
+            &self.events(),
+        )?;
+        if let Some(max_size) = self.options.cache_max_size {
+            let removed =
+                cache_helper::prune_cache(&self.options.cache_dir, max_size, Some(cache_path))?;
+            for path in removed {
+                info!("removed cache: {}", path.display());
+            }
+        }
+        Ok(())
+    }
+
+    fn save_diff(&self, diff_path: &Path) -> Result<()> {
+        info!("saving session changes to: {}", diff_path.display());
+        let entries =
+            diff_helper::archive_upper_diff(&self.rootfs_base_dir, self.started, diff_path)?;
+        info!("saved {} changed entries", entries);
+        self.events().emit(Event::DiffSaved {
+            path: diff_path,
+            entries,
+        });
+        Ok(())
+    }
+
+    // handed over prefixed like the container env, the host bash running
+    // init.sh still needs the host PATH & none of the image's shell or loader
+    // settings, call in the single threaded fork 2 child only
+    unsafe fn set_image_env(&self) {
+        for env in self.image_config.env.iter() {
+            let Some((key, value)) = env.split_once("=") else {
+                continue;
+            };
+            let Some(name) = session_env_name(key) else {
+                debug!("not applying image env {}", key);
+                continue;
+            };
+            unsafe {
+                std::env::set_var(name, value);
+            }
+        }
+        if let Some(working_dir) = &self.image_config.working_dir {
+            unsafe {
+                std::env::set_var("IMAGE_WORKDIR", working_dir);
+            }
+        }
+    }
+
+    // the main process's environment, read before forking so a vanished
+    // process fails the session instead of silently starting without it
+    fn container_env(&self) -> Result<Vec<(String, String)>> {
+        if !self.options.inherit_env {
+            return Ok(Vec::new());
+        }
+        if !self.container_info.running {
+            warn!("container isn't running, no environment to inherit");
+            return Ok(Vec::new());
+        }
+        let env = namespace_helper::process_environ(self.container_info.pid)
+            .context("failed to read the container environment")?;
+        Ok(env
+            .into_iter()
+            .filter(|(key, _)| {
+                self.options.inherit_env_all || !SHELL_OWN_ENV.contains(&key.as_str())
+            })
+            .collect())
+    }
+
+    // handed over like the image env so the container can't steer the host
+    // bash either, call in the single threaded fork 2
+    // child only
+    unsafe fn set_container_env(&self, env: &[(String, String)]) {
+        for (key, value) in env {
+            let Some(name) = session_env_name(key) else {
+                debug!("not inheriting {}", key);
+                continue;
+            };
+            unsafe {
+                std::env::set_var(name, value);
+            }
+        }
+    }
+
+    // the container's cwd as seen after pivot_root, checked on the host side
+    // since its volumes are mounted under the container mount as well
+    fn container_workdir(&self) -> PathBuf {
+        let container_root = Path::new(self.options.container_mount_path.trim_start_matches('/'));
+        let Some(working_dir) = &self.container_info.working_dir else {
+            return Path::new("/").join(container_root);
+        };
+        let workdir = container_root.join(working_dir.trim_start_matches('/'));
+        if !self.mergedfs_dir.join(&workdir).is_dir() {
+            debug!(
+                "container workdir {} not found, starting in the container root",
+                working_dir
+            );
+            return Path::new("/").join(container_root);
+        }
+        Path::new("/").join(workdir)
+    }
+
+    // runs in the fork 1 child, only returns on error
+    fn run_shell(&self) -> Result<()> {
+        // clone mount namespace
+        let enter_res = unsafe { libc::unshare(libc::CLONE_NEWNS) };
+        if enter_res != 0 {
+            let err = std::io::Error::last_os_error();
+            error!("failed to unshare namespaces: {}", err);
+            return Err(anyhow::anyhow!("Failed to unshare namespaces: {}", err));
+        }
+
+        let container_env = self.container_env()?;
+
+        // fork 2
+        let pty = if self.options.pty {
+            Some(pty_helper::open_pty()?)
+        } else {
+            None
+        };
+        let fork_res = unsafe { libc::fork() };
+        match fork_res {
+            // In the child process
+            0 => {
+                // own process group so signals can be forwarded to the shell
+                // & its children alone, attach_slave makes its own session
+                match pty {
+                    Some(pty) => pty_helper::attach_slave(pty)?,
+                    None => utils::take_foreground()?,
+                }
+                set_current_dir(&self.mergedfs_dir)?;
+                // init.sh runs on the host root until pivot_root, then execs $SHELL
+                unsafe {
+                    if self.options.apply_image_env {
+                        self.set_image_env();
+                    }
+                    // after the image env so the container's values win
+                    self.set_container_env(&container_env);
+                    if self.options.apply_image_user
+                        && let Some(user) = &self.image_config.user
+                    {
+                        std::env::set_var("IMAGE_USER", user);
+                    }
+                    if self.options.cd_container {
+                        std::env::set_var("CONTAINER_WORKDIR", self.container_workdir());
+                    }
+                    std::env::set_var("SHELL", &self.shell);
+                    if self.options.rc_file.is_some() {
+                        std::env::set_var("RC_FILE", format!("/{}", RC_FILE));
+                    }
+                    std::env::set_var("MOUNT_PROC", env_flag(self.options.mount_proc));
+                    std::env::set_var("MOUNT_SYS", env_flag(self.options.mount_sys));
+                    std::env::set_var("MOUNT_DEV", env_flag(self.options.mount_dev));
+                }
+                unsafe {
+                    let cmd = CString::new("/usr/bin/bash").expect("CString::new failed");
+                    let arg0 = CString::new("bash").expect("CString::new failed");
+                    let arg1 = CString::new(self.options.init_file.trim_start_matches("/"))
+                        .expect("CString::new failed");
+                    // one-off command is forwarded to init.sh, which execs it instead of the shell
+                    let command = self
+                        .options
+                        .command
+                        .iter()
+                        .map(|arg| CString::new(arg.as_str()).expect("CString::new failed"))
+                        .collect::<Vec<_>>();
+                    let mut args = vec![arg0.as_ptr(), arg1.as_ptr()];
+                    args.extend(command.iter().map(|arg| arg.as_ptr()));
+                    args.push(std::ptr::null()); // Null-terminated argument list
+                    libc::execv(cmd.as_ptr(), args.as_ptr());
+                }
+                // execv only returns on failure, errno says why
+                let err = std::io::Error::last_os_error();
+                error!("exec failed: {}", err);
+                Err(anyhow::anyhow!("Exec failed: {}", err))
+            }
+            // In the parent process
+            pid if pid > 0 => {
+                // the shell ends with us, whether stopped by the container
+                // watcher, a signal sent to us or as pid 1 of the namespace
+                signal_helper::forward_signals_to(pid)?;
+                let status = match pty {
+                    Some(pty) => {
+                        drop(pty.slave);
+                        pty_helper::relay_until_exit(pty.master, pid)?
+                    }
+                    None => {
+                        let status = utils::wait_child(pid);
+                        utils::restore_foreground();
+                        status
+                    }
+                };
+                std::process::exit(utils::exit_code(status));
+            }
+            // If fork fails
+            _ => {
+                let err = std::io::Error::last_os_error();
+                error!("fork failed: {}", err);
+                Err(anyhow::anyhow!("Fork failed: {}", err))
+            }
+        }
+    }
+}
+
+impl Drop for DebugSession {
+    fn drop(&mut self) {
+        // the mount guards take over from the signal handler
+        self.mount_cleanup.disarm();
+    }
+}
+
+// archive a rootfs into the cache with its checksum & image config sidecars
+fn save_cache_entry(
+    rootfs_dir: &Path,
+    cache_path: &Path,
+    image_config: &ImageConfig,
+    events: &EventEmitter,
+) -> Result<()> {
+    info!("saving work cache to: {}", cache_path.display());
+    cache_helper::archive_rootfs(rootfs_dir, cache_path).context(format!(
+        "failed to archive rootfs, path: {}",
+        rootfs_dir.display(),
+    ))?;
+    cache_helper::write_checksum(cache_path)?;
+    cache_helper::write_image_config(cache_path, image_config)?;
+    events.emit(Event::CacheSaved { path: cache_path });
+    Ok(())
+}
+
+// merged view of a container whose driver only reports its layers, the upper
+// dir is stacked as the top lower dir since its work dir belongs to the
+// container's own mount, so the view is readonly
+// the shell is started by init.sh after pivot_root, so look for it in the
+// rootfs, symlinks like alpine's /bin/sh -> /bin/busybox resolved in there too
+fn rootfs_shell(rootfs_dir: &Path, shell: &str) -> Result<String> {
+    if utils::resolve_rootfs_path(rootfs_dir, shell).is_some() {
+        return Ok(shell.to_string());
+    }
+    warn!(
+        "shell {} not found in rootfs, falling back to /bin/sh",
+        shell
+    );
+    if utils::resolve_rootfs_path(rootfs_dir, "/bin/sh").is_none() {
+        return Err(anyhow::anyhow!("no usable shell found in rootfs"));
+    }
+    Ok(String::from("/bin/sh"))
+}
+
+fn container_layers_mount_opt(container_info: &ContainerInfo) -> String {
+    format!(
+        "lowerdir={}:{}",
+        container_info.upper_dir.as_deref().unwrap_or_default(),
+        container_info.lower_dir.as_deref().unwrap_or_default()
+    )
+}
+
+// variables the shell & its login setup provide, skipped by inherit_env
+// unless inherit_env_all
+const SHELL_OWN_ENV: [&str; 6] = ["PATH", "HOME", "HOSTNAME", "PWD", "SHLVL", "TERM"];
+
+// init.sh exports variables with this prefix under their own name once it
+// has pivoted into the rootfs
+const SESSION_ENV_PREFIX: &str = "SESSION_ENV_";
+
+// never handed to the shell: init.sh's own settings & loop variables, and
+// variables that make bash or the dynamic loader run code of the container's
+// choosing
+const UNSAFE_ENV: [&str; 19] = [
+    "SHELL",
+    "env_name",
+    "env_value",
+    "RC_FILE",
+    "IMAGE_WORKDIR",
+    "IMAGE_USER",
+    "CONTAINER_WORKDIR",
+    "MOUNT_PROC",
+    "MOUNT_SYS",
+    "MOUNT_DEV",
+    "BASH_ENV",
+    "ENV",
+    "SHELLOPTS",
+    "BASHOPTS",
+    "PROMPT_COMMAND",
+    "PS0",
+    "PS4",
+    "IFS",
+    "CDPATH",
+];
+const UNSAFE_ENV_PREFIXES: [&str; 3] = ["LD_", "BASH_FUNC_", SESSION_ENV_PREFIX];
+
+// prefixed name a variable is handed to init.sh under, none if it's unsafe
+// or not a valid shell name
+fn session_env_name(key: &str) -> Option<String> {
+    let valid_name = key
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name
+        || UNSAFE_ENV.contains(&key)
+        || UNSAFE_ENV_PREFIXES
+            .iter()
+            .any(|prefix| key.starts_with(prefix))
+    {
+        return None;
+    }
+    Some(format!("{}{}", SESSION_ENV_PREFIX, key))
+}
+
+// written next to init.sh, found at / once init.sh has pivoted into the rootfs
+const RC_FILE: &str = "rc.sh";
+
+// what the shell would have read on its own, for --rc-append
+const RC_PRELUDE: &str = r#"[ -f /etc/bash.bashrc ] && . /etc/bash.bashrc
+[ -f "$HOME/.bashrc" ] && . "$HOME/.bashrc"
+"#;
+
+// boolean option as understood by init.sh
+fn env_flag(enabled: bool) -> &'static str {
+    if enabled { "1" } else { "0" }
+}
+
+// docker bind mounts resolv.conf into the container so the copy in its fs can
+// be empty, fall back to the host's then
+fn copy_resolv_conf(container_mount_path: &Path, mergedfs_dir: &Path) -> Result<()> {
+    let container_resolv_conf = container_mount_path.join("etc/resolv.conf");
+    let source = if container_resolv_conf.metadata().is_ok_and(|m| m.len() > 0) {
+        container_resolv_conf
+    } else {
+        PathBuf::from("/etc/resolv.conf")
+    };
+    if !source.exists() {
+        warn!("no resolv.conf found, keeping the one from the image");
+        return Ok(());
+    }
+    let etc_dir = mergedfs_dir.join("etc");
+    create_dir_all(&etc_dir)?;
+    // the image may ship it as a symlink, which must not be written through
+    let dst_path = etc_dir.join("resolv.conf");
+    if dst_path.symlink_metadata().is_ok() {
+        fs::remove_file(&dst_path)?;
+    }
+    fs::copy(&source, &dst_path)
+        .context(format!("failed to copy {} into rootfs", source.display()))?;
+    info!("copied {} into rootfs", source.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container_runtime::mock::MockRuntime;
+    use std::collections::HashMap;
+
+    #[test]
+    fn container_info_waits_only_for_namespace_sessions() {
+        let rt = Runtime::new().unwrap();
+        let runtime = MockRuntime {
+            running: true,
+            ..MockRuntime::default()
+        };
+        let builder = DebugSession::builder()
+            .container_id("c1")
+            .wait(Some(Duration::from_secs(1)));
+        let info = builder.container_info(&rt, &runtime).unwrap();
+        assert_eq!(info.pid, 42);
+        builder.fs_only(true).container_info(&rt, &runtime).unwrap();
+        assert_eq!(
+            *runtime.calls.borrow(),
+            vec!["wait c1", "info c1 true", "info c1 false"]
+        );
+    }
+
+    #[test]
+    fn container_info_needs_a_container_id() {
+        let rt = Runtime::new().unwrap();
+        let runtime = MockRuntime::default();
+        assert!(
+            DebugSession::builder()
+                .container_info(&rt, &runtime)
+                .is_err()
+        );
+        assert!(runtime.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn image_digests_only_query_the_runtime_for_digest_keys() {
+        let rt = Runtime::new().unwrap();
+        let runtime = MockRuntime {
+            digests: HashMap::from([(String::from("debian:12"), String::from("sha256:aa"))]),
+            ..MockRuntime::default()
+        };
+        let builder = DebugSession::builder()
+            .image("debian:12")
+            .stack_images(vec![String::from("tools:latest")]);
+        assert_eq!(
+            builder.image_digests(&rt, &runtime).unwrap(),
+            vec![None, None]
+        );
+        assert!(runtime.calls.borrow().is_empty());
+
+        let builder = builder.cache_key(CacheKey::Digest);
+        assert_eq!(
+            builder.image_digests(&rt, &runtime).unwrap(),
+            vec![Some(String::from("sha256:aa")), None]
+        );
+        assert_eq!(
+            *runtime.calls.borrow(),
+            vec!["digest debian:12", "digest tools:latest"]
+        );
+    }
+
+    #[test]
+    fn session_env_name_prefixes_plain_variables() {
+        assert_eq!(
+            session_env_name("DATABASE_URL").as_deref(),
+            Some("SESSION_ENV_DATABASE_URL")
+        );
+        assert_eq!(session_env_name("_x1").as_deref(), Some("SESSION_ENV__x1"));
+    }
+
+    #[test]
+    fn session_env_name_drops_code_running_variables() {
+        for key in [
+            "BASH_ENV",
+            "ENV",
+            "LD_PRELOAD",
+            "LD_LIBRARY_PATH",
+            "BASH_FUNC_ls%%",
+        ] {
+            assert_eq!(session_env_name(key), None, "{}", key);
+        }
+    }
+
+    #[test]
+    fn session_env_name_drops_init_script_variables() {
+        for key in [
+            "SHELL",
+            "RC_FILE",
+            "IMAGE_USER",
+            "CONTAINER_WORKDIR",
+            "SESSION_ENV_X",
+        ] {
+            assert_eq!(session_env_name(key), None, "{}", key);
+        }
+    }
+
+    #[test]
+    fn session_env_name_drops_invalid_names() {
+        for key in ["", "1A", "A-B", "a.b"] {
+            assert_eq!(session_env_name(key), None, "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn container_layers_mount_opt_stacks_upper_over_lower() {
+        let container_info = ContainerInfo {
+            upper_dir: Some("/overlay2/abc/diff".to_string()),
+            lower_dir: Some("/overlay2/l/1:/overlay2/l/2".to_string()),
+            ..ContainerInfo::default()
+        };
+        assert_eq!(
+            container_layers_mount_opt(&container_info),
+            "lowerdir=/overlay2/abc/diff:/overlay2/l/1:/overlay2/l/2"
+        );
+    }
+
+    #[test]
+    fn rootfs_shell_resolves_absolute_links_in_the_rootfs() {
+        let rootfs = tempfile::tempdir().unwrap();
+        let rootfs_dir = rootfs.path();
+        fs::create_dir_all(rootfs_dir.join("bin")).unwrap();
+        fs::write(rootfs_dir.join("bin/busybox"), b"").unwrap();
+        std::os::unix::fs::symlink("/bin/busybox", rootfs_dir.join("bin/sh")).unwrap();
+        // alpine has no bash, its /bin/sh is a link to /bin/busybox
+        assert_eq!(
+            rootfs_shell(rootfs_dir, "/usr/bin/bash").unwrap(),
+            "/bin/sh"
+        );
+        assert_eq!(rootfs_shell(rootfs_dir, "/bin/sh").unwrap(), "/bin/sh");
+        fs::remove_file(rootfs_dir.join("bin/busybox")).unwrap();
+        assert!(rootfs_shell(rootfs_dir, "/usr/bin/bash").is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::Result;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use std::{
+    ffi::CString,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicI32, Ordering},
+    },
+};
+
+// process group signals are forwarded to instead of exiting, set once the
+// debug shell is forked
+static FORWARD_PID: AtomicI32 = AtomicI32::new(0);
+
+// mounts to detach when interrupted, the normal exit path disarms it and lets
+// the mount guards unmount instead
+pub struct MountCleanup {
+    // pre-allocated for the signal handler, which must not allocate
+    c_paths: Vec<CString>,
+    owner_pid: libc::pid_t,
+    done: AtomicBool,
+}
+
+impl MountCleanup {
+    // paths are unmounted in reverse order, push parents before nested mounts
+    pub fn new(paths: Vec<PathBuf>) -> Result<Arc<Self>> {
+        let c_paths = paths
+            .iter()
+            .map(|path| CString::new(path.as_os_str().as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Arc::new(MountCleanup {
+            c_paths,
+            owner_pid: unsafe { libc::getpid() },
+            done: AtomicBool::new(false),
+        }))
+    }
+
+    fn claim(&self) -> bool {
+        !self.done.swap(true, Ordering::SeqCst)
+    }
+
+    // called on the normal exit path before the mount guards drop
+    pub fn disarm(&self) {
+        self.claim();
+    }
+
+    // async-signal-safe variant, errors are ignored
+    fn unmount_from_signal(&self) {
+        // forked children inherit the handler but don't own the mounts
+        if unsafe { libc::getpid() } != self.owner_pid || !self.claim() {
+            return;
+        }
+        for path in self.c_paths.iter().rev() {
+            unsafe {
+                libc::umount2(path.as_ptr(), libc::MNT_DETACH);
+            }
+        }
+    }
+}
+
+pub fn install_cleanup_handler(cleanup: &Arc<MountCleanup>) -> Result<()> {
+    for signal in [SIGINT, SIGTERM] {
+        let cleanup = cleanup.clone();
+        unsafe {
+            signal_hook::low_level::register(signal, move || {
+                if forward_to_group(signal) {
+                    return;
+                }
+                cleanup.unmount_from_signal();
+                signal_hook::low_level::exit(128 + signal);
+            })?;
+        }
+    }
+    Ok(())
+}
+
+// forward SIGINT, SIGTERM & SIGQUIT to the process group led by pid rather
+// than exiting, the caller waits for it instead
+pub fn forward_signals_to(pid: libc::pid_t) -> Result<()> {
+    FORWARD_PID.store(pid, Ordering::SeqCst);
+    unsafe {
+        signal_hook::low_level::register(SIGQUIT, || {
+            forward_to_group(SIGQUIT);
+        })?;
+    }
+    Ok(())
+}
+
+// async-signal-safe, false when nothing is forwarded to
+fn forward_to_group(signal: libc::c_int) -> bool {
+    let forward_pid = FORWARD_PID.load(Ordering::SeqCst);
+    if forward_pid <= 0 {
+        return false;
+    }
+    unsafe {
+        libc::killpg(forward_pid, signal);
+        // an interactive shell ignores SIGTERM but not a hangup
+        if signal == SIGTERM {
+            libc::killpg(forward_pid, SIGHUP);
+        }
+    }
+    true
+}
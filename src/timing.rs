@@ -0,0 +1,45 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::events::{Event, EventEmitter};
+
+// wall time spent per session phase, for telling what a slow session waited on
+#[derive(Debug, Default)]
+pub struct PhaseTimings {
+    // in first seen order, repeated phases add up, e.g. one pull per stacked image
+    phases: Mutex<Vec<(&'static str, Duration)>>,
+}
+
+impl PhaseTimings {
+    pub fn record(&self, phase: &'static str, elapsed: Duration) {
+        let mut phases = self.phases.lock().unwrap_or_else(|e| e.into_inner());
+        match phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => phases.push((phase, elapsed)),
+        }
+    }
+
+    pub fn time<T>(&self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = f();
+        self.record(phase, start.elapsed());
+        res
+    }
+
+    // summary table in the logs, one event per phase in json mode
+    pub fn report(&self, events: &EventEmitter) {
+        let phases = self.phases.lock().unwrap_or_else(|e| e.into_inner());
+        if phases.is_empty() {
+            return;
+        }
+        info!("phase timings:");
+        for (phase, elapsed) in phases.iter() {
+            info!("  {:<16} {:>8.2}s", phase, elapsed.as_secs_f64());
+            events.emit(Event::PhaseTiming {
+                phase,
+                seconds: elapsed.as_secs_f64(),
+            });
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+use tar::{Archive, Builder};
+
+use crate::docker_helper::ContainerInfo;
+use crate::utils;
+
+/// Copy a file or directory out of the container fs onto the host, no
+/// namespace is entered. Returns the path written on the host.
+pub fn copy_from_container(
+    container_info: &ContainerInfo,
+    container_path: &str,
+    host_path: &Path,
+) -> Result<PathBuf> {
+    let merged_dir = container_info.merged_dir.as_deref().context(format!(
+        "storage driver {} exposes no merged container fs to copy from",
+        container_info.driver
+    ))?;
+    let source = utils::rootfs_path(Path::new(merged_dir), container_path)?;
+    let metadata = source
+        .symlink_metadata()
+        .context(format!("{} not found in the container", container_path))?;
+
+    // like cp, an existing directory receives the source by name
+    let dest = match source.file_name() {
+        Some(file_name) if host_path.is_dir() => host_path.join(file_name),
+        _ => host_path.to_path_buf(),
+    };
+    if metadata.is_dir() {
+        copy_dir(&source, &dest)?;
+    } else {
+        // fs::copy keeps the permission bits
+        fs::copy(&source, &dest).context(format!(
+            "failed to copy {} to {}",
+            container_path,
+            dest.display()
+        ))?;
+    }
+    Ok(dest)
+}
+
+// stream the tree through a tar so symlinks stay links & nothing is followed
+// out of the container fs
+fn copy_dir(source: &Path, dest: &Path) -> Result<()> {
+    let (reader, writer) = pipe()?;
+    let source = source.to_path_buf();
+    let archiver = thread::spawn(move || -> Result<()> {
+        let mut archive = Builder::new(writer);
+        archive.follow_symlinks(false);
+        archive.append_dir_all(".", &source)?;
+        archive.finish()?;
+        Ok(())
+    });
+
+    fs::create_dir_all(dest)?;
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    let unpack_res = archive
+        .unpack(dest)
+        .context(format!("failed to write {}", dest.display()));
+    // a failed unpack closes the pipe, which fails the archiver as well
+    drop(archive);
+    let archive_res = archiver
+        .join()
+        .map_err(|_| anyhow::anyhow!("archiver thread panicked"))?;
+    unpack_res?;
+    archive_res
+}
+
+fn pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(anyhow::anyhow!(
+            "failed to create pipe: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let (reader, writer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    Ok((File::from(reader), File::from(writer)))
+}
@@ -1,40 +1,157 @@
-use anyhow::{Ok, Result};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
 use std::{
-    fs::{File, Permissions, create_dir_all, remove_file, set_permissions},
-    io::{Read, copy},
-    os::unix::fs::{PermissionsExt, symlink},
-    path::Path,
+    collections::HashSet,
+    ffi::CString,
+    fs::{
+        File, Permissions, create_dir_all, hard_link, read_dir, remove_dir_all, remove_file,
+        set_permissions,
+    },
+    io::{BufWriter, ErrorKind, Read, Write, copy},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{PermissionsExt, chown, lchown, symlink},
+    },
+    path::{Component, Path, PathBuf},
+    time::Duration,
 };
 use tar::Archive;
+use tracing::{debug, warn};
 
+// overlay whiteout markers, see https://github.com/opencontainers/image-spec/blob/main/layer.md#whiteouts
+const WHITEOUT_PREFIX: &str = ".wh.";
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+// how overlayfs itself marks an opaque dir, its whiteouts are 0:0 char devices
+const OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+// pax extended header prefix used for extended attributes
+pub const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+// layer compression magic bytes, see RFC 1952 & RFC 8878
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+// write buffer bounds for extracted files, small files are written in one
+// syscall without allocating the full buffer for each of them
+const MIN_WRITE_BUFFER: usize = 8 * 1024;
+const MAX_WRITE_BUFFER: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+// compression of a blob from its first bytes, anything else is taken as a plain tar
+pub fn sniff_compression(header: &[u8]) -> Compression {
+    if header.starts_with(&GZIP_MAGIC) {
+        Compression::Gzip
+    } else if header.starts_with(&ZSTD_MAGIC) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    }
+}
+
+// extract a tar onto dst_dir, applying whiteouts as deletions
 pub fn extract_archive(reader: &mut dyn Read, dst_dir: &Path) -> Result<()> {
+    extract_entries(reader, dst_dir, false)
+}
+
+// extract a single layer into its own dir, usable as an overlay lower dir:
+// whiteouts become overlay's 0:0 char devices & opaque dir xattrs
+pub fn extract_layer(reader: &mut dyn Read, dst_dir: &Path) -> Result<()> {
+    extract_entries(reader, dst_dir, true)
+}
+
+fn extract_entries(reader: &mut dyn Read, dst_dir: &Path, overlay_whiteouts: bool) -> Result<()> {
     let mut tar_archive = Archive::new(reader);
-    for entry in tar_archive.entries().unwrap() {
+    // only root can hand files over to other users
+    let preserve_owner = unsafe { libc::geteuid() } == 0;
+    // paths written by this layer, an opaque marker must not clear them
+    let mut layer_paths: HashSet<PathBuf> = HashSet::new();
+    // hardlinks whose target wasn't extracted yet
+    let mut deferred_links: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let canonical_dst_dir = dst_dir.canonicalize()?;
+    // an empty tar, or no bytes at all, is a layer without entries
+    for entry in tar_archive.entries()? {
         let mut tar_file = entry?;
-        let path = tar_file.path()?;
-        let dst_path = dst_dir.join(&path);
+        let path = entry_path(&mut tar_file)?;
+        let dst_path = safe_join(dst_dir, &canonical_dst_dir, &path)?;
+
+        if let Some(file_name) = path.file_name().and_then(|name| name.to_str()) {
+            if file_name == WHITEOUT_OPAQUE {
+                let opaque_dir = dst_path.parent().unwrap_or(dst_dir);
+                if overlay_whiteouts {
+                    create_dir_all(opaque_dir)?;
+                    xattr::set(opaque_dir, OVERLAY_OPAQUE_XATTR, b"y")?;
+                } else {
+                    clear_dir(opaque_dir, &layer_paths)?;
+                }
+                continue;
+            }
+            if let Some(target_name) = file_name.strip_prefix(WHITEOUT_PREFIX) {
+                let target_path = path.with_file_name(target_name);
+                let target_path = safe_join(dst_dir, &canonical_dst_dir, &target_path)?;
+                remove_path(&target_path)?;
+                if overlay_whiteouts {
+                    if let Some(parent) = target_path.parent() {
+                        create_dir_all(parent)?;
+                    }
+                    make_node(&target_path, libc::S_IFCHR, 0)?;
+                }
+                continue;
+            }
+        }
+        layer_paths.insert(dst_path.clone());
+        let uid = Some(tar_file.header().uid()? as u32);
+        let gid = Some(tar_file.header().gid()? as u32);
+        let xattrs = read_pax_xattrs(&mut tar_file)?;
 
         match tar_file.header().entry_type() {
             tar::EntryType::Regular => {
-                let mut dst_file = File::create(dst_path)?;
-                dst_file.set_permissions(Permissions::from_mode(tar_file.header().mode()?))?;
+                // replace rather than write through an existing symlink or
+                // hardlink, a directory of a lower layer is replaced too
+                if dst_path.symlink_metadata().is_ok() {
+                    remove_path(&dst_path)?;
+                }
+                let mode = tar_file.header().mode()?;
+                let capacity = usize::try_from(tar_file.size())
+                    .unwrap_or(MAX_WRITE_BUFFER)
+                    .clamp(MIN_WRITE_BUFFER, MAX_WRITE_BUFFER);
+                let mut dst_file = BufWriter::with_capacity(capacity, File::create(&dst_path)?);
                 copy(&mut tar_file, &mut dst_file)?;
+                // into_inner flushes, a failed write surfaces here instead of on drop
+                let dst_file = dst_file.into_inner().map_err(|e| e.into_error())?;
+                if preserve_owner {
+                    chown(&dst_path, uid, gid)?;
+                }
+                // explicit chmod last, the create mode is masked by umask and both
+                // writes & chown clear setuid/setgid
+                dst_file.set_permissions(Permissions::from_mode(mode))?;
             }
             tar::EntryType::Directory => {
+                // a lower layer's symlink, file, fifo or device at the path is
+                // replaced, an existing directory is merged into
+                if dst_path.symlink_metadata().is_ok_and(|m| !m.is_dir()) {
+                    remove_file(&dst_path)?;
+                }
                 create_dir_all(&dst_path)?;
-                set_permissions(dst_path, Permissions::from_mode(tar_file.header().mode()?))?;
+                if preserve_owner {
+                    chown(&dst_path, uid, gid)?;
+                }
+                set_permissions(&dst_path, Permissions::from_mode(tar_file.header().mode()?))?;
             }
-            tar::EntryType::Symlink | tar::EntryType::Link => {
+            tar::EntryType::Symlink => {
+                // the entry's link name, the header's is cut at 100 bytes
                 let link = tar_file
-                    .header()
                     .link_name()?
-                    .unwrap()
+                    .context(format!("symlink without target: {}", path.display()))?
                     .to_string_lossy()
                     .to_string();
                 let original_path = Path::new(&link);
-                if dst_path.exists() {
-                    println!("overriding symlink: {}", dst_path.display());
-                    remove_file(&dst_path)?;
+                // exists() follows links, a dangling one would be left in place
+                if dst_path.symlink_metadata().is_ok() {
+                    debug!("overriding with symlink: {}", dst_path.display());
+                    remove_path(&dst_path)?;
                 }
                 symlink(original_path, &dst_path).map_err(|e| {
                     anyhow::anyhow!(
@@ -44,14 +161,952 @@ pub fn extract_archive(reader: &mut dyn Read, dst_dir: &Path) -> Result<()> {
                         dst_path.display()
                     )
                 })?;
+                if preserve_owner {
+                    lchown(&dst_path, uid, gid)?;
+                }
             }
-            _ => println!(
-                "warning: skipping entry type: {:?} for {}",
+            tar::EntryType::Link => {
+                // hardlink names are relative to the archive root
+                let link = tar_file
+                    .link_name()?
+                    .context(format!("hardlink without target: {}", path.display()))?
+                    .to_path_buf();
+                let original_path = safe_join(dst_dir, &canonical_dst_dir, &link)?;
+                if original_path.symlink_metadata().is_ok() {
+                    create_hard_link(&original_path, &dst_path)?;
+                } else {
+                    // target comes later in the archive
+                    deferred_links.push((original_path, dst_path.clone()));
+                }
+            }
+            entry_type @ (tar::EntryType::Block | tar::EntryType::Char | tar::EntryType::Fifo) => {
+                // mknod of devices needs CAP_MKNOD, fifos don't
+                if !preserve_owner && entry_type != tar::EntryType::Fifo {
+                    warn!(
+                        "skipping {:?} device {}, creating it needs root",
+                        entry_type,
+                        dst_path.display()
+                    );
+                    continue;
+                }
+                if dst_path.symlink_metadata().is_ok() {
+                    remove_path(&dst_path)?;
+                }
+                let header = tar_file.header();
+                let file_type = match entry_type {
+                    tar::EntryType::Block => libc::S_IFBLK,
+                    tar::EntryType::Char => libc::S_IFCHR,
+                    _ => libc::S_IFIFO,
+                };
+                let dev = libc::makedev(
+                    header.device_major()?.unwrap_or(0),
+                    header.device_minor()?.unwrap_or(0),
+                );
+                make_node(&dst_path, file_type, dev)?;
+                if preserve_owner {
+                    chown(&dst_path, uid, gid)?;
+                }
+                set_permissions(&dst_path, Permissions::from_mode(header.mode()?))?;
+            }
+            _ => warn!(
+                "skipping entry type: {:?} for {}",
                 tar_file.header().entry_type(),
                 dst_path.display()
             ),
         }
+
+        // set after chown, which would otherwise drop security.capability
+        if dst_path.symlink_metadata().is_ok() {
+            for (name, value) in xattrs.iter() {
+                if let Err(e) = xattr::set(&dst_path, name, value) {
+                    warn!(
+                        "failed to set xattr {} on {}: {}",
+                        name,
+                        dst_path.display(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    for (original_path, dst_path) in deferred_links.iter() {
+        create_hard_link(original_path, dst_path)?;
+    }
+
+    Ok(())
+}
+
+// the mode's permission bits are set afterwards, free of the umask
+fn make_node(dst_path: &Path, file_type: libc::mode_t, dev: libc::dev_t) -> Result<()> {
+    let c_path = CString::new(dst_path.as_os_str().as_bytes())?;
+    if unsafe { libc::mknod(c_path.as_ptr(), file_type | 0o600, dev) } != 0 {
+        return Err(anyhow::anyhow!(
+            "failed to create node {}: {}",
+            dst_path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+fn create_hard_link(original_path: &Path, dst_path: &Path) -> Result<()> {
+    if dst_path.symlink_metadata().is_ok() {
+        remove_path(dst_path)?;
+    }
+    hard_link(original_path, dst_path).map_err(|e| {
+        anyhow::anyhow!(
+            "failed to hard link: {}, original path: {}, file {}",
+            e,
+            original_path.display(),
+            dst_path.display()
+        )
+    })
+}
+
+// writer that hashes everything passing through, for checking blobs while
+// they are written
+pub struct Sha256Writer<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Sha256Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Sha256Writer {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    // digest in `sha256:<hex>` form
+    pub fn finish(self) -> String {
+        format!("sha256:{:x}", self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for Sha256Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// decode a wait status into a shell style exit code
+pub fn exit_code(status: libc::c_int) -> i32 {
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        1
+    }
+}
+
+// wait for pid & return its wait status, as pid 1 of the namespace also reap
+// orphaned grandchildren that would otherwise stay zombies
+pub fn wait_child(pid: libc::pid_t) -> libc::c_int {
+    let is_init = unsafe { libc::getpid() } == 1;
+    let mut status = 0;
+    loop {
+        let waited = unsafe { libc::waitpid(if is_init { -1 } else { pid }, &mut status, 0) };
+        if waited == pid {
+            return status;
+        }
+        if waited == -1 && std::io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            return status;
+        }
+    }
+}
+
+// whether a file is older than ttl, a zero ttl never expires
+pub fn is_expired(path: &Path, ttl: Duration) -> Result<bool> {
+    if ttl.is_zero() {
+        return Ok(false);
+    }
+    let age = path.metadata()?.modified()?.elapsed().unwrap_or_default();
+    Ok(age > ttl)
+}
+
+// entry path with GNU long names & PAX paths applied, the header alone only
+// holds 100 bytes. a path that doesn't match its PAX record or carries a NUL
+// is an extension that wasn't applied, fail rather than write a cut name
+fn entry_path<R: Read>(tar_file: &mut tar::Entry<'_, R>) -> Result<PathBuf> {
+    let path_bytes = tar_file.path_bytes().into_owned();
+    if path_bytes.is_empty() || path_bytes.contains(&0) {
+        return Err(anyhow::anyhow!(
+            "malformed entry path: {}",
+            String::from_utf8_lossy(&path_bytes)
+        ));
+    }
+    if let Some(extensions) = tar_file.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            if extension.key() == Ok("path") && extension.value_bytes() != path_bytes {
+                return Err(anyhow::anyhow!(
+                    "entry path {} doesn't match its pax path {}, the name would be truncated",
+                    String::from_utf8_lossy(&path_bytes),
+                    String::from_utf8_lossy(extension.value_bytes())
+                ));
+            }
+        }
+    }
+    Ok(tar_file.path()?.into_owned())
+}
+
+fn read_pax_xattrs<R: Read>(tar_file: &mut tar::Entry<'_, R>) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    let Some(extensions) = tar_file.pax_extensions()? else {
+        return Ok(xattrs);
+    };
+    for extension in extensions {
+        let extension = extension?;
+        let Ok(key) = extension.key() else {
+            continue;
+        };
+        if let Some(name) = key.strip_prefix(PAX_XATTR_PREFIX) {
+            xattrs.push((name.to_string(), extension.value_bytes().to_vec()));
+        }
+    }
+    Ok(xattrs)
+}
+
+// join an entry path onto dst_dir, rejecting paths that escape it either by
+// `..`/absolute components or through a symlinked parent directory
+pub fn safe_join(dst_dir: &Path, canonical_dst_dir: &Path, entry_path: &Path) -> Result<PathBuf> {
+    if entry_path
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(anyhow::anyhow!(
+            "refusing to extract entry outside of rootfs: {}",
+            entry_path.display()
+        ));
+    }
+    let dst_path = dst_dir.join(entry_path);
+    // the root entry itself, nothing to resolve
+    if !entry_path
+        .components()
+        .any(|component| matches!(component, Component::Normal(_)))
+    {
+        return Ok(dst_path);
+    }
+    if let Some(parent) = dst_path.parent()
+        && parent.exists()
+        && !parent.canonicalize()?.starts_with(canonical_dst_dir)
+    {
+        return Err(anyhow::anyhow!(
+            "refusing to extract entry through a symlink outside of rootfs: {}",
+            entry_path.display()
+        ));
+    }
+    Ok(dst_path)
+}
+
+// move the calling process into its own process group & make it the terminal's
+// foreground group so Ctrl-C reaches it instead of us, call in the child
+pub fn take_foreground() -> Result<()> {
+    if unsafe { libc::setpgid(0, 0) } != 0 {
+        return Err(anyhow::anyhow!(
+            "failed to create process group: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    set_foreground_group(unsafe { libc::getpgrp() });
+    Ok(())
+}
+
+// hand the terminal back to our process group once the child group is gone
+pub fn restore_foreground() {
+    set_foreground_group(unsafe { libc::getpgrp() });
+}
+
+fn set_foreground_group(pgrp: libc::pid_t) {
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+        return;
+    }
+    // a background group changing the foreground gets SIGTTOU otherwise
+    unsafe {
+        let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgrp);
+        libc::signal(libc::SIGTTOU, previous);
+    }
+}
+
+// path inside rootfs_dir for an absolute or relative path in the rootfs,
+// rejecting `..` & existing symlinks that lead out of it before anything is
+// created there
+pub fn rootfs_path(rootfs_dir: &Path, path: &str) -> Result<PathBuf> {
+    let relative_path = Path::new(path.trim_start_matches('/'));
+    if relative_path
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(anyhow::anyhow!("refusing path outside of rootfs: {}", path));
+    }
+    // unlike archive entries any component may already be a symlink
+    let canonical_rootfs_dir = rootfs_dir.canonicalize()?;
+    let mut existing_path = rootfs_dir.to_path_buf();
+    for component in relative_path.components() {
+        existing_path.push(component);
+        if existing_path.symlink_metadata().is_err() {
+            break;
+        }
+        if !existing_path
+            .canonicalize()
+            .is_ok_and(|path| path.starts_with(&canonical_rootfs_dir))
+        {
+            return Err(anyhow::anyhow!(
+                "refusing path through a symlink outside of rootfs: {}",
+                path
+            ));
+        }
+    }
+    Ok(rootfs_dir.join(relative_path))
+}
+
+// where path leads inside rootfs_dir, following symlinks the way the kernel
+// would once rootfs_dir is the root: absolute targets restart at rootfs_dir &
+// `..` stops there. none if a component is missing or links loop
+pub fn resolve_rootfs_path(rootfs_dir: &Path, path: &str) -> Option<PathBuf> {
+    // components left to walk, next one last
+    let mut pending = Vec::new();
+    push_components(&mut pending, Path::new(path));
+    let mut resolved = PathBuf::new();
+    let mut link_count = 0;
+    while let Some(component) = pending.pop() {
+        if component == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = rootfs_dir.join(&resolved).join(&component);
+        if !candidate.symlink_metadata().ok()?.is_symlink() {
+            resolved.push(component);
+            continue;
+        }
+        // the kernel's limit for nested links
+        link_count += 1;
+        if link_count > 40 {
+            return None;
+        }
+        let target = std::fs::read_link(&candidate).ok()?;
+        if target.is_absolute() {
+            resolved.clear();
+        }
+        push_components(&mut pending, &target);
+    }
+    Some(rootfs_dir.join(resolved))
+}
+
+fn push_components(pending: &mut Vec<std::ffi::OsString>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(name) => pending.push(name.to_os_string()),
+            Component::ParentDir => pending.push("..".into()),
+            _ => {}
+        }
+    }
+}
+
+// remove a file, symlink or directory tree, missing path is not an error
+fn remove_path(path: &Path) -> Result<()> {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if metadata.is_dir() {
+        remove_dir_all(path)?;
+    } else {
+        remove_file(path)?;
     }
+    Ok(())
+}
 
+// clear directory content inherited from lower layers
+fn clear_dir(dir: &Path, keep: &HashSet<PathBuf>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in read_dir(dir)? {
+        let entry_path = entry?.path();
+        if !keep.contains(&entry_path) {
+            remove_path(&entry_path)?;
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tar_layer(build: impl FnOnce(&mut tar::Builder<Vec<u8>>)) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        build(&mut builder);
+        builder.into_inner().unwrap()
+    }
+
+    fn add_file(builder: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8], mode: u32) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(mode);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    fn add_dir(builder: &mut tar::Builder<Vec<u8>>, path: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        builder
+            .append_data(&mut header, path, std::io::empty())
+            .unwrap();
+    }
+
+    fn add_file_with_xattrs(
+        builder: &mut tar::Builder<Vec<u8>>,
+        path: &str,
+        xattrs: &[(&str, &[u8])],
+    ) {
+        let pax_keys = xattrs
+            .iter()
+            .map(|(name, value)| (format!("{}{}", PAX_XATTR_PREFIX, name), *value))
+            .collect::<Vec<_>>();
+        builder
+            .append_pax_extensions(pax_keys.iter().map(|(key, value)| (key.as_str(), *value)))
+            .unwrap();
+        add_file(builder, path, b"#!/bin/true", 0o755);
+    }
+
+    // extract the layers onto a fresh dir, lowest first
+    fn extract_layers(layers: &[Vec<u8>]) -> tempfile::TempDir {
+        let dst_dir = tempfile::tempdir().unwrap();
+        for layer in layers {
+            extract_archive(&mut layer.as_slice(), dst_dir.path()).unwrap();
+        }
+        dst_dir
+    }
+
+    #[test]
+    fn whiteout_removes_the_lower_layer_file() {
+        let lower = tar_layer(|b| {
+            add_dir(b, "etc");
+            add_file(b, "etc/deleted", b"old", 0o644);
+            add_file(b, "etc/kept", b"kept", 0o644);
+        });
+        let upper = tar_layer(|b| add_file(b, "etc/.wh.deleted", b"", 0o644));
+        let rootfs = extract_layers(&[lower, upper]);
+        assert!(!rootfs.path().join("etc/deleted").exists());
+        assert!(!rootfs.path().join("etc/.wh.deleted").exists());
+        assert!(rootfs.path().join("etc/kept").exists());
+    }
+
+    #[test]
+    fn whiteout_removes_a_lower_layer_dir() {
+        let lower = tar_layer(|b| {
+            add_dir(b, "var");
+            add_dir(b, "var/cache");
+            add_file(b, "var/cache/blob", b"blob", 0o644);
+        });
+        let upper = tar_layer(|b| add_file(b, "var/.wh.cache", b"", 0o644));
+        let rootfs = extract_layers(&[lower, upper]);
+        assert!(!rootfs.path().join("var/cache").exists());
+        assert!(rootfs.path().join("var").is_dir());
+    }
+
+    #[test]
+    fn opaque_marker_clears_only_lower_layer_content() {
+        let lower = tar_layer(|b| {
+            add_dir(b, "app");
+            add_file(b, "app/old", b"old", 0o644);
+        });
+        // the marker may come after the layer's own entries of the dir
+        let upper = tar_layer(|b| {
+            add_dir(b, "app");
+            add_file(b, "app/new", b"new", 0o644);
+            add_file(b, "app/.wh..wh..opq", b"", 0o644);
+        });
+        let rootfs = extract_layers(&[lower, upper]);
+        assert!(!rootfs.path().join("app/old").exists());
+        assert_eq!(
+            std::fs::read(rootfs.path().join("app/new")).unwrap(),
+            b"new"
+        );
+        assert!(!rootfs.path().join("app/.wh..wh..opq").exists());
+    }
+
+    #[test]
+    fn pax_xattrs_are_applied() {
+        let probe = tempfile::tempdir().unwrap();
+        if xattr::set(probe.path(), "user.probe", b"1").is_err() {
+            // the fs of the temp dir has no user xattrs
+            return;
+        }
+        let layer = tar_layer(|b| {
+            add_file_with_xattrs(b, "bin/tool", &[("user.origin", b"layer")]);
+        });
+        let rootfs = extract_layers(&[tar_layer(|b| add_dir(b, "bin")), layer]);
+        assert_eq!(
+            xattr::get(rootfs.path().join("bin/tool"), "user.origin").unwrap(),
+            Some(b"layer".to_vec())
+        );
+    }
+
+    #[test]
+    fn security_capability_survives_extraction() {
+        // setting file capabilities needs CAP_SETFCAP
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        // vfs_cap_data revision 2, effective, cap_net_raw permitted
+        let mut capability = Vec::new();
+        for word in [0x0200_0001u32, 1 << 13, 0, 0, 0] {
+            capability.extend_from_slice(&word.to_le_bytes());
+        }
+        let layer = tar_layer(|b| {
+            add_dir(b, "bin");
+            add_file_with_xattrs(b, "bin/ping", &[("security.capability", &capability)]);
+        });
+        let rootfs = extract_layers(&[layer]);
+        assert_eq!(
+            xattr::get(rootfs.path().join("bin/ping"), "security.capability").unwrap(),
+            Some(capability)
+        );
+    }
+
+    #[test]
+    fn unsupported_xattrs_only_warn() {
+        let layer = tar_layer(|b| {
+            add_dir(b, "bin");
+            add_file_with_xattrs(b, "bin/tool", &[("bogus.namespace", b"x")]);
+        });
+        let rootfs = extract_layers(&[layer]);
+        assert!(rootfs.path().join("bin/tool").exists());
+    }
+
+    #[test]
+    fn safe_join_accepts_nested_paths() {
+        let dst_dir = tempfile::tempdir().unwrap();
+        let canonical = dst_dir.path().canonicalize().unwrap();
+        std::fs::create_dir_all(dst_dir.path().join("usr/bin")).unwrap();
+        for entry_path in ["usr/bin/ls", "./usr/bin/ls", "new/dir/file", "."] {
+            let joined = safe_join(dst_dir.path(), &canonical, Path::new(entry_path)).unwrap();
+            assert!(joined.starts_with(dst_dir.path()), "{}", entry_path);
+        }
+    }
+
+    #[test]
+    fn safe_join_rejects_parent_and_absolute_paths() {
+        let dst_dir = tempfile::tempdir().unwrap();
+        let canonical = dst_dir.path().canonicalize().unwrap();
+        for entry_path in ["../etc/passwd", "usr/../../etc/passwd", "/etc/passwd"] {
+            assert!(
+                safe_join(dst_dir.path(), &canonical, Path::new(entry_path)).is_err(),
+                "{}",
+                entry_path
+            );
+        }
+    }
+
+    #[test]
+    fn safe_join_rejects_symlinked_parents_leaving_the_dir() {
+        let outside = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let canonical = dst_dir.path().canonicalize().unwrap();
+        symlink(outside.path(), dst_dir.path().join("escape")).unwrap();
+        std::fs::create_dir(dst_dir.path().join("real")).unwrap();
+        symlink("real", dst_dir.path().join("inside")).unwrap();
+        assert!(safe_join(dst_dir.path(), &canonical, Path::new("escape/file")).is_err());
+        assert!(safe_join(dst_dir.path(), &canonical, Path::new("inside/file")).is_ok());
+        // the link itself may be replaced, only writing through it escapes
+        assert!(safe_join(dst_dir.path(), &canonical, Path::new("escape")).is_ok());
+    }
+
+    #[test]
+    fn extraction_never_writes_through_an_escaping_symlink() {
+        let outside = tempfile::tempdir().unwrap();
+        let layer = tar_layer(|b| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            b.append_link(&mut header, "escape", outside.path())
+                .unwrap();
+            add_file(b, "escape/owned", b"owned", 0o644);
+        });
+        let dst_dir = tempfile::tempdir().unwrap();
+        assert!(extract_archive(&mut layer.as_slice(), dst_dir.path()).is_err());
+        assert!(!outside.path().join("owned").exists());
+    }
+
+    #[test]
+    fn special_mode_bits_are_kept() {
+        let layer = tar_layer(|b| {
+            add_dir(b, "usr");
+            add_file(b, "usr/sudo", b"#!/bin/true", 0o4755);
+            add_file(b, "usr/wall", b"#!/bin/true", 0o2755);
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+            header.set_mode(0o1777);
+            b.append_data(&mut header, "tmp", std::io::empty()).unwrap();
+        });
+        let rootfs = extract_layers(&[layer]);
+        let mode = |path: &str| {
+            std::fs::metadata(rootfs.path().join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("usr/sudo"), 0o4755);
+        assert_eq!(mode("usr/wall"), 0o2755);
+        assert_eq!(mode("tmp"), 0o1777);
+    }
+
+    #[test]
+    fn layer_whiteouts_become_overlay_markers() {
+        // 0:0 char devices & trusted xattrs need root
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let layer = tar_layer(|b| {
+            add_dir(b, "etc");
+            add_file(b, "etc/.wh.deleted", b"", 0o644);
+            add_dir(b, "app");
+            add_file(b, "app/.wh..wh..opq", b"", 0o644);
+        });
+        let layer_dir = tempfile::tempdir().unwrap();
+        extract_layer(&mut layer.as_slice(), layer_dir.path()).unwrap();
+        let whiteout = std::fs::symlink_metadata(layer_dir.path().join("etc/deleted")).unwrap();
+        assert!(std::os::unix::fs::FileTypeExt::is_char_device(
+            &whiteout.file_type()
+        ));
+        assert_eq!(std::os::unix::fs::MetadataExt::rdev(&whiteout), 0);
+        assert_eq!(
+            xattr::get(layer_dir.path().join("app"), OVERLAY_OPAQUE_XATTR).unwrap(),
+            Some(b"y".to_vec())
+        );
+        assert!(!layer_dir.path().join("etc/.wh.deleted").exists());
+    }
+
+    #[test]
+    fn sniff_compression_by_magic() {
+        let plain = tar_layer(|b| add_file(b, "file", b"data", 0o644));
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&plain).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(plain.as_slice(), 0).unwrap();
+        assert_eq!(sniff_compression(&gzip), Compression::Gzip);
+        assert_eq!(sniff_compression(&zstd), Compression::Zstd);
+        assert_eq!(sniff_compression(&plain), Compression::None);
+    }
+
+    #[test]
+    fn sniff_compression_short_header() {
+        assert_eq!(sniff_compression(&[]), Compression::None);
+        assert_eq!(sniff_compression(&GZIP_MAGIC[..1]), Compression::None);
+        assert_eq!(sniff_compression(&ZSTD_MAGIC[..3]), Compression::None);
+    }
+
+    #[test]
+    fn long_entry_names_keep_the_full_path() {
+        let long_dir = "d".repeat(80);
+        let long_path = format!("{}/{}.txt", long_dir, "f".repeat(80));
+        let pax_path = format!("{}/{}.txt", long_dir, "p".repeat(80));
+        let layer = tar_layer(|b| {
+            add_dir(b, &long_dir);
+            // GNU long name entry
+            add_file(b, &long_path, b"gnu", 0o644);
+            // PAX path record, the header only holds the first 100 bytes
+            b.append_pax_extensions([("path", pax_path.as_bytes())])
+                .unwrap();
+            let mut header = tar::Header::new_ustar();
+            header
+                .as_ustar_mut()
+                .unwrap()
+                .name
+                .copy_from_slice(&pax_path.as_bytes()[..100]);
+            header.set_size(3);
+            header.set_mode(0o644);
+            header.set_cksum();
+            b.append(&header, &b"pax"[..]).unwrap();
+        });
+        let rootfs = extract_layers(&[layer]);
+        assert_eq!(
+            std::fs::read(rootfs.path().join(&long_path)).unwrap(),
+            b"gnu"
+        );
+        assert_eq!(
+            std::fs::read(rootfs.path().join(&pax_path)).unwrap(),
+            b"pax"
+        );
+        // nothing was written under a cut name
+        assert_eq!(read_dir(rootfs.path().join(&long_dir)).unwrap().count(), 2);
+    }
+
+    fn add_node(
+        builder: &mut tar::Builder<Vec<u8>>,
+        path: &str,
+        entry_type: tar::EntryType,
+        dev: (u32, u32),
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_device_major(dev.0).unwrap();
+        header.set_device_minor(dev.1).unwrap();
+        header.set_size(0);
+        header.set_mode(0o620);
+        builder
+            .append_data(&mut header, path, std::io::empty())
+            .unwrap();
+    }
+
+    #[test]
+    fn fifo_entries_extract_without_root() {
+        let layer = tar_layer(|b| add_node(b, "run/fifo", tar::EntryType::Fifo, (0, 0)));
+        let rootfs = extract_layers(&[tar_layer(|b| add_dir(b, "run")), layer]);
+        let metadata = rootfs.path().join("run/fifo").symlink_metadata().unwrap();
+        assert!(std::os::unix::fs::FileTypeExt::is_fifo(
+            &metadata.file_type()
+        ));
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o620);
+    }
+
+    #[test]
+    fn device_entries_keep_their_numbers() {
+        let layer = tar_layer(|b| {
+            add_dir(b, "dev");
+            add_node(b, "dev/null", tar::EntryType::Char, (1, 3));
+            add_node(b, "dev/loop0", tar::EntryType::Block, (7, 0));
+        });
+        let rootfs = extract_layers(&[layer]);
+        // mknod of devices needs root, without it they're skipped
+        if unsafe { libc::geteuid() } != 0 {
+            assert!(!rootfs.path().join("dev/null").exists());
+            return;
+        }
+        let null = rootfs.path().join("dev/null").symlink_metadata().unwrap();
+        assert!(std::os::unix::fs::FileTypeExt::is_char_device(
+            &null.file_type()
+        ));
+        assert_eq!(
+            std::os::unix::fs::MetadataExt::rdev(&null),
+            libc::makedev(1, 3)
+        );
+        let loop0 = rootfs.path().join("dev/loop0").symlink_metadata().unwrap();
+        assert!(std::os::unix::fs::FileTypeExt::is_block_device(
+            &loop0.file_type()
+        ));
+        assert_eq!(
+            std::os::unix::fs::MetadataExt::rdev(&loop0),
+            libc::makedev(7, 0)
+        );
+        assert_eq!(null.permissions().mode() & 0o7777, 0o620);
+    }
+
+    fn add_link(
+        builder: &mut tar::Builder<Vec<u8>>,
+        entry_type: tar::EntryType,
+        path: &str,
+        target: &str,
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+
+    #[test]
+    fn symlink_replaces_a_dangling_symlink() {
+        let lower = tar_layer(|b| add_link(b, tar::EntryType::Symlink, "current", "gone"));
+        let upper = tar_layer(|b| {
+            add_file(b, "v2", b"v2", 0o644);
+            add_link(b, tar::EntryType::Symlink, "current", "v2");
+        });
+        let rootfs = extract_layers(&[lower, upper]);
+        assert_eq!(
+            std::fs::read_link(rootfs.path().join("current")).unwrap(),
+            Path::new("v2")
+        );
+    }
+
+    #[test]
+    fn symlink_replaces_a_regular_file() {
+        let lower = tar_layer(|b| add_file(b, "config", b"old", 0o644));
+        let upper = tar_layer(|b| add_link(b, tar::EntryType::Symlink, "config", "/etc/config"));
+        let rootfs = extract_layers(&[lower, upper]);
+        assert_eq!(
+            std::fs::read_link(rootfs.path().join("config")).unwrap(),
+            Path::new("/etc/config")
+        );
+    }
+
+    #[test]
+    fn file_replaces_a_symlink_without_writing_through_it() {
+        let lower = tar_layer(|b| {
+            add_file(b, "target", b"target", 0o644);
+            add_link(b, tar::EntryType::Symlink, "config", "target");
+        });
+        let upper = tar_layer(|b| add_file(b, "config", b"new", 0o644));
+        let rootfs = extract_layers(&[lower, upper]);
+        let config = rootfs.path().join("config");
+        assert!(config.symlink_metadata().unwrap().is_file());
+        assert_eq!(std::fs::read(&config).unwrap(), b"new");
+        assert_eq!(
+            std::fs::read(rootfs.path().join("target")).unwrap(),
+            b"target"
+        );
+    }
+
+    #[test]
+    fn entries_replace_a_lower_directory() {
+        let lower = tar_layer(|b| {
+            add_dir(b, "data");
+            add_file(b, "data/file", b"old", 0o644);
+            add_dir(b, "cache");
+            add_file(b, "orig", b"orig", 0o644);
+        });
+        let upper = tar_layer(|b| {
+            add_file(b, "data", b"file now", 0o644);
+            add_link(b, tar::EntryType::Link, "cache", "orig");
+        });
+        let rootfs = extract_layers(&[lower, upper]);
+        assert_eq!(
+            std::fs::read(rootfs.path().join("data")).unwrap(),
+            b"file now"
+        );
+        let cache = rootfs.path().join("cache").symlink_metadata().unwrap();
+        let orig = rootfs.path().join("orig").symlink_metadata().unwrap();
+        assert_eq!(
+            std::os::unix::fs::MetadataExt::ino(&cache),
+            std::os::unix::fs::MetadataExt::ino(&orig)
+        );
+    }
+
+    #[test]
+    fn empty_layers_extract_to_nothing() {
+        // no bytes at all, and just the two zero end blocks
+        for layer in [Vec::new(), tar_layer(|_| {})] {
+            let dst_dir = tempfile::tempdir().unwrap();
+            extract_archive(&mut layer.as_slice(), dst_dir.path()).unwrap();
+            extract_layer(&mut layer.as_slice(), dst_dir.path()).unwrap();
+            assert_eq!(read_dir(dst_dir.path()).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn whiteout_only_layer() {
+        let lower = tar_layer(|b| {
+            add_dir(b, "etc");
+            add_file(b, "etc/deleted", b"old", 0o644);
+            add_file(b, "etc/kept", b"kept", 0o644);
+        });
+        // no entry for etc itself, only the whiteout inside it
+        let upper = tar_layer(|b| add_file(b, "etc/.wh.deleted", b"", 0o644));
+        let rootfs = extract_layers(&[lower, upper.clone()]);
+        assert!(!rootfs.path().join("etc/deleted").exists());
+        assert!(rootfs.path().join("etc/kept").exists());
+        // as an overlay lower dir the parent is created for the marker
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let layer_dir = tempfile::tempdir().unwrap();
+        extract_layer(&mut upper.as_slice(), layer_dir.path()).unwrap();
+        let whiteout = std::fs::symlink_metadata(layer_dir.path().join("etc/deleted")).unwrap();
+        assert!(std::os::unix::fs::FileTypeExt::is_char_device(
+            &whiteout.file_type()
+        ));
+    }
+
+    #[test]
+    fn resolve_rootfs_path_follows_links_inside_the_rootfs() {
+        let rootfs = tempfile::tempdir().unwrap();
+        let rootfs_dir = rootfs.path();
+        create_dir_all(rootfs_dir.join("bin")).unwrap();
+        std::fs::write(rootfs_dir.join("bin/busybox"), b"").unwrap();
+        // alpine's /bin/sh, absent from most hosts
+        symlink("/bin/busybox", rootfs_dir.join("bin/sh")).unwrap();
+        symlink("sh", rootfs_dir.join("bin/ash")).unwrap();
+        symlink("../../../bin/busybox", rootfs_dir.join("bin/up")).unwrap();
+        symlink("/bin", rootfs_dir.join("sbin")).unwrap();
+        for path in [
+            "/bin/sh",
+            "bin/ash",
+            "/bin/up",
+            "/sbin/sh",
+            "/bin/./busybox",
+        ] {
+            assert_eq!(
+                resolve_rootfs_path(rootfs_dir, path),
+                Some(rootfs_dir.join("bin/busybox")),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_rootfs_path_never_resolves_on_the_host() {
+        let rootfs = tempfile::tempdir().unwrap();
+        let rootfs_dir = rootfs.path();
+        // exists on the host, not in the rootfs
+        symlink("/tmp", rootfs_dir.join("tmp-link")).unwrap();
+        symlink("loop-b", rootfs_dir.join("loop-a")).unwrap();
+        symlink("loop-a", rootfs_dir.join("loop-b")).unwrap();
+        assert_eq!(resolve_rootfs_path(rootfs_dir, "/tmp-link"), None);
+        assert_eq!(resolve_rootfs_path(rootfs_dir, "/tmp"), None);
+        assert_eq!(resolve_rootfs_path(rootfs_dir, "/loop-a"), None);
+    }
+
+    #[test]
+    fn directory_replaces_a_lower_file_or_fifo() {
+        let lower = tar_layer(|b| {
+            add_file(b, "data", b"file", 0o644);
+            add_node(b, "queue", tar::EntryType::Fifo, (0, 0));
+        });
+        let upper = tar_layer(|b| {
+            add_dir(b, "data");
+            add_file(b, "data/file", b"now a dir", 0o644);
+            add_dir(b, "queue");
+        });
+        let rootfs = extract_layers(&[lower, upper]);
+        assert!(
+            rootfs
+                .path()
+                .join("data")
+                .symlink_metadata()
+                .unwrap()
+                .is_dir()
+        );
+        assert_eq!(
+            std::fs::read(rootfs.path().join("data/file")).unwrap(),
+            b"now a dir"
+        );
+        assert!(
+            rootfs
+                .path()
+                .join("queue")
+                .symlink_metadata()
+                .unwrap()
+                .is_dir()
+        );
+    }
+
+    #[test]
+    fn directory_keeps_the_lower_directory_content() {
+        let lower = tar_layer(|b| {
+            add_dir(b, "etc");
+            add_file(b, "etc/kept", b"kept", 0o644);
+        });
+        let upper = tar_layer(|b| add_dir(b, "etc"));
+        let rootfs = extract_layers(&[lower, upper]);
+        assert!(rootfs.path().join("etc/kept").exists());
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::Result;
+use std::{
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    thread::{self, JoinHandle},
+};
+use tracing::{debug, info};
+
+// watches the container's init process & sends SIGTERM to the session once it
+// exits, a pidfd can't be fooled by pid reuse & needs no docker connection,
+// which may not be reachable from the container's network namespace
+pub struct ContainerWatcher {
+    stop_writer: OwnedFd,
+    handle: JoinHandle<()>,
+}
+
+impl ContainerWatcher {
+    // open the pidfd before entering the pid namespace, pids are resolved in ours
+    pub fn open(container_pid: libc::pid_t) -> Result<OwnedFd> {
+        let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, container_pid, 0) };
+        if pidfd == -1 {
+            return Err(anyhow::anyhow!(
+                "pidfd_open failed: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(pidfd as i32) })
+    }
+
+    pub fn spawn(pidfd: OwnedFd, session_pid: libc::pid_t) -> Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(anyhow::anyhow!(
+                "failed to create watcher pipe: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let (stop_reader, stop_writer) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let handle = thread::spawn(move || {
+            if wait_container_exit(&pidfd, &stop_reader) {
+                info!("container exited, ending the debug session");
+                unsafe {
+                    libc::kill(session_pid, libc::SIGTERM);
+                }
+            }
+        });
+        Ok(ContainerWatcher {
+            stop_writer,
+            handle,
+        })
+    }
+
+    // closing the pipe wakes the watcher up, call once the session has exited
+    pub fn stop(self) {
+        drop(self.stop_writer);
+        let _ = self.handle.join();
+    }
+}
+
+// true when the container exited, false when stopped through the pipe
+fn wait_container_exit(pidfd: &OwnedFd, stop_reader: &OwnedFd) -> bool {
+    let mut fds = [
+        libc::pollfd {
+            fd: pidfd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: stop_reader.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    loop {
+        let poll_res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if poll_res == -1 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            debug!("container watcher poll failed: {}", err);
+            return false;
+        }
+        if fds[1].revents != 0 {
+            return false;
+        }
+        if fds[0].revents != 0 {
+            return true;
+        }
+    }
+}